@@ -1,13 +1,21 @@
 use crate::{parse_context::ParseContext, parse_error::ParseError, utils::parse_leading_usize};
 
+/// Represents a doc test, as listed by `cargo test -- --list`.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct DocTest<'a> {
+    /// The item the doc test is attached to, for example "passing_doctest".
     pub name: &'a str,
     pub line_number: usize,
     pub file_name: &'a str,
 }
 
 impl<'a> DocTest<'a> {
+    /// Returns the name that libtest reports the doc test's outcome
+    /// under, for example "src/lib.rs - passing_doctest (line 3)".
+    pub fn full_name(&self) -> String {
+        format!("{} - {} (line {})", self.file_name, self.name, self.line_number)
+    }
+
     /// Construct a new `DocTest` from a line of the form
     /// "src/lib.rs - passing_doctest (line 3): test".
     pub(crate) fn parse<'ctx>(
@@ -74,5 +82,6 @@ mod tests {
         assert_eq!(result.name, "passing_doctest");
         assert_eq!(result.file_name, "src/lib.rs");
         assert_eq!(result.line_number, 233);
+        assert_eq!(result.full_name(), "src/lib.rs - passing_doctest (line 233)");
     }
 }
@@ -2,12 +2,18 @@ mod crate_name;
 mod doc_test;
 mod parse_context;
 mod parse_error;
+mod test_run;
 mod utils;
 
 pub use crate_name::CrateName;
-use doc_test::DocTest;
+pub use doc_test::DocTest;
 use parse_context::ParseContext;
 pub use parse_error::ParseError;
+use test_run::parse_running_binary;
+pub use test_run::{
+    parse_long_running_test, parse_test_outcome, parse_test_run, parse_test_run_line,
+    LongRunningTest, TestOutcome, TestResult, TestRunLine, TestRunResults,
+};
 use utils::parse_leading_usize;
 
 /// Parses the output of `cargo test -- --list` and returns the result.
@@ -32,7 +38,7 @@ pub fn parse_test_list(data: &str) -> Result<Vec<Tests>, ParseError> {
 
         if line.starts_with(RUNNING_PREFIX) {
             // Ok, we found a standard test listing.
-            let line = line.trim_start_matches(RUNNING_PREFIX);
+            let line = parse_running_binary(line.trim_start_matches(RUNNING_PREFIX));
             let crate_name = CrateName::parse(line, &ctx)?;
             let mut crate_tests = Tests {
                 crate_name,
@@ -230,6 +236,20 @@ mod parse_test_list_simple_tests {
         assert_eq!(tests[0].crate_name.full_name, "/abc-9bdf7ee7378a8684");
     }
 
+    #[test]
+    fn parse_test_list_for_one_crate_with_source_file() {
+        let tests = parse_test_list(
+            "  Running unittests src/lib.rs (target/debug/deps/abc-9bdf7ee7378a8684)\n0 tests, 0 benchmarks",
+        )
+        .unwrap();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(
+            tests[0].crate_name.full_name,
+            "target/debug/deps/abc-9bdf7ee7378a8684"
+        );
+        assert_eq!(tests[0].crate_name.basename, "abc");
+    }
+
     #[test]
     fn parse_test_list_for_two_crates_with_no_bodies() {
         let tests = parse_test_list("  Running /abc-9bdf7ee7378a8684\n0 tests, 0 benchmarks\n  Running /def-0490fca25dc32581\n0 tests, 0 benchmarks").unwrap();
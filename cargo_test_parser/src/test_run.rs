@@ -0,0 +1,475 @@
+use crate::{
+    crate_name::CrateName,
+    parse_context::ParseContext,
+    utils::{parse_leading_usize, parse_seconds},
+};
+use std::time::Duration;
+
+/// Represents a warning from libtest that a test is taking a long time to
+/// execute. This is often the first sign that a test has deadlocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LongRunningTest<'a> {
+    /// The name of the test, for example "tests::slow_test".
+    pub name: &'a str,
+
+    /// The number of seconds the test has been running for, as reported by libtest.
+    pub seconds: usize,
+}
+
+/// Parses a line of the form "test tests::slow_test has been running for over 60 seconds",
+/// as emitted by libtest when a test is taking a long time. Returns `None` if the line is
+/// not of that form.
+///
+/// When running with `--test-threads=1` the warning can appear on the same line as
+/// the test's own "test tests::slow_test ... " announcement, so we only look at the
+/// text following the last "test " before the warning.
+pub fn parse_long_running_test(line: &str) -> Option<LongRunningTest<'_>> {
+    const TEST_PREFIX: &str = "test ";
+    const RUNNING_FOR: &str = " has been running for over ";
+
+    let line = line.trim();
+    let idx = line.find(RUNNING_FOR)?;
+    let (lhs, rhs) = (&line[..idx], &line[idx + RUNNING_FOR.len()..]);
+
+    let name_start = lhs.rfind(TEST_PREFIX)? + TEST_PREFIX.len();
+    let name = lhs[name_start..].trim();
+    if name.is_empty() || !rhs.ends_with(" seconds") {
+        return None;
+    }
+
+    let seconds = parse_leading_usize(rhs)?;
+    Some(LongRunningTest { name, seconds })
+}
+
+/// The outcome of a single test, as reported by libtest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+/// Represents a line of the form "test tests::a ... ok" from the output of
+/// `cargo test`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestOutcome<'a> {
+    /// The name of the test, for example "tests::a". For doc tests this is
+    /// the full name, for example "src/lib.rs - passing_doctest (line 3)".
+    pub name: &'a str,
+    pub result: TestResult,
+    /// How long the test took, which libtest only reports when it is
+    /// run with `-Z unstable-options --report-time`.
+    pub duration: Option<Duration>,
+}
+
+/// The outcomes of the tests in one test binary (or the doc tests of one
+/// crate) from a single run of `cargo test`.
+#[derive(Debug, Clone)]
+pub struct TestRunResults<'a> {
+    pub crate_name: CrateName<'a>,
+    /// True if these are the results of a 'Doc-tests' section.
+    pub is_doc_tests: bool,
+    pub outcomes: Vec<TestOutcome<'a>>,
+    /// How long the binary took to run its tests, from libtest's summary
+    /// line. `None` if the binary didn't get as far as the summary.
+    pub duration: Option<Duration>,
+}
+
+/// A line from the output of `cargo test` that `parse_test_run_line` recognises.
+#[derive(Debug, Clone)]
+pub enum TestRunLine<'a> {
+    /// The start of the tests in a test binary, or of the doc tests of a crate.
+    Section {
+        crate_name: CrateName<'a>,
+        is_doc_tests: bool,
+    },
+    /// The outcome of a test in the current section.
+    Outcome(TestOutcome<'a>),
+    /// The time the current section took, from libtest's summary line.
+    Finished(Duration),
+}
+
+/// Parses the output of `cargo test` and returns the outcome of every test
+/// that was run, grouped by test binary. Unlike `parse_test_list` this is
+/// lenient: the tests can write anything to stdout, so lines that are not
+/// recognised are skipped rather than treated as errors.
+pub fn parse_test_run(data: &str) -> Vec<TestRunResults<'_>> {
+    let mut results: Vec<TestRunResults> = Vec::new();
+
+    for line in data.lines() {
+        match parse_test_run_line(line) {
+            Some(TestRunLine::Section {
+                crate_name,
+                is_doc_tests,
+            }) => results.push(TestRunResults {
+                crate_name,
+                is_doc_tests,
+                outcomes: Vec::new(),
+                duration: None,
+            }),
+            // Outcomes before the first section heading can't be attributed
+            // to a binary, so they are dropped.
+            Some(TestRunLine::Outcome(outcome)) => {
+                if let Some(current) = results.last_mut() {
+                    current.outcomes.push(outcome);
+                }
+            }
+            Some(TestRunLine::Finished(duration)) => {
+                if let Some(current) = results.last_mut() {
+                    current.duration = Some(duration);
+                }
+            }
+            None => {}
+        }
+    }
+
+    results
+}
+
+/// Parses a single line of the output of `cargo test`, which allows the
+/// outcomes of the tests to be followed as they are written. Returns `None`
+/// if the line is not one that `parse_test_run` makes use of.
+pub fn parse_test_run_line(line: &str) -> Option<TestRunLine<'_>> {
+    const RUNNING_PREFIX: &str = "Running ";
+    const DOC_TEST_PREFIX: &str = "Doc-tests ";
+
+    // Malformed crate names are skipped rather than reported, so the
+    // context is only needed to satisfy `CrateName::parse`.
+    let ctx = ParseContext::new("");
+    let line = line.trim();
+
+    if line.starts_with(RUNNING_PREFIX) {
+        let binary = parse_running_binary(line.trim_start_matches(RUNNING_PREFIX));
+        let crate_name = CrateName::parse(binary, &ctx).ok()?;
+        Some(TestRunLine::Section {
+            crate_name,
+            is_doc_tests: false,
+        })
+    } else if line.starts_with(DOC_TEST_PREFIX) {
+        let name = line.trim_start_matches(DOC_TEST_PREFIX);
+        let crate_name = CrateName::parse(name, &ctx).ok()?;
+        Some(TestRunLine::Section {
+            crate_name,
+            is_doc_tests: true,
+        })
+    } else if let Some(outcome) = parse_test_outcome(line) {
+        Some(TestRunLine::Outcome(outcome))
+    } else {
+        parse_finished_in(line).map(TestRunLine::Finished)
+    }
+}
+
+/// Parses the time from libtest's summary line, for example "test result: ok.
+/// 2 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.01s".
+fn parse_finished_in(line: &str) -> Option<Duration> {
+    const RESULT_PREFIX: &str = "test result: ";
+    const FINISHED_IN: &str = "; finished in ";
+
+    if !line.starts_with(RESULT_PREFIX) {
+        return None;
+    }
+
+    let idx = line.rfind(FINISHED_IN)?;
+    parse_seconds(line[idx + FINISHED_IN.len()..].trim())
+}
+
+/// Extracts the path of the test binary from the remainder of a 'Running'
+/// line. Older versions of cargo print just the path, newer ones print the
+/// source file followed by the path in brackets, for example
+/// "unittests src/lib.rs (target/debug/deps/example_lib_tests-9bdf7ee7378a8684)".
+pub(crate) fn parse_running_binary(line: &str) -> &str {
+    let line = line.trim();
+    match (line.rfind('('), line.ends_with(')')) {
+        (Some(idx), true) => &line[idx + 1..line.len() - 1],
+        _ => line,
+    }
+}
+
+/// Parses a line of the form "test tests::a ... ok", returning `None` if the
+/// line is not of that form. Ignored tests may have a reason appended, as in
+/// "test tests::a ... ignored, too slow", and tests that are expected to panic
+/// have " - should panic" appended to their name. With `--report-time` the
+/// time is appended to the result, as in "test tests::a ... ok <0.003s>".
+pub fn parse_test_outcome(line: &str) -> Option<TestOutcome<'_>> {
+    const TEST_PREFIX: &str = "test ";
+    const SEPARATOR: &str = " ... ";
+    const SHOULD_PANIC: &str = " - should panic";
+
+    let line = line.trim();
+    if !line.starts_with(TEST_PREFIX) {
+        return None;
+    }
+
+    let line = &line[TEST_PREFIX.len()..];
+    let idx = line.rfind(SEPARATOR)?;
+    let (name, result) = (&line[..idx], &line[idx + SEPARATOR.len()..]);
+    let name = name.trim_end_matches(SHOULD_PANIC).trim();
+
+    let (result, duration) = match (result.rfind(" <"), result.trim_end().ends_with('>')) {
+        (Some(idx), true) => (
+            &result[..idx],
+            parse_seconds(result[idx + 2..].trim_end().trim_end_matches('>')),
+        ),
+        _ => (result, None),
+    };
+
+    let result = match result.trim() {
+        "ok" => TestResult::Passed,
+        "FAILED" => TestResult::Failed,
+        r if r == "ignored" || r.starts_with("ignored,") => TestResult::Ignored,
+        _ => return None,
+    };
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(TestOutcome {
+            name,
+            result,
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod parse_long_running_test_tests {
+    use super::*;
+
+    #[test]
+    fn parse_for_empty_data() {
+        assert!(parse_long_running_test("").is_none());
+    }
+
+    #[test]
+    fn parse_for_normal_test_lines() {
+        assert!(parse_long_running_test("test tests::a ... ok").is_none());
+        assert!(parse_long_running_test("running 2 tests").is_none());
+    }
+
+    #[test]
+    fn parse_for_truncated_data() {
+        assert!(parse_long_running_test("test  has been running for over 60 seconds").is_none());
+        assert!(parse_long_running_test("test a has been running for over 60").is_none());
+        assert!(parse_long_running_test("test a has been running for over seconds").is_none());
+    }
+
+    #[test]
+    fn parse_for_good_data() {
+        let result =
+            parse_long_running_test("test tests::slow has been running for over 60 seconds")
+                .unwrap();
+        assert_eq!(result.name, "tests::slow");
+        assert_eq!(result.seconds, 60);
+    }
+
+    #[test]
+    fn parse_for_warning_on_same_line_as_test_announcement() {
+        let result = parse_long_running_test(
+            "test tests::slow ... test tests::slow has been running for over 60 seconds",
+        )
+        .unwrap();
+        assert_eq!(result.name, "tests::slow");
+        assert_eq!(result.seconds, 60);
+    }
+}
+
+#[cfg(test)]
+mod parse_test_outcome_tests {
+    use super::*;
+
+    #[test]
+    fn parse_for_empty_data() {
+        assert!(parse_test_outcome("").is_none());
+    }
+
+    #[test]
+    fn parse_for_other_lines() {
+        assert!(parse_test_outcome("running 2 tests").is_none());
+        assert!(parse_test_outcome("test result: ok. 2 passed; 0 failed").is_none());
+        assert!(parse_test_outcome("test tests::a ... ").is_none());
+        assert!(parse_test_outcome("test  ... ok").is_none());
+    }
+
+    #[test]
+    fn parse_for_each_result() {
+        let result = parse_test_outcome("test tests::a ... ok").unwrap();
+        assert_eq!(result.name, "tests::a");
+        assert_eq!(result.result, TestResult::Passed);
+
+        let result = parse_test_outcome("test tests::b ... FAILED").unwrap();
+        assert_eq!(result.result, TestResult::Failed);
+
+        let result = parse_test_outcome("test tests::c ... ignored").unwrap();
+        assert_eq!(result.result, TestResult::Ignored);
+
+        let result = parse_test_outcome("test tests::d ... ignored, too slow").unwrap();
+        assert_eq!(result.name, "tests::d");
+        assert_eq!(result.result, TestResult::Ignored);
+    }
+
+    #[test]
+    fn parse_for_should_panic_and_doc_tests() {
+        let result = parse_test_outcome("test tests::a - should panic ... ok").unwrap();
+        assert_eq!(result.name, "tests::a");
+
+        let result =
+            parse_test_outcome("test src/lib.rs - passing_doctest (line 3) ... ok").unwrap();
+        assert_eq!(result.name, "src/lib.rs - passing_doctest (line 3)");
+    }
+
+    #[test]
+    fn parse_for_reported_times() {
+        let result = parse_test_outcome("test tests::a ... ok <0.003s>").unwrap();
+        assert_eq!(result.name, "tests::a");
+        assert_eq!(result.result, TestResult::Passed);
+        assert_eq!(result.duration, Some(Duration::from_millis(3)));
+
+        let result = parse_test_outcome("test tests::b ... FAILED <1.250s>").unwrap();
+        assert_eq!(result.result, TestResult::Failed);
+        assert_eq!(result.duration, Some(Duration::from_millis(1250)));
+
+        let result = parse_test_outcome("test tests::c ... ok").unwrap();
+        assert_eq!(result.duration, None);
+    }
+}
+
+#[cfg(test)]
+mod parse_test_run_tests {
+    use super::*;
+
+    #[test]
+    fn parse_for_empty_data() {
+        assert!(parse_test_run("").is_empty());
+    }
+
+    #[test]
+    fn parse_for_outcomes_before_any_binary() {
+        assert!(parse_test_run("test tests::a ... ok").is_empty());
+    }
+
+    #[test]
+    fn parse_for_multiple_binaries_and_doc_tests() {
+        let input = "    Finished test [unoptimized + debuginfo] target(s) in 0.05s
+     Running target/debug/deps/example_bin_tests-b371342d81493fca
+
+running 2 tests
+test tests::failing_test1 ... FAILED
+test tests::passing_printing_test ... ok
+
+failures:
+
+---- tests::failing_test1 stdout ----
+hello from a test
+
+     Running unittests src/lib.rs (target/debug/deps/example_lib_tests-35c4554393436661)
+
+running 1 test
+test tests::ignored_test ... ignored
+
+   Doc-tests example_lib_tests
+
+running 1 test
+test src/lib.rs - passing_doctest (line 3) ... ok
+";
+
+        let results = parse_test_run(input);
+        assert_eq!(results.len(), 3);
+
+        assert_eq!(results[0].crate_name.basename, "example_bin_tests");
+        assert_eq!(results[0].crate_name.uuid, "b371342d81493fca");
+        assert!(!results[0].is_doc_tests);
+        assert_eq!(results[0].outcomes.len(), 2);
+        assert_eq!(results[0].outcomes[0].result, TestResult::Failed);
+        assert_eq!(results[0].outcomes[1].result, TestResult::Passed);
+
+        assert_eq!(results[1].crate_name.basename, "example_lib_tests");
+        assert_eq!(results[1].crate_name.uuid, "35c4554393436661");
+        assert_eq!(results[1].outcomes[0].result, TestResult::Ignored);
+
+        assert_eq!(results[2].crate_name.basename, "example_lib_tests");
+        assert!(results[2].is_doc_tests);
+        assert_eq!(
+            results[2].outcomes[0].name,
+            "src/lib.rs - passing_doctest (line 3)"
+        );
+    }
+
+    #[test]
+    fn parse_for_binary_times() {
+        let input = "     Running target/debug/deps/example_bin_tests-b371342d81493fca
+
+running 1 test
+test tests::passing_test ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.25s
+
+     Running target/debug/deps/example_lib_tests-35c4554393436661
+
+running 1 test
+";
+
+        let results = parse_test_run(input);
+        assert_eq!(results[0].duration, Some(Duration::from_millis(250)));
+        assert_eq!(results[1].duration, None);
+    }
+}
+
+#[cfg(test)]
+mod parse_test_run_line_tests {
+    use super::*;
+
+    #[test]
+    fn parse_for_unrecognised_lines() {
+        assert!(parse_test_run_line("").is_none());
+        assert!(parse_test_run_line("running 2 tests").is_none());
+        assert!(parse_test_run_line("hello from a test").is_none());
+    }
+
+    #[test]
+    fn parse_for_section_headings() {
+        match parse_test_run_line(
+            "     Running unittests src/lib.rs (target/debug/deps/example_lib_tests-35c4554393436661)",
+        ) {
+            Some(TestRunLine::Section {
+                crate_name,
+                is_doc_tests,
+            }) => {
+                assert_eq!(crate_name.basename, "example_lib_tests");
+                assert_eq!(crate_name.uuid, "35c4554393436661");
+                assert!(!is_doc_tests);
+            }
+            line => panic!("Unexpected {:?}", line),
+        }
+
+        match parse_test_run_line("   Doc-tests example_lib_tests") {
+            Some(TestRunLine::Section {
+                crate_name,
+                is_doc_tests,
+            }) => {
+                assert_eq!(crate_name.basename, "example_lib_tests");
+                assert!(is_doc_tests);
+            }
+            line => panic!("Unexpected {:?}", line),
+        }
+    }
+
+    #[test]
+    fn parse_for_outcomes_and_summaries() {
+        match parse_test_run_line("test tests::a ... ok") {
+            Some(TestRunLine::Outcome(outcome)) => {
+                assert_eq!(outcome.name, "tests::a");
+                assert_eq!(outcome.result, TestResult::Passed);
+            }
+            line => panic!("Unexpected {:?}", line),
+        }
+
+        match parse_test_run_line(
+            "test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.25s",
+        ) {
+            Some(TestRunLine::Finished(duration)) => {
+                assert_eq!(duration, Duration::from_millis(250))
+            }
+            line => panic!("Unexpected {:?}", line),
+        }
+    }
+}
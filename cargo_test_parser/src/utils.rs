@@ -1,4 +1,5 @@
 use crate::{parse_context::ParseContext, parse_error::ParseError};
+use std::time::Duration;
 
 /// Splits the input into the part before and the part after
 /// the character at `idx` (that character is not included in
@@ -47,6 +48,16 @@ pub fn parse_leading_usize(data: &str) -> Option<usize> {
     data.parse().ok()
 }
 
+/// Parses a number of seconds as printed by libtest, for example "0.003s".
+pub fn parse_seconds(data: &str) -> Option<Duration> {
+    let seconds = data.strip_suffix('s')?.parse::<f64>().ok()?;
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,4 +140,18 @@ mod tests {
         assert_eq!(parse_leading_usize("122abc"), Some(122));
         assert_eq!(parse_leading_usize("43 tests"), Some(43));
     }
+
+    #[test]
+    fn parse_seconds_for_bad_data() {
+        assert!(parse_seconds("").is_none());
+        assert!(parse_seconds("0.5").is_none());
+        assert!(parse_seconds("-1.0s").is_none());
+        assert!(parse_seconds("abcs").is_none());
+    }
+
+    #[test]
+    fn parse_seconds_for_good_data() {
+        assert_eq!(parse_seconds("0.003s"), Some(Duration::from_millis(3)));
+        assert_eq!(parse_seconds("2s"), Some(Duration::from_secs(2)));
+    }
 }
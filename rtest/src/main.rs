@@ -4,7 +4,7 @@ use std::{io::Write, sync::mpsc::channel};
 
 use rtest_core::{
     configuration,
-    engine::JobEngine,
+    engine::{JobEngine, ShutdownMode},
     jobs::{FileSyncJob, ShadowCopyJob},
     source_directory_watcher,
     state::State,
@@ -20,34 +20,43 @@ fn main() {
     let config = configuration::new();
     info!("{:?}", config);
 
-    let state = State::new(config);
+    let state = State::load(config.clone());
+    let engine = JobEngine::new(config.clone(), state.clone());
 
-    // let engine = JobEngine::new(config.clone(), state.clone());
+    // If a shadow copy operation is required, kick one off.
+    let mut watch = None;
+    if config.destination.is_copying() {
+        let job = ShadowCopyJob::new(config.destination.clone());
+        engine.add_job(job);
 
-    // // If a shadow copy operation is required, kick one off.
-    // if config.destination.is_copying() {
-    //     let job = ShadowCopyJob::new(config.destination.clone());
-    //     engine.add_job(job);
+        // Then watch for incremental file changes. Use another thread to
+        // add jobs to the engine.
+        let (sender, receiver) = channel::<FileSyncEvent>();
+        let watcher = source_directory_watcher::start_watching(config.source_directory(), sender);
 
-    //     // Then watch for incremental file changes. Use another thread to
-    //     // add jobs to the engine.
-    //     let (sender, receiver) = channel::<FileSyncEvent>();
-    //     source_directory_watcher::start_watching(&config.source_directory(), sender);
+        let forwarder = std::thread::spawn({
+            let engine = engine.clone();
+            let dest = config.destination.clone();
 
-    //     std::thread::spawn({
-    //         let engine = engine.clone();
-    //         let dest = config.destination.clone();
+            move || {
+                for event in receiver {
+                    let job = FileSyncJob::new(dest.clone(), event);
+                    engine.add_job(job);
+                }
+            }
+        });
 
-    //         move || {
-    //             for event in receiver {
-    //                 let job = FileSyncJob::new(dest.clone(), event);
-    //                 engine.add_job(job);
-    //             }
-    //         }
-    //     });
-    // }
+        watch = Some((watcher, forwarder));
+    }
 
-    ui::show_main_window(state.clone());
+    ui::show_main_window(state, engine.clone());
+
+    // Stopping the watcher drops the sender, which ends the forwarding thread.
+    if let Some((watcher, forwarder)) = watch {
+        watcher.stop();
+        let _ = forwarder.join();
+    }
+    engine.shutdown(ShutdownMode::Cancel);
 
     info!("Stopping {}", env!("CARGO_PKG_NAME"));
 }
@@ -2,9 +2,12 @@ use gio::prelude::*;
 use gtk::prelude::*;
 use gtk::*;
 use log::info;
-use rtest_core::state::State;
+use rtest_core::{configuration::BuildMode, engine::JobEngine, state::State};
 
-pub fn show_main_window(state: State) {
+/// The columns of the test list, see `fill_test_list`.
+const TEST_COLUMNS: &[&str] = &["Crate", "Test", "Kind", "Mode", "State"];
+
+pub fn show_main_window(state: State, engine: JobEngine) {
     let application = gtk::Application::new(Some("philipdaniels.com.rtest"), Default::default())
         .expect("Initialization failed...");
 
@@ -17,7 +20,7 @@ pub fn show_main_window(state: State) {
 
         // Load the window UI
         let builder = Builder::from_resource("/rtest/main_window.glade");
-        connect_callbacks(&builder, state.clone());
+        connect_callbacks(&builder, state.clone(), engine.clone());
 
         // Get a reference to the window
         let window: ApplicationWindow = builder
@@ -33,7 +36,14 @@ pub fn show_main_window(state: State) {
     application.run(&args);
 }
 
-fn connect_callbacks(builder: &Builder, state: State) {
+fn connect_callbacks(builder: &Builder, state: State, engine: JobEngine) {
+    let tests = create_test_list(builder);
+    fill_test_list(&tests, &state);
+    let status = builder
+        .get_object::<Label>("lblStatus")
+        .expect("Couldn't get lblStatus");
+    show_status(&status, &engine);
+
     let menu = builder
         .get_object::<MenuItem>("mnuRedo")
         .expect("Couldn't get mnuRedo");
@@ -46,13 +56,78 @@ fn connect_callbacks(builder: &Builder, state: State) {
     let menu = builder
         .get_object::<MenuItem>("mnuRefresh")
         .expect("Couldn't get mnuRefresh");
-    menu.connect_activate(move |_mnu| on_mnu_refresh_activated(_mnu, state.clone()));
+    menu.connect_activate(move |_mnu| {
+        on_mnu_refresh_activated(_mnu, state.clone(), &tests);
+        show_status(&status, &engine);
+    });
+}
+
+/// Shows why the tests are being run by cargo when the test runner
+/// was asked to run the test binaries directly, if they are.
+fn show_status(status: &Label, engine: &JobEngine) {
+    match engine.direct_run_fallback() {
+        Some(reason) => status.set_text(&format!("Running the tests with cargo: {}", reason)),
+        None => status.set_text(""),
+    }
+}
+
+/// Creates the model of the test list and the columns that show it.
+fn create_test_list(builder: &Builder) -> ListStore {
+    let tree = builder
+        .get_object::<TreeView>("tvTests")
+        .expect("Couldn't get tvTests");
+
+    let store = ListStore::new(&[glib::Type::String; 5]);
+    tree.set_model(Some(&store));
+
+    for (idx, title) in TEST_COLUMNS.iter().enumerate() {
+        let column = TreeViewColumn::new();
+        column.set_title(title);
+        column.set_resizable(true);
+        let cell = CellRendererText::new();
+        column.pack_start(&cell, true);
+        column.add_attribute(&cell, "text", idx as i32);
+        tree.append_column(&column);
+    }
+
+    store
+}
+
+/// Shows every test that the `State` knows about, the doc tests
+/// alongside the unit tests of the same crate. Results that were loaded
+/// from the previous session, and are not yet replaced, are marked as such.
+fn fill_test_list(store: &ListStore, state: &State) {
+    store.clear();
+
+    for &build_mode in &[BuildMode::Debug, BuildMode::Release] {
+        for test in state.tests(build_mode) {
+            let kind = match test.doc_test {
+                Some(location) => {
+                    format!("Doc test ({}:{})", location.file_name, location.line_number)
+                }
+                None => "Unit test".to_string(),
+            };
+            let mode = format!("{:?}", build_mode);
+            let test_state = if test.from_previous_session {
+                format!("{:?} (previous session)", test.state)
+            } else {
+                format!("{:?}", test.state)
+            };
+
+            store.insert_with_values(
+                None,
+                &[0, 1, 2, 3, 4],
+                &[&test.crate_basename, &test.name, &kind, &mode, &test_state],
+            );
+        }
+    }
 }
 
 fn on_mnu_redo_activated(_mnu: &MenuItem, mut state: State) {
     info!("on_mnu_redo_activated free function")
 }
 
-fn on_mnu_refresh_activated(_mnu: &MenuItem, state: State) {
-    info!("on_mnu_refresh_activated free function")
+fn on_mnu_refresh_activated(_mnu: &MenuItem, state: State, tests: &ListStore) {
+    info!("on_mnu_refresh_activated free function");
+    fill_test_list(tests, &state);
 }
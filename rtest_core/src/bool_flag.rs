@@ -0,0 +1,33 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Atomic reference counted bool flag.
+/// It is safe to use and call this from multiple threads.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct BoolFlag {
+    flag: Arc<AtomicBool>,
+}
+
+impl BoolFlag {
+    pub(crate) fn is_true(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_false(&self) -> bool {
+        !self.is_true()
+    }
+
+    pub(crate) fn set(&self, value: bool) {
+        self.flag.store(value, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_true(&self) {
+        self.set(true);
+    }
+
+    pub(crate) fn set_false(&self) {
+        self.set(false);
+    }
+}
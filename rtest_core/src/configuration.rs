@@ -1,11 +1,21 @@
-use crate::shadow_copy_destination::ShadowCopyDestination;
+use crate::{
+    job_history::RetentionPolicy,
+    jobs::{JobType, RetryPolicy},
+    pipeline::{FailurePolicy, Pipeline, PipelineStage},
+    shadow_copy_destination::ShadowCopyDestination,
+    test_selection::IgnoredTests,
+    test_settings::{parse_env_var, Backtrace, TestExecutionSettings, TestSettings},
+};
 use clap::{App, Arg};
 use log::info;
+use serde::{Deserialize, Serialize};
 use std::{
+    num::NonZeroUsize,
     ops::Deref,
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 /// Represents the global configuration of `rtest` during one run.
@@ -34,12 +44,53 @@ pub enum CompilationMode {
 
 /// The `BuildMode` is used to parameterise invocations
 /// of cargo subprocesses - i.e. do we add "--release"?.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum BuildMode {
     Debug,
     Release,
 }
 
+/// Specifies which tests the pipeline's run-tests stage runs, based
+/// on the results of the previous run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunStrategy {
+    /// Run every test.
+    All,
+    /// Run only the tests that failed last time, or every test if none did.
+    FailedOnly,
+    /// Run the tests that failed last time, then, if they now pass, the rest.
+    FailedFirst,
+}
+
+/// Specifies how the tests are run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestRunner {
+    /// Run the tests using `cargo test`.
+    Cargo,
+    /// Run the test binaries that were built by the pipeline directly, several
+    /// at a time. This avoids the overhead of cargo checking that everything is
+    /// up to date, and cargo running the binaries one after another. Doc tests
+    /// have no binaries, so they are still run using `cargo test --doc`.
+    Direct,
+    /// Like `Direct`, but each test is run in a process of its own, so a test
+    /// that crashes the process (by segfaulting, aborting or calling
+    /// `std::process::exit`) doesn't stop the rest of the tests from running.
+    Isolated,
+}
+
+impl CompilationMode {
+    /// Returns the build modes that must be executed to satisfy this
+    /// compilation mode, in the order they should be executed.
+    pub fn build_modes(&self) -> &'static [BuildMode] {
+        match self {
+            CompilationMode::None => &[],
+            CompilationMode::Debug => &[BuildMode::Debug],
+            CompilationMode::Release => &[BuildMode::Release],
+            CompilationMode::Both => &[BuildMode::Debug, BuildMode::Release],
+        }
+    }
+}
+
 impl Deref for Configuration {
     type Target = InnerConfiguration;
     fn deref(&self) -> &Self::Target {
@@ -57,6 +108,134 @@ pub fn new() -> Configuration {
     }
 }
 
+impl Configuration {
+    /// Returns a builder for a configuration that does not come from the
+    /// command line, for use by tests and other tools that embed the engine.
+    /// Everything has the same default as on the command line, except that
+    /// by default `source` is used in place rather than being shadow copied.
+    pub fn builder<P: Into<PathBuf>>(source: P) -> ConfigurationBuilder {
+        ConfigurationBuilder {
+            args: CommandLineArguments::with_defaults(source.into()),
+        }
+    }
+}
+
+/// Builds a `Configuration` without using the command line.
+/// See `Configuration::builder`.
+#[derive(Debug, Clone)]
+pub struct ConfigurationBuilder {
+    args: CommandLineArguments,
+}
+
+impl ConfigurationBuilder {
+    /// Shadow copies the source directory to `destination`, which should be empty.
+    pub fn shadow_copy_to<P: Into<PathBuf>>(mut self, destination: P) -> Self {
+        self.args.do_shadow_copy = true;
+        self.args.destination = Some(destination.into());
+        self
+    }
+
+    pub fn build_mode(mut self, build_mode: CompilationMode) -> Self {
+        self.args.build_mode = build_mode;
+        self
+    }
+
+    pub fn test_mode(mut self, test_mode: CompilationMode) -> Self {
+        self.args.test_mode = test_mode;
+        self
+    }
+
+    pub fn build_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.args.build_timeout = timeout;
+        self
+    }
+
+    pub fn list_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.args.list_timeout = timeout;
+        self
+    }
+
+    pub fn run_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.args.run_timeout = timeout;
+        self
+    }
+
+    pub fn job_retention_policy(mut self, policy: RetentionPolicy) -> Self {
+        self.args.job_retention_policy = policy;
+        self
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.args.retry_policy = policy;
+        self
+    }
+
+    pub fn pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.args.pipeline = pipeline;
+        self
+    }
+
+    pub fn run_strategy(mut self, run_strategy: RunStrategy) -> Self {
+        self.args.run_strategy = run_strategy;
+        self
+    }
+
+    pub fn ignored_tests(mut self, ignored_tests: IgnoredTests) -> Self {
+        self.args.ignored_tests = ignored_tests;
+        self
+    }
+
+    pub fn flaky_reruns(mut self, reruns: usize) -> Self {
+        self.args.flaky_reruns = reruns;
+        self
+    }
+
+    pub fn duration_regression_threshold(mut self, percent: usize) -> Self {
+        self.args.duration_regression_threshold = percent;
+        self
+    }
+
+    pub fn test_runner(mut self, test_runner: TestRunner) -> Self {
+        self.args.test_runner = test_runner;
+        self
+    }
+
+    pub fn test_binary_threads(mut self, threads: usize) -> Self {
+        self.args.test_binary_threads = threads;
+        self
+    }
+
+    /// Sets the settings that tests are run with, except in packages
+    /// that have settings of their own.
+    pub fn test_settings(mut self, settings: TestSettings) -> Self {
+        self.args.test_settings.default = settings;
+        self
+    }
+
+    /// Sets the settings that the tests in `package` are run with.
+    pub fn package_test_settings<S: Into<String>>(
+        mut self,
+        package: S,
+        settings: TestSettings,
+    ) -> Self {
+        self.args
+            .test_settings
+            .packages
+            .insert(package.into(), settings);
+        self
+    }
+
+    pub fn build(self) -> Configuration {
+        let destination = self.args.make_shadow_copy_destination();
+        Configuration {
+            inner: Arc::new(InnerConfiguration {
+                args: self.args,
+                destination,
+            }),
+        }
+    }
+}
+
 impl InnerConfiguration {
     pub fn build_mode(&self) -> CompilationMode {
         self.args.build_mode
@@ -70,6 +249,105 @@ impl InnerConfiguration {
         &self.args.source
     }
 
+    /// Returns the per-project directory that rtest uses to store its own data.
+    /// This is inside the source's target directory, so it is ignored by the
+    /// directory watcher and removed by `cargo clean`.
+    pub fn rtest_directory(&self) -> PathBuf {
+        self.args.source.join("target").join("rtest")
+    }
+
+    /// Returns the directory that job records evicted from memory are written to.
+    pub fn job_history_directory(&self) -> PathBuf {
+        self.rtest_directory().join("history")
+    }
+
+    /// Returns the file that the `State` is saved to, so that the
+    /// results of the tests are still known after a restart.
+    pub fn state_file(&self) -> PathBuf {
+        self.rtest_directory().join("state.json")
+    }
+
+    /// Returns the policy that determines how many completed jobs are kept in memory.
+    pub fn job_retention_policy(&self) -> RetentionPolicy {
+        self.args.job_retention_policy
+    }
+
+    /// Returns the maximum time a job of the specified type is allowed to run for,
+    /// or `None` if it can run forever. Only jobs that run child processes can
+    /// be timed out. A stress test's iterations are timed out individually.
+    pub fn job_timeout(&self, job_type: JobType) -> Option<Duration> {
+        match job_type {
+            JobType::ShadowCopy | JobType::FileSync => None,
+            JobType::BuildAllTests | JobType::BuildWorkspace | JobType::CargoCommand => {
+                self.args.build_timeout
+            }
+            JobType::ListAllTests => self.args.list_timeout,
+            JobType::RunTests | JobType::StressTest => self.args.run_timeout,
+            JobType::LoadWorkspace => Some(METADATA_TIMEOUT),
+        }
+    }
+
+    /// Returns the policy that determines whether a failed job of the specified type
+    /// is retried. Only jobs that run cargo are retried, and then only if they failed
+    /// for a reason unrelated to the code being compiled, such as a locked file.
+    /// Stress tests are never retried, because their failures are the point.
+    pub fn job_retry_policy(&self, job_type: JobType) -> RetryPolicy {
+        match job_type {
+            JobType::ShadowCopy | JobType::FileSync | JobType::StressTest => RetryPolicy::never(),
+            JobType::BuildAllTests
+            | JobType::BuildWorkspace
+            | JobType::ListAllTests
+            | JobType::RunTests
+            | JobType::CargoCommand
+            | JobType::LoadWorkspace => self.args.retry_policy,
+        }
+    }
+
+    /// Returns the pipeline of jobs that is run whenever the source files change.
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.args.pipeline
+    }
+
+    /// Returns the strategy the engine starts with. It can be changed
+    /// while rtest is running, see `JobEngine::set_run_strategy`.
+    pub fn run_strategy(&self) -> RunStrategy {
+        self.args.run_strategy
+    }
+
+    /// Returns whether the pipeline runs ignored tests when rtest starts. It
+    /// can be changed while rtest is running, see `JobEngine::set_ignored_tests`.
+    pub fn ignored_tests(&self) -> IgnoredTests {
+        self.args.ignored_tests
+    }
+
+    /// Returns the number of times a failing test is rerun to find out
+    /// whether it is flaky. Zero means that failing tests aren't rerun.
+    pub fn flaky_reruns(&self) -> usize {
+        self.args.flaky_reruns
+    }
+
+    /// Returns how much slower than its average, in percent, a test has
+    /// to be to count as a regression, see `State::duration_regressions`.
+    pub fn duration_regression_threshold(&self) -> usize {
+        self.args.duration_regression_threshold
+    }
+
+    pub fn test_runner(&self) -> TestRunner {
+        self.args.test_runner
+    }
+
+    /// Returns the number of test processes that are run at the same
+    /// time when the `TestRunner` is `TestRunner::Direct` or `TestRunner::Isolated`.
+    pub fn test_binary_threads(&self) -> usize {
+        self.args.test_binary_threads
+    }
+
+    /// Returns the libtest arguments and environment variables
+    /// that the tests in each package are run with.
+    pub fn test_settings(&self) -> &TestExecutionSettings {
+        &self.args.test_settings
+    }
+
     /// Resets the destination directory. See `drop` implementatation of
     /// `DestinationDirectory` for details.
     pub fn reset_destination(&mut self) {
@@ -84,6 +362,19 @@ struct CommandLineArguments {
     destination: Option<PathBuf>,
     build_mode: CompilationMode,
     test_mode: CompilationMode,
+    build_timeout: Option<Duration>,
+    list_timeout: Option<Duration>,
+    run_timeout: Option<Duration>,
+    job_retention_policy: RetentionPolicy,
+    retry_policy: RetryPolicy,
+    pipeline: Pipeline,
+    run_strategy: RunStrategy,
+    ignored_tests: IgnoredTests,
+    flaky_reruns: usize,
+    duration_regression_threshold: usize,
+    test_runner: TestRunner,
+    test_binary_threads: usize,
+    test_settings: TestExecutionSettings,
 }
 
 impl FromStr for CompilationMode {
@@ -100,6 +391,32 @@ impl FromStr for CompilationMode {
     }
 }
 
+impl FromStr for RunStrategy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(RunStrategy::All),
+            "failed-only" => Ok(RunStrategy::FailedOnly),
+            "failed-first" => Ok(RunStrategy::FailedFirst),
+            _ => Err("no matching RunStrategy"),
+        }
+    }
+}
+
+impl FromStr for TestRunner {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cargo" => Ok(TestRunner::Cargo),
+            "direct" => Ok(TestRunner::Direct),
+            "isolated" => Ok(TestRunner::Isolated),
+            _ => Err("no matching TestRunner"),
+        }
+    }
+}
+
 fn get_cli_arguments() -> CommandLineArguments {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -125,6 +442,160 @@ fn get_cli_arguments() -> CommandLineArguments {
                 .long("test-mode")
                 .possible_values(&["none", "debug", "release", "both"]),
         )
+        .arg(
+            Arg::with_name("RUN-STRATEGY")
+                .about("Specifies which tests to run, based on the results of the previous run")
+                .short('r')
+                .long("run-strategy")
+                .possible_values(&["all", "failed-only", "failed-first"]),
+        )
+        .arg(
+            Arg::with_name("IGNORED-TESTS")
+                .about("Specifies whether tests marked #[ignore] are run: not at all, only them, or along with the rest")
+                .long("ignored-tests")
+                .possible_values(&["normal", "only", "include"]),
+        )
+        .arg(
+            Arg::with_name("FLAKY-RERUNS")
+                .about("Reruns each failing test this many times to find out whether it is flaky (default: 0, never)")
+                .long("flaky-reruns")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("TEST-RUNNER")
+                .about("Specifies whether tests are run using cargo, by running the test binaries directly, or with each test in its own process")
+                .long("test-runner")
+                .possible_values(&["cargo", "direct", "isolated"]),
+        )
+        .arg(
+            Arg::with_name("TEST-BINARY-THREADS")
+                .about("The number of test processes to run at once with the direct and isolated test runners (default: 4)")
+                .long("test-binary-threads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("TEST-THREADS")
+                .about("The number of threads each test binary runs its tests on (default: 1)")
+                .long("test-threads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("BACKTRACE")
+                .about("Sets RUST_BACKTRACE for the tests (default: inherited)")
+                .long("backtrace")
+                .possible_values(&["0", "1", "full"]),
+        )
+        .arg(
+            Arg::with_name("LOG-FILTER")
+                .about("Sets RUST_LOG for the tests (default: inherited)")
+                .long("log-filter")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("TEST-ENV")
+                .about("Sets an environment variable for the tests, as KEY=VALUE")
+                .long("test-env")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("NOCAPTURE")
+                .about("Shows the output of the tests as they run, rather than after each test")
+                .long("nocapture"),
+        )
+        .arg(
+            Arg::with_name("REPORT-TIME")
+                .about("Records how long each test takes (needs a nightly toolchain)")
+                .long("report-time"),
+        )
+        .arg(
+            Arg::with_name("DURATION-REGRESSION-THRESHOLD")
+                .about("How much slower than usual, in percent, a test has to be to count as a regression (default: 50)")
+                .long("duration-regression-threshold")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("TEST-ARG")
+                .about("Passes an extra argument to the test binaries (e.g. --test-arg=--include-ignored)")
+                .long("test-arg")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .number_of_values(1)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("PACKAGE-TEST-SETTING")
+                .about("Changes a test setting for one package, as package:setting=value (e.g. db:threads=1, db:env=KEY=VALUE)")
+                .long("package-test-setting")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("BUILD-TIMEOUT")
+                .about("Kill builds that take longer than this many seconds (default: no limit)")
+                .long("build-timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("LIST-TIMEOUT")
+                .about("Kill test listings that take longer than this many seconds (default: no limit)")
+                .long("list-timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("RUN-TIMEOUT")
+                .about("Kill test runs that take longer than this many seconds (default: no limit)")
+                .long("run-timeout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("JOB-HISTORY-COUNT")
+                .about("The number of completed jobs to keep in memory (default: 100)")
+                .long("job-history-count")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("JOB-HISTORY-HOURS")
+                .about("Completed jobs older than this are deleted, in memory and on disk (default: no limit)")
+                .long("job-history-hours")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("JOB-HISTORY-MB")
+                .about("The amount of job output to keep in memory, in MB (default: 64)")
+                .long("job-history-mb")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("RETRY-ATTEMPTS")
+                .about("The number of times to try a job that fails for a transient reason (default: 3)")
+                .long("retry-attempts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("RETRY-BACKOFF")
+                .about("Seconds to wait before retrying a job, doubled for each retry (default: 2)")
+                .long("retry-backoff")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("STAGE")
+                .about("Adds a cargo command stage to the pipeline, as name:trigger:args (e.g. clippy:build-tests:clippy)")
+                .long("stage")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("CONTINUE-ON-FAILURE")
+                .about("Runs the stages after the named stage even if it fails")
+                .long("continue-on-failure")
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple(true),
+        )
         .arg("[source] 'The source directory (defaults to cwd)'")
         .arg("[dest] 'The destination directory for shadow copies (defaults to a temp folder)'")
         .get_matches();
@@ -142,6 +613,106 @@ fn get_cli_arguments() -> CommandLineArguments {
         .expect("Invalid BUILD-MODE");
     let test_mode = CompilationMode::from_str(matches.value_of("TEST-MODE").unwrap_or("debug"))
         .expect("Invalid TEST-MODE");
+    let run_strategy = RunStrategy::from_str(matches.value_of("RUN-STRATEGY").unwrap_or("all"))
+        .expect("Invalid RUN-STRATEGY");
+    let ignored_tests =
+        IgnoredTests::from_str(matches.value_of("IGNORED-TESTS").unwrap_or("normal"))
+            .expect("Invalid IGNORED-TESTS");
+    let test_runner = TestRunner::from_str(matches.value_of("TEST-RUNNER").unwrap_or("cargo"))
+        .expect("Invalid TEST-RUNNER");
+
+    let parse_timeout = |name: &str| {
+        matches
+            .value_of(name)
+            .map(|v| Duration::from_secs(v.parse().expect(name)))
+    };
+    let build_timeout = parse_timeout("BUILD-TIMEOUT");
+    let list_timeout = parse_timeout("LIST-TIMEOUT");
+    let run_timeout = parse_timeout("RUN-TIMEOUT");
+
+    let parse_usize = |name: &str| {
+        matches
+            .value_of(name)
+            .map(|v| v.parse::<usize>().expect(name))
+    };
+    let job_retention_policy = RetentionPolicy {
+        max_jobs: parse_usize("JOB-HISTORY-COUNT").or(Some(DEFAULT_JOB_HISTORY_COUNT)),
+        max_age: parse_usize("JOB-HISTORY-HOURS").map(|h| chrono::Duration::hours(h as i64)),
+        max_output_bytes: parse_usize("JOB-HISTORY-MB")
+            .or(Some(DEFAULT_JOB_HISTORY_MB))
+            .map(|mb| mb * 1024 * 1024),
+    };
+
+    let flaky_reruns = parse_usize("FLAKY-RERUNS").unwrap_or(0);
+    let duration_regression_threshold = parse_usize("DURATION-REGRESSION-THRESHOLD")
+        .unwrap_or(DEFAULT_DURATION_REGRESSION_THRESHOLD);
+    let test_binary_threads = parse_usize("TEST-BINARY-THREADS")
+        .unwrap_or(DEFAULT_TEST_BINARY_THREADS)
+        .max(1);
+
+    let mut test_settings = TestExecutionSettings {
+        default: TestSettings {
+            // As with the "threads" test setting, 0 threads is an error.
+            threads: matches.value_of("TEST-THREADS").map_or(1, |v| {
+                v.parse::<NonZeroUsize>()
+                    .expect("Invalid TEST-THREADS")
+                    .get()
+            }),
+            backtrace: matches
+                .value_of("BACKTRACE")
+                .map(|v| Backtrace::from_str(v).expect("Invalid BACKTRACE")),
+            log_filter: matches.value_of("LOG-FILTER").map(Into::into),
+            env: matches
+                .values_of("TEST-ENV")
+                .into_iter()
+                .flatten()
+                .map(|v| parse_env_var(v).expect("Invalid TEST-ENV"))
+                .collect(),
+            nocapture: matches.is_present("NOCAPTURE"),
+            report_time: matches.is_present("REPORT-TIME"),
+            extra_args: matches
+                .values_of("TEST-ARG")
+                .into_iter()
+                .flatten()
+                .map(Into::into)
+                .collect(),
+        },
+        packages: Default::default(),
+    };
+    for setting in matches
+        .values_of("PACKAGE-TEST-SETTING")
+        .into_iter()
+        .flatten()
+    {
+        test_settings
+            .set_for_package(setting)
+            .expect("Invalid PACKAGE-TEST-SETTING");
+    }
+
+    let retry_policy = RetryPolicy {
+        max_attempts: matches
+            .value_of("RETRY-ATTEMPTS")
+            .map_or(DEFAULT_RETRY_POLICY.max_attempts, |v| {
+                v.parse().expect("RETRY-ATTEMPTS")
+            }),
+        backoff: parse_timeout("RETRY-BACKOFF").unwrap_or(DEFAULT_RETRY_POLICY.backoff),
+    };
+
+    let mut pipeline = Pipeline::default();
+    for stage in matches.values_of("STAGE").into_iter().flatten() {
+        let stage = PipelineStage::from_str(stage).expect("Invalid STAGE");
+        pipeline.add_stage(stage).expect("Invalid STAGE");
+    }
+    for name in matches
+        .values_of("CONTINUE-ON-FAILURE")
+        .into_iter()
+        .flatten()
+    {
+        pipeline
+            .stage_mut(name)
+            .expect("Invalid CONTINUE-ON-FAILURE")
+            .on_failure = FailurePolicy::Continue;
+    }
 
     CommandLineArguments {
         do_shadow_copy,
@@ -149,10 +720,66 @@ fn get_cli_arguments() -> CommandLineArguments {
         destination,
         build_mode,
         test_mode,
+        build_timeout,
+        list_timeout,
+        run_timeout,
+        job_retention_policy,
+        retry_policy,
+        pipeline,
+        run_strategy,
+        ignored_tests,
+        flaky_reruns,
+        duration_regression_threshold,
+        test_runner,
+        test_binary_threads,
+        test_settings,
     }
 }
 
+/// `cargo metadata` doesn't resolve dependencies (see `Workspace::metadata_command`),
+/// so it should be quick. If it takes longer than this it is probably stuck on a lock.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(60);
+
+const DEFAULT_JOB_HISTORY_COUNT: usize = 100;
+const DEFAULT_TEST_BINARY_THREADS: usize = 4;
+const DEFAULT_DURATION_REGRESSION_THRESHOLD: usize = 50;
+const DEFAULT_JOB_HISTORY_MB: usize = 64;
+
+const DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    backoff: Duration::from_secs(2),
+};
+
 impl CommandLineArguments {
+    /// The arguments that you get if you don't specify any options,
+    /// except that no shadow copy is made.
+    fn with_defaults(source: PathBuf) -> Self {
+        Self {
+            do_shadow_copy: false,
+            source,
+            destination: None,
+            build_mode: CompilationMode::None,
+            test_mode: CompilationMode::Debug,
+            build_timeout: None,
+            list_timeout: None,
+            run_timeout: None,
+            job_retention_policy: RetentionPolicy {
+                max_jobs: Some(DEFAULT_JOB_HISTORY_COUNT),
+                max_age: None,
+                max_output_bytes: Some(DEFAULT_JOB_HISTORY_MB * 1024 * 1024),
+            },
+            retry_policy: DEFAULT_RETRY_POLICY,
+            pipeline: Pipeline::default(),
+            run_strategy: RunStrategy::All,
+            ignored_tests: IgnoredTests::Normal,
+            flaky_reruns: 0,
+            duration_regression_threshold: DEFAULT_DURATION_REGRESSION_THRESHOLD,
+            test_runner: TestRunner::Cargo,
+            test_binary_threads: DEFAULT_TEST_BINARY_THREADS,
+            test_settings: Default::default(),
+        }
+    }
+
     pub fn make_shadow_copy_destination(&self) -> ShadowCopyDestination {
         if self.do_shadow_copy {
             if self.destination.is_none() {
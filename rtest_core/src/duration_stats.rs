@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, time::Duration};
+
+/// The number of durations kept for each test and test binary.
+const MAX_SAMPLES: usize = 50;
+
+/// The number of earlier durations needed before a
+/// slower one can be considered to be a regression.
+const MIN_HISTORY: usize = 3;
+
+/// A test has to be at least this much slower than usual to count as a
+/// regression, otherwise very quick tests are flagged because of noise.
+const MIN_SLOWDOWN: Duration = Duration::from_millis(10);
+
+/// The durations of the most recent runs of a test or a test binary.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DurationHistory {
+    /// Oldest first, at most `MAX_SAMPLES` of them.
+    samples: VecDeque<Duration>,
+}
+
+/// Statistics about how long a test or a test binary takes to run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DurationStats {
+    pub last: Duration,
+    pub mean: Duration,
+    /// The 95th percentile, using the nearest-rank method.
+    pub p95: Duration,
+    /// The number of runs the statistics are based on.
+    pub runs: usize,
+}
+
+/// An entry in the report of the slowest tests, see `State::slowest_tests`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowTest {
+    /// See `CrateName::basename`.
+    pub crate_basename: String,
+    pub test: String,
+    pub stats: DurationStats,
+}
+
+/// An entry in the report of the slowest test binaries,
+/// see `State::slowest_binaries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowBinary {
+    /// See `CrateName::basename`.
+    pub crate_basename: String,
+    pub stats: DurationStats,
+}
+
+/// A test that took much longer than usual the last time it was
+/// run, see `State::duration_regressions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DurationRegression {
+    /// See `CrateName::basename`.
+    pub crate_basename: String,
+    pub test: String,
+    pub last: Duration,
+    /// The mean of the durations before the last one.
+    pub previous_mean: Duration,
+}
+
+impl DurationHistory {
+    /// Adds the duration of the latest run, forgetting the oldest if need be.
+    pub fn record(&mut self, duration: Duration) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(duration);
+    }
+
+    /// Returns the statistics, or `None` if no durations have been recorded.
+    pub fn stats(&self) -> Option<DurationStats> {
+        let last = *self.samples.back()?;
+        let mut sorted = self.samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort();
+        let rank = (sorted.len() * 95).div_ceil(100);
+
+        Some(DurationStats {
+            last,
+            mean: mean(&sorted),
+            p95: sorted[rank - 1],
+            runs: sorted.len(),
+        })
+    }
+
+    /// Returns the mean of the durations before the last one if the last one
+    /// is more than `threshold_percent` slower than it. There has to be
+    /// enough history for the comparison to mean something.
+    pub fn regression(&self, threshold_percent: usize) -> Option<Duration> {
+        let mut previous = self.samples.iter().copied().collect::<Vec<_>>();
+        let last = previous.pop()?;
+        if previous.len() < MIN_HISTORY {
+            return None;
+        }
+
+        let previous_mean = mean(&previous);
+        let limit = previous_mean.mul_f64(1.0 + threshold_percent as f64 / 100.0);
+        if last > limit && last >= previous_mean + MIN_SLOWDOWN {
+            Some(previous_mean)
+        } else {
+            None
+        }
+    }
+}
+
+fn mean(durations: &[Duration]) -> Duration {
+    if durations.is_empty() {
+        Duration::default()
+    } else {
+        durations.iter().sum::<Duration>() / durations.len() as u32
+    }
+}
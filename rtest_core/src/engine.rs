@@ -1,18 +1,30 @@
 use crate::{
-    configuration::{BuildMode, Configuration},
+    bool_flag::BoolFlag,
+    configuration::{BuildMode, Configuration, RunStrategy, TestRunner},
+    job_history::{JobHistory, JobStore},
     jobs::{
-        BuildAllTestsJob, CompletedJob, CompletionStatus, Job, JobKind, ListAllTestsJob, PendingJob,
-        RunTestsJob,
+        CompletedJob, CompletionStatus, DirectRun, ExecutingJob, ExecutionContext, Job, JobId,
+        JobKind, JobOutput, JobSnapshot, JobType, LoadWorkspaceJob, PendingJob, ProcessRunner,
+        ResourceTotals, RunTestsJob, StressOptions, StressTestJob, SystemProcessRunner,
     },
-    thread_clutch::ThreadClutch, state::State,
+    pipeline::{PipelineProgress, StageAction},
+    source_revision::source_revision,
+    state::State,
+    test_history::TestRunInfo,
+    test_selection::{IgnoredTests, TestBinaries, TestFilter, TestSelection, TestTarget},
+    test_settings::TestExecutionSettings,
+    utils::plural_s,
+    workspace::ChangedPackages,
 };
-use log::info;
-use std::collections::VecDeque;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex, MutexGuard,
+    mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    Arc, Mutex, MutexGuard,
 };
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 /*
 * While a job is executing, the GUI needs to update to show the latest status.
@@ -39,160 +51,866 @@ pub struct JobEngine {
     /// The list of pending (yet to be executed) jobs.
     pending_jobs: Arc<Mutex<VecDeque<PendingJob>>>,
 
-    executing_job: Arc<Mutex<Option<PendingJob>>>,
+    /// The job that is currently executing, if any.
+    /// When more than one of `pending_jobs`, `executing_job` and `completed_jobs`
+    /// need to be locked they must be locked in that order, which is the order in
+    /// which jobs pass through them. This allows `snapshot` to see a consistent view.
+    executing_job: Arc<Mutex<Option<ExecutingJob>>>,
 
-    /// The list of completed jobs.
-    completed_jobs: Arc<Mutex<VecDeque<CompletedJob>>>,
+    /// The list of completed jobs. Older jobs are moved to disk
+    /// according to the configured retention policy.
+    completed_jobs: Arc<Mutex<JobHistory>>,
 
-    /// A clutch that allows us to pause and restart the JOB_STARTER thread.
-    /// This basically allows us to pause the entire job queue, because if we
-    /// don't start to execute new jobs, nothing happens. Yet we can still
-    /// add new jobs to the queue, because that is controlled by a different thread.
-    job_starter_clutch: ThreadClutch,
+    /// Used to send commands to the JOB_EXECUTOR thread.
+    commands: Sender<EngineCommand>,
 
-    /// The `job_added_signal` is notified when a new job is added to the pending queue.
-    /// This will cause the JOB_STARTER thread to wake up (it goes to sleep when
-    /// there are no pending jobs).
-    job_added_signal: Arc<Condvar>,
+    /// Set while the engine is paused. Only the JOB_EXECUTOR thread changes
+    /// this, in response to the `Pause` and `Resume` commands.
+    paused: BoolFlag,
 
-    build_tests_required: BoolFlag,
-    list_tests_required: BoolFlag,
-    run_tests_required: BoolFlag,
+    /// The JOB_EXECUTOR thread, joined by `shutdown`.
+    executor_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+
+    /// Tracks which stages of the configured pipeline need to run.
+    pipeline_progress: PipelineProgress,
+
+    /// Determines which tests the pipeline's run-tests stage runs.
+    run_strategy: Arc<Mutex<RunStrategy>>,
+
+    /// Determines whether the pipeline's run-tests stage runs ignored tests.
+    ignored_tests: Arc<Mutex<IgnoredTests>>,
+
+    /// The packages that need to be rebuilt and retested because of changes
+    /// to the source files.
+    changed_packages: Arc<ChangedPackages>,
+
+    /// Why the tests are run by cargo even though the `TestRunner` asks for
+    /// the test binaries to be run directly, if they are.
+    direct_run_fallback: Arc<Mutex<Option<String>>>,
+
+    /// The settings that tests are run with, shared by every job's `ExecutionContext`.
+    test_settings: Arc<TestExecutionSettings>,
+
+    /// Used by the jobs to run cargo.
+    process_runner: Arc<dyn ProcessRunner>,
+}
+
+/// The commands that drive the JOB_EXECUTOR thread. Everything that can
+/// affect what the engine does next arrives on the same channel, so the
+/// thread only has one thing to wait for and cannot miss anything.
+enum EngineCommand {
+    AddJob(PendingJob),
+    Pause,
+    Resume,
+    /// Cancel the executing job.
+    Cancel,
+    Shutdown(ShutdownMode),
+    /// Sent by the JOB_WORKER thread when it has finished executing a job.
+    JobCompleted(CompletedJob),
+}
+
+/// A job that is executing on a JOB_WORKER thread.
+struct RunningJob {
+    context: ExecutionContext,
+    worker_thread: JoinHandle<()>,
+}
+
+/// A consistent view of all the jobs known to the engine, as returned by
+/// `JobEngine::snapshot`. Every job appears in exactly one of the lists.
+#[derive(Debug, Clone)]
+pub struct EngineSnapshot {
+    /// True if the engine has been paused.
+    pub paused: bool,
+
+    /// The pending jobs, in the order they will be executed.
+    pub pending: Vec<JobSnapshot>,
+
+    /// The job that is currently executing, if any.
+    pub executing: Option<JobSnapshot>,
+
+    /// The completed jobs that are held in memory, oldest first. Older
+    /// jobs have been moved to the `JobStore`.
+    pub completed: Vec<JobSnapshot>,
+}
+
+/// Specifies what happens to the pending jobs when the engine is shut down.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Execute all the pending jobs, then stop.
+    Drain,
+    /// Discard the pending jobs and cancel the executing job, killing any
+    /// child process it has started.
+    Cancel,
 }
 
 impl JobEngine {
     /// Creates a new job engine that is running and ready to process jobs.
     pub fn new(configuration: Configuration, state: State) -> Self {
+        Self::with_process_runner(configuration, state, Arc::new(SystemProcessRunner))
+    }
+
+    /// Creates a new job engine whose jobs run their child processes
+    /// using `process_runner`.
+    pub fn with_process_runner(
+        configuration: Configuration,
+        state: State,
+        process_runner: Arc<dyn ProcessRunner>,
+    ) -> Self {
+        let completed_jobs = JobHistory::new(
+            configuration.job_retention_policy(),
+            JobStore::new(configuration.job_history_directory()),
+        );
+
+        let pipeline_progress = PipelineProgress::new(configuration.pipeline());
+        let run_strategy = Arc::new(Mutex::new(configuration.run_strategy()));
+        let ignored_tests = Arc::new(Mutex::new(configuration.ignored_tests()));
+        let test_settings = Arc::new(configuration.test_settings().clone());
+        let (sender, receiver) = mpsc::channel();
+
         let this = Self {
             configuration,
             state,
             pending_jobs: Default::default(),
             executing_job: Default::default(),
-            completed_jobs: Default::default(),
-            job_starter_clutch: Default::default(),
-            job_added_signal: Default::default(),
-            build_tests_required: Default::default(),
-            list_tests_required: Default::default(),
-            run_tests_required: Default::default(),
+            completed_jobs: Arc::new(Mutex::new(completed_jobs)),
+            commands: sender,
+            paused: Default::default(),
+            executor_thread: Default::default(),
+            pipeline_progress,
+            run_strategy,
+            ignored_tests,
+            changed_packages: Default::default(),
+            direct_run_fallback: Default::default(),
+            test_settings,
+            process_runner,
         };
 
         // Start the JOB_EXECUTOR thread. This thread picks jobs off the front
         // of the queue and executes them one at a time.
         let builder = thread::Builder::new().name("JOB_EXECUTOR".into());
-        builder
+        let handle = builder
             .spawn({
                 let mut this = this.clone();
-                move || this.execute_jobs()
+                move || this.execute_jobs(receiver)
             })
             .expect("Cannot create JOB_EXECUTOR thread");
+        *this.executor_thread.lock().unwrap() = Some(handle);
 
         this
     }
 
+    /// Stops the engine and waits for the JOB_EXECUTOR thread to finish.
+    /// No more jobs are accepted once this has been called, and no further
+    /// pipeline jobs (build, list, run tests) are started. A paused engine
+    /// is resumed so that it can stop.
+    pub fn shutdown(&self, mode: ShutdownMode) {
+        info!("JobEngine shutting down, mode={:?}", mode);
+        self.send(EngineCommand::Shutdown(mode));
+
+        let handle = self.executor_thread.lock().unwrap().take();
+        if let Some(handle) = handle {
+            if handle.thread().id() == thread::current().id() {
+                warn!("JobEngine::shutdown called on the JOB_EXECUTOR thread, it cannot be joined");
+                return;
+            }
+
+            if handle.join().is_err() {
+                warn!("The JOB_EXECUTOR thread panicked");
+            }
+        }
+
+        info!("JobEngine shut down");
+    }
+
+    /// Returns a snapshot of the job queue. The snapshot is taken while
+    /// holding all the queue locks, so a job that is moving from one stage
+    /// to the next appears in exactly one place.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let pending_jobs_guard = self.pending_jobs.lock().unwrap();
+        let executing_job_guard = self.executing_job.lock().unwrap();
+        let completed_jobs_guard = self.completed_jobs.lock().unwrap();
+
+        EngineSnapshot {
+            paused: self.paused.is_true(),
+            pending: pending_jobs_guard.iter().map(JobSnapshot::from).collect(),
+            executing: executing_job_guard.as_ref().map(JobSnapshot::from),
+            completed: completed_jobs_guard.iter().map(JobSnapshot::from).collect(),
+        }
+    }
+
+    /// Returns the resources used by all the jobs completed so far,
+    /// totalled by type.
+    pub fn resource_totals(&self) -> HashMap<JobType, ResourceTotals> {
+        self.completed_jobs.lock().unwrap().resource_totals().clone()
+    }
+
+    /// Returns the output (stdout and stderr) of a job, starting at byte `offset`.
+    /// Pass an offset of 0 to get all of the output. For a job that is still
+    /// executing this is the output so far, and the `next_offset` in the result
+    /// can be passed in the next call to get just the output written since.
+    /// The output of a completed job is its `CompletedJob::transcript`, so the
+    /// offsets stay valid when the job completes.
+    ///
+    /// Completed jobs are looked up in memory first and then in the current
+    /// session of the `JobStore`. Returns `None` if the job cannot be found.
+    pub fn job_output(&self, id: &JobId, offset: usize) -> Option<JobOutput> {
+        {
+            // Jobs move through these in order, so holding all the locks
+            // guarantees that a job in flight is found in one of them.
+            let pending_jobs_guard = self.pending_jobs.lock().unwrap();
+            let executing_job_guard = self.executing_job.lock().unwrap();
+            let completed_jobs_guard = self.completed_jobs.lock().unwrap();
+
+            if pending_jobs_guard.iter().any(|job| job.id() == id) {
+                return Some(JobOutput::live(&Default::default(), offset));
+            }
+
+            if let Some(job) = executing_job_guard.as_ref().filter(|job| job.id() == id) {
+                return Some(JobOutput::live(job.live_output(), offset));
+            }
+
+            let completed_job = completed_jobs_guard.iter().find(|job| job.id() == id);
+            if let Some(job) = completed_job {
+                return Some(JobOutput::completed(job.transcript(), offset));
+            }
+        }
+
+        // Not in memory, so it has been evicted (or never existed). Eviction
+        // writes the record before releasing the lock, so it is on disk now.
+        let store = self.completed_jobs.lock().unwrap().store().clone();
+        match store.load(id) {
+            Ok(record) => Some(JobOutput::completed(&record.transcript, offset)),
+            Err(err) => {
+                info!(
+                    "{} not found in {}, err = {}",
+                    id,
+                    store.session_directory().display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Changes which tests are run by the pipeline. Takes effect from
+    /// the next run of the pipeline's run-tests stage.
+    pub fn set_run_strategy(&self, run_strategy: RunStrategy) {
+        info!("Run strategy set to {:?}", run_strategy);
+        *self.run_strategy.lock().unwrap() = run_strategy;
+    }
+
+    pub fn run_strategy(&self) -> RunStrategy {
+        *self.run_strategy.lock().unwrap()
+    }
+
+    /// Changes whether ignored tests are run by the pipeline. Takes
+    /// effect from the next run of the pipeline's run-tests stage.
+    pub fn set_ignored_tests(&self, ignored_tests: IgnoredTests) {
+        info!("Ignored tests set to {:?}", ignored_tests);
+        *self.ignored_tests.lock().unwrap() = ignored_tests;
+    }
+
+    pub fn ignored_tests(&self) -> IgnoredTests {
+        *self.ignored_tests.lock().unwrap()
+    }
+
+    /// Returns why the tests were last run by cargo rather than directly, as
+    /// the `TestRunner` asks, or `None` if they weren't.
+    pub fn direct_run_fallback(&self) -> Option<String> {
+        self.direct_run_fallback.lock().unwrap().clone()
+    }
+
+    /// Adds a job that runs just the doc tests, with `cargo test --doc`.
+    pub fn run_doc_tests(&self, build_mode: BuildMode) {
+        let filter = TestFilter::workspace().with_target(TestTarget::Doc);
+        let job = RunTestsJob::with_selection(
+            self.configuration.destination.clone(),
+            build_mode,
+            TestSelection::all().with_filter(filter),
+        );
+        self.add_job(job);
+    }
+
+    /// Adds a job that runs the tests selected by `filter` over and over,
+    /// to reproduce an intermittent failure.
+    pub fn stress_test(&self, build_mode: BuildMode, filter: TestFilter, options: StressOptions) {
+        let job = StressTestJob::new(
+            self.configuration.destination.clone(),
+            build_mode,
+            filter,
+            options,
+        );
+        self.add_job(job);
+    }
+
+    /// Adds a job that runs just the ignored tests, for example slow
+    /// tests that are only run on demand.
+    pub fn run_ignored_tests(&self, build_mode: BuildMode) {
+        let selection = TestSelection::all().with_ignored(IgnoredTests::Only);
+        let job = RunTestsJob::with_direct_run(
+            self.configuration.destination.clone(),
+            build_mode,
+            selection,
+            self.direct_run(build_mode),
+        );
+        self.add_job(job);
+    }
+
     /// Pauses the job engine.
     /// This does not clear out the list of pending jobs, nor does it stop the
     /// currently executing job, if any. However, after that job has completed
     /// no new jobs will begin to execute.
     pub fn pause(&self) {
-        info!("JobEngine paused");
-        self.job_starter_clutch.pause_threads();
+        self.send(EngineCommand::Pause);
     }
 
     /// Restarts the job engine after a pause.
     pub fn restart(&self) {
-        info!("JobEngine restarting");
-        self.job_starter_clutch.release_threads();
+        self.send(EngineCommand::Resume);
+    }
+
+    /// Cancels the executing job, if any, killing any child process it has
+    /// started. The pending jobs are not affected.
+    pub fn cancel(&self) {
+        self.send(EngineCommand::Cancel);
     }
 
     /// Add a job to the end of the queue.
     pub fn add_job(&self, job: PendingJob) {
-        // This lock won't block the caller much, because all other locks
-        // on the `pending_jobs` are very short lived.
-        let pending_jobs_guard = self.pending_jobs.lock().unwrap();
-        self.add_job_inner(job, pending_jobs_guard);
+        self.send(EngineCommand::AddJob(job));
+    }
+
+    fn send(&self, command: EngineCommand) {
+        if self.commands.send(command).is_err() {
+            warn!("The JobEngine has shut down, command ignored");
+        }
     }
 
-    fn execute_jobs(&mut self) {
-        let dummy_mutex = Mutex::new(());
+    /// The body of the JOB_EXECUTOR thread. Jobs are started one at a time on a
+    /// JOB_WORKER thread, which leaves this thread free to respond to commands,
+    /// such as `Cancel`, while the job is executing.
+    fn execute_jobs(&mut self, commands: Receiver<EngineCommand>) {
+        let mut running_job: Option<RunningJob> = None;
+        let mut shutdown_mode: Option<ShutdownMode> = None;
 
         loop {
-            // If we are paused, wait until we are released.
-            self.job_starter_clutch.wait_for_release();
-
-            // Do we have a job to execute?
-            if let Some(job) = self.get_next_job() {
-                let mut executing_job_guard = self.executing_job.lock().unwrap();
-                *executing_job_guard = Some(job.clone());
-                // This is potentially time consuming, everything else in this
-                // method should be fast (hence the locks will be released quickly).
-                let completed_job = job.execute();
-
-                let kind = completed_job.kind();
-                match kind {
-                    JobKind::ShadowCopy(_) => {}
-                    JobKind::FileSync(_) => {}
-                    JobKind::BuildAllTests(_) => {}
-                    JobKind::BuildWorkspace(_) => {}
-                    JobKind::ListAllTests(kind) => {
-                        let tests = kind.parse_tests().unwrap();
-                        self.state.update_test_list(&tests);
+            if running_job.is_none() && self.paused.is_false() {
+                running_job = self.start_next_job();
+            }
+
+            if running_job.is_none()
+                && shutdown_mode.is_some()
+                && self.pending_jobs.lock().unwrap().is_empty()
+            {
+                info!("JOB_EXECUTOR thread stopping");
+                return;
+            }
+
+            // Completed jobs can grow too old to keep while nothing is happening.
+            let mut completed_jobs_guard = self.completed_jobs.lock().unwrap();
+            completed_jobs_guard.apply_retention_policy();
+            let expiry_wait = completed_jobs_guard.time_until_next_expiry();
+            drop(completed_jobs_guard);
+
+            // Wait for the next command. If a retried job is waiting out its
+            // backoff we also need to wake up when it is ready to start.
+            let job_wait = if running_job.is_none() && self.paused.is_false() {
+                self.time_until_next_job_is_ready()
+            } else {
+                None
+            };
+            let wait = match (job_wait, expiry_wait) {
+                (Some(job_wait), Some(expiry_wait)) => Some(job_wait.min(expiry_wait)),
+                (job_wait, expiry_wait) => job_wait.or(expiry_wait),
+            };
+
+            let command = match wait {
+                Some(wait) => match commands.recv_timeout(wait) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                },
+                None => match commands.recv() {
+                    Ok(command) => command,
+                    Err(_) => return,
+                },
+            };
+
+            match command {
+                EngineCommand::AddJob(job) => {
+                    if shutdown_mode.is_some() {
+                        warn!("{} not added, the JobEngine is shutting down", job);
+                    } else {
+                        let pending_jobs_guard = self.pending_jobs.lock().unwrap();
+                        self.add_job_inner(job, pending_jobs_guard);
+                    }
+                }
+                EngineCommand::Pause => {
+                    info!("JobEngine paused");
+                    self.paused.set_true();
+                }
+                EngineCommand::Resume => {
+                    info!("JobEngine restarting");
+                    self.paused.set_false();
+                }
+                EngineCommand::Cancel => {
+                    if let Some(running_job) = &running_job {
+                        info!("Cancelling the executing job");
+                        running_job.context.cancel();
                     }
-                    JobKind::RunTests(_) => {}
                 }
+                EngineCommand::Shutdown(mode) => {
+                    shutdown_mode = Some(mode);
+                    self.paused.set_false();
 
-                self.set_engine_state_flags(&completed_job);
-                let pending_jobs_lock = self.pending_jobs.lock().unwrap();
-                let mut completed_jobs_lock = self.completed_jobs.lock().unwrap();
+                    if mode == ShutdownMode::Cancel {
+                        let mut pending_jobs_guard = self.pending_jobs.lock().unwrap();
+                        let num_discarded = pending_jobs_guard.len();
+                        pending_jobs_guard.clear();
+                        drop(pending_jobs_guard);
+                        info!(
+                            "Discarded {} pending job{}",
+                            num_discarded,
+                            plural_s(num_discarded)
+                        );
 
-                let msg = format!(
-                    "{} completed, there are now {} pending and {} completed jobs",
-                    completed_job,
-                    pending_jobs_lock.len(),
-                    completed_jobs_lock.len() + 1
-                );
+                        if let Some(running_job) = &running_job {
+                            running_job.context.cancel();
+                        }
+                    }
+                }
+                EngineCommand::JobCompleted(completed_job) => {
+                    if let Some(running_job) = running_job.take() {
+                        if running_job.worker_thread.join().is_err() {
+                            warn!("The JOB_WORKER thread panicked");
+                        }
+                    }
+
+                    self.job_completed(completed_job, shutdown_mode.is_none());
+                }
+            }
+        }
+    }
+
+    /// Moves the job at the front of the pending queue into `executing_job` and
+    /// starts it on a JOB_WORKER thread. Both locks are held while the job is moved
+    /// so that `snapshot` always sees the job in one place or the other.
+    fn start_next_job(&self) -> Option<RunningJob> {
+        let mut pending_jobs_guard = self.pending_jobs.lock().unwrap();
+
+        // A retried job waits at the front of the queue until its backoff has elapsed.
+        if pending_jobs_guard.front().is_some_and(|job| !job.is_ready()) {
+            return None;
+        }
+
+        let job = ExecutingJob::from(pending_jobs_guard.pop_front()?);
+        *self.executing_job.lock().unwrap() = Some(job.clone());
+        drop(pending_jobs_guard);
+
+        let timeout = self.configuration.job_timeout(job.kind().job_type());
+        let context = ExecutionContext::new(timeout)
+            .with_process_runner(self.process_runner.clone())
+            .with_live_output(job.live_output().clone())
+            .with_test_settings(self.test_settings.clone())
+            .with_test_progress(self.state.test_progress(self.test_run_info(job.id())));
+
+        let worker_thread = thread::Builder::new()
+            .name("JOB_WORKER".into())
+            .spawn({
+                let context = context.clone();
+                let commands = self.commands.clone();
+                move || {
+                    // This is potentially time consuming.
+                    let completed_job = job.execute(&context);
+                    let _ = commands.send(EngineCommand::JobCompleted(completed_job));
+                }
+            })
+            .expect("Cannot create JOB_WORKER thread");
+
+        Some(RunningJob {
+            context,
+            worker_thread,
+        })
+    }
+
+    /// Identifies a run of the tests by `job_id`, for the history of each
+    /// test's outcomes. The revision is that of the source directory.
+    fn test_run_info(&self, job_id: &JobId) -> TestRunInfo {
+        TestRunInfo {
+            job_id: job_id.clone(),
+            source_revision: source_revision(self.configuration.destination.source_directory()),
+        }
+    }
+
+    /// Returns how long it will be until the job at the front of the
+    /// queue can be started, if it has to wait.
+    fn time_until_next_job_is_ready(&self) -> Option<Duration> {
+        let pending_jobs_guard = self.pending_jobs.lock().unwrap();
+        pending_jobs_guard.front()?.time_until_ready()
+    }
+
+    /// Records a completed job and works out what to do next. Retries and
+    /// pipeline jobs are only queued if the engine is `accepting_jobs`.
+    fn job_completed(&mut self, completed_job: CompletedJob, accepting_jobs: bool) {
+        let mut load_workspace_job = None;
+        let kind = completed_job.kind();
+        match kind {
+            JobKind::ShadowCopy(_) if completed_job.succeeded() => {
+                load_workspace_job = self.load_workspace_job();
+            }
+            JobKind::ShadowCopy(_) => {}
+            JobKind::FileSync(kind) if completed_job.succeeded() => {
+                if self.source_path_changed(kind.source_path()) {
+                    load_workspace_job = self.load_workspace_job();
+                }
+            }
+            JobKind::FileSync(_) => {}
+            JobKind::BuildAllTests(_) => {}
+            JobKind::BuildWorkspace(_) => {}
+            JobKind::ListAllTests(kind) if completed_job.succeeded() => {
+                let tests = kind.parse_tests().unwrap();
+                self.state.update_test_list(kind.build_mode(), &tests);
+                self.state.save();
+            }
+            JobKind::ListAllTests(_) => {}
+            JobKind::RunTests(kind) => {
+                // Even a failed or cancelled run has results for the tests that completed.
+                // Their outcomes were applied as they ran, apart from the reruns.
+                let results = kind.parse_results();
+                if kind.reruns() > 0 {
+                    self.state
+                        .update_flaky_results(kind.build_mode(), &results, kind.isolated_runs());
+                } else {
+                    let run = self.test_run_info(completed_job.id());
+                    self.state.update_test_durations(kind.build_mode(), &results);
+                    self.state
+                        .update_isolated_results(kind.build_mode(), &run, kind.isolated_runs());
+                }
+                self.state.save();
+            }
+            JobKind::CargoCommand(_) => {}
+            JobKind::StressTest(_) => {}
+            JobKind::LoadWorkspace(kind) => {
+                self.changed_packages.set_workspace(kind.workspace().cloned());
+            }
+        }
+
+        // A job that is going to be retried, or that has the rest of the tests
+        // to follow, hasn't finished yet as far as the pipeline is concerned,
+        // so the flags are left alone.
+        let follow_on_job = if accepting_jobs {
+            self.retry_job(&completed_job)
+                .or_else(|| Self::remaining_tests_job(&completed_job))
+        } else {
+            None
+        };
+        if follow_on_job.is_none() {
+            self.set_engine_state_flags(&completed_job);
+            self.tests_run(&completed_job);
+        }
+
+        // The reruns don't change the outcome of the stage, which failed
+        // anyway, but they are run before anything else the pipeline does.
+        let flaky_reruns_job = if accepting_jobs && follow_on_job.is_none() {
+            self.flaky_reruns_job(&completed_job)
+        } else {
+            None
+        };
+
+        let mut pending_jobs_lock = self.pending_jobs.lock().unwrap();
+        let mut executing_job_lock = self.executing_job.lock().unwrap();
+        let mut completed_jobs_lock = self.completed_jobs.lock().unwrap();
+
+        let msg = format!(
+            "{} completed, there are now {} pending and {} completed jobs",
+            completed_job,
+            pending_jobs_lock.len(),
+            completed_jobs_lock.len() + 1
+        );
+
+        completed_jobs_lock.push(completed_job);
+        *executing_job_lock = None;
+        drop(completed_jobs_lock);
+        drop(executing_job_lock);
+
+        info!("{}", msg);
+
+        if let Some(job) = follow_on_job.or(flaky_reruns_job) {
+            pending_jobs_lock.push_front(job);
+            return;
+        }
+
+        // The workspace decides which packages the pipeline builds and
+        // tests, so it is loaded before anything else is done.
+        if let Some(job) = load_workspace_job.filter(|_| accepting_jobs) {
+            pending_jobs_lock.push_front(job);
+            return;
+        }
+
+        if pending_jobs_lock.is_empty() && accepting_jobs {
+            if let Some(job) = self.next_pipeline_job() {
+                self.add_job_inner(job, pending_jobs_lock);
+            }
+        }
+    }
+
+    /// Works out the next job in the pipeline. When the test mode is
+    /// `CompilationMode::Both` the debug pipeline is run to completion
+    /// before the release pipeline is started.
+    fn next_pipeline_job(&self) -> Option<PendingJob> {
+        let build_modes = self.configuration.test_mode().build_modes();
+        let stage_ref = self.pipeline_progress.next_stage(build_modes)?;
+        let stage = &self.configuration.pipeline().stages()[stage_ref.index];
+        let packages = self.changed_packages.packages(stage_ref.build_mode);
+        let job = match stage.action {
+            StageAction::RunTests => self
+                .run_tests_job(stage_ref.build_mode, packages)
+                .with_stage(stage_ref),
+            _ => stage.create_job(self.configuration.destination.clone(), stage_ref, &packages),
+        };
 
-                completed_jobs_lock.push_back(completed_job);
-                drop(completed_jobs_lock);
+        Some(job)
+    }
+
+    /// Creates the job that runs the tests in the `packages` (or the whole
+    /// workspace if there are none), according to the `RunStrategy`. If none
+    /// of their tests failed last time then every test is run.
+    fn run_tests_job(&self, build_mode: BuildMode, packages: Vec<String>) -> PendingJob {
+        let destination = self.configuration.destination.clone();
+        let ignored_tests = self.ignored_tests();
+        let failed = self.failed_tests(build_mode, &packages);
+        let selection = TestSelection::packages(packages).with_ignored(ignored_tests);
+        let direct_run = self.direct_run(build_mode);
+        if failed.is_empty() {
+            return RunTestsJob::with_direct_run(destination, build_mode, selection, direct_run);
+        }
+
+        match self.run_strategy() {
+            RunStrategy::All => {
+                RunTestsJob::with_direct_run(destination, build_mode, selection, direct_run)
+            }
+            RunStrategy::FailedOnly => {
+                let selection = failed
+                    .into_iter()
+                    .fold(TestSelection::all(), TestSelection::with_filter)
+                    .with_ignored(ignored_tests);
+                RunTestsJob::with_direct_run(destination, build_mode, selection, direct_run)
+            }
+            RunStrategy::FailedFirst => {
+                RunTestsJob::failed_first(destination, build_mode, failed, selection, direct_run)
+            }
+        }
+    }
 
-                *executing_job_guard = None;
-                drop(executing_job_guard);
+    /// Returns the tests in the `packages` (or the whole workspace if there are
+    /// none) that failed last time, with a filter for each binary's package and
+    /// target. If it isn't known which package each binary belongs to then the
+    /// failed tests are selected by name across the whole workspace.
+    fn failed_tests(&self, build_mode: BuildMode, packages: &[String]) -> Vec<TestFilter> {
+        let failed = self.state.failed_tests(build_mode);
+        if failed.is_empty() {
+            return Vec::new();
+        }
 
-                info!("{}", msg);
+        let binaries = match self.changed_packages.workspace() {
+            Some(workspace) => workspace.test_binaries(&failed).ok(),
+            None => None,
+        };
+        let binaries = match binaries {
+            Some(binaries) => binaries.binaries,
+            None => {
+                let mut tests = failed.into_iter().flat_map(|b| b.tests).collect::<Vec<_>>();
+                tests.sort();
+                tests.dedup();
+                return vec![TestFilter {
+                    tests,
+                    ..TestFilter::workspace()
+                }];
+            }
+        };
 
-                let build_mode = match self.configuration.build_mode() {
-                    crate::configuration::CompilationMode::None => BuildMode::Debug,
-                    crate::configuration::CompilationMode::Debug => BuildMode::Debug,
-                    crate::configuration::CompilationMode::Release => BuildMode::Release,
-                    crate::configuration::CompilationMode::Both => BuildMode::Debug,
+        binaries
+            .into_iter()
+            .filter(|binary| packages.is_empty() || packages.contains(&binary.package))
+            .map(|binary| {
+                // If the binary's target is ambiguous, the whole package is run.
+                let target = match binary.targets.as_slice() {
+                    [target] => target.clone(),
+                    _ => TestTarget::All,
                 };
+                TestFilter {
+                    package: Some(binary.package),
+                    target,
+                    tests: binary.tests,
+                }
+            })
+            .collect()
+    }
 
-                if pending_jobs_lock.is_empty() {
-                    if self.build_tests_required.is_true() {
-                        let job =
-                            BuildAllTestsJob::new(self.configuration.destination.clone(), build_mode);
-                        self.add_job_inner(job, pending_jobs_lock);
-                    } else if self.list_tests_required.is_true() {
-                        let job =
-                            ListAllTestsJob::new(self.configuration.destination.clone(), build_mode);
-                        self.add_job_inner(job, pending_jobs_lock);
-                    } else if self.run_tests_required.is_true() {
-                        let job =
-                            RunTestsJob::new(self.configuration.destination.clone(), build_mode);
-                        self.add_job_inner(job, pending_jobs_lock);
-                    }
+    /// Returns how to run the test binaries directly when the `TestRunner` is
+    /// `TestRunner::Direct` or `TestRunner::Isolated`. Cargo is used instead if
+    /// the tests haven't been listed yet, or if it isn't known which package
+    /// every binary belongs to, see `direct_run_fallback`.
+    fn direct_run(&self, build_mode: BuildMode) -> Option<DirectRun> {
+        let isolate = match self.configuration.test_runner() {
+            TestRunner::Cargo => return None,
+            TestRunner::Direct => false,
+            TestRunner::Isolated => true,
+        };
+
+        let mut fallback = self.direct_run_fallback.lock().unwrap();
+        match self.test_binaries(build_mode) {
+            Ok(binaries) => {
+                *fallback = None;
+                Some(DirectRun {
+                    binaries,
+                    threads: self.configuration.test_binary_threads(),
+                    isolate,
+                })
+            }
+            Err(reason) => {
+                warn!("Running the tests with cargo: {}", reason);
+                *fallback = Some(reason);
+                None
+            }
+        }
+    }
+
+    /// Works out which package each of the listed test binaries belongs to.
+    fn test_binaries(&self, build_mode: BuildMode) -> Result<TestBinaries, String> {
+        let binaries = self.state.test_binaries(build_mode);
+        if binaries.is_empty() {
+            return Err("The tests have not been listed yet".to_string());
+        }
+
+        let workspace = self.changed_packages.workspace();
+        let workspace = workspace.ok_or_else(|| "The workspace is not known".to_string())?;
+        workspace.test_binaries(&binaries)
+    }
+
+    /// Returns a job that runs `cargo metadata` to find the packages in the workspace
+    /// and the dependencies between them, see `LoadWorkspaceJob`. If the destination
+    /// isn't a cargo project, or the job fails, every change causes the whole
+    /// workspace to be tested.
+    fn load_workspace_job(&self) -> Option<PendingJob> {
+        let destination = self.configuration.destination.clone();
+        if !destination.cwd().join("Cargo.toml").is_file() {
+            self.changed_packages.set_workspace(None);
+            return None;
+        }
+
+        Some(LoadWorkspaceJob::new(destination))
+    }
+
+    /// Records which packages are affected by a change to the file or
+    /// directory at `source_path`. A change to a manifest can change the
+    /// packages themselves, so returns true if the workspace should be reloaded.
+    fn source_path_changed(&self, source_path: &Path) -> bool {
+        let source_directory = self.configuration.destination.source_directory();
+        let path = source_path
+            .strip_prefix(source_directory)
+            .unwrap_or(source_path);
+
+        self.changed_packages.path_changed(path)
+    }
+
+    /// Once the pipeline has run the tests, only subsequent changes need testing.
+    fn tests_run(&self, job: &CompletedJob) {
+        let status = job.completion_status();
+        if status == CompletionStatus::Cancelled || status == CompletionStatus::TimedOut {
+            return;
+        }
+
+        if let (JobKind::RunTests(kind), Some(_)) = (job.kind(), job.stage()) {
+            self.changed_packages.tests_run(kind.build_mode());
+        }
+    }
+
+    /// Returns a job that runs the rest of the tests if `job` ran the
+    /// previously failing tests of a `RunStrategy::FailedFirst` run and
+    /// they all passed. If any still fail the pipeline stops there, as it
+    /// would for any other failed stage.
+    fn remaining_tests_job(job: &CompletedJob) -> Option<PendingJob> {
+        match job.kind() {
+            JobKind::RunTests(kind) if job.succeeded() => {
+                let remaining_job = kind.remaining_tests_job()?;
+                Some(match job.stage() {
+                    Some(stage) => remaining_job.with_stage(stage),
+                    None => remaining_job,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a job that reruns the tests that failed in `job`, if it ran
+    /// tests and `Configuration::flaky_reruns` is set, to find out which of
+    /// them are flaky. The reruns themselves are never rerun.
+    fn flaky_reruns_job(&self, job: &CompletedJob) -> Option<PendingJob> {
+        let reruns = self.configuration.flaky_reruns();
+        let stopped = matches!(
+            job.completion_status(),
+            CompletionStatus::Cancelled | CompletionStatus::TimedOut
+        );
+        if reruns == 0 || stopped {
+            return None;
+        }
+
+        match job.kind() {
+            JobKind::RunTests(kind) if kind.reruns() == 0 => {
+                let failed = kind.failed_tests();
+                if failed.is_empty() {
+                    return None;
                 }
-            } else {
-                // The idea here is that this will BLOCK and you are not allowed to touch the
-                // data guarded by the MUTEX until the signal happens.
-                let guard = dummy_mutex.lock().unwrap();
-                let _ = self.job_added_signal.wait(guard).unwrap();
+
+                info!(
+                    "{} Rerunning {} failed test{} to check for flakiness",
+                    job.id(),
+                    failed.len(),
+                    plural_s(failed.len())
+                );
+                Some(RunTestsJob::flaky_reruns(
+                    self.configuration.destination.clone(),
+                    kind.build_mode(),
+                    failed,
+                    kind.selection(),
+                    reruns,
+                    kind.direct_run().cloned(),
+                ))
             }
+            _ => None,
         }
     }
 
-    fn get_next_job(&self) -> Option<PendingJob> {
-        let mut pending_jobs_guard = self.pending_jobs.lock().unwrap();
-        pending_jobs_guard.pop_front()
+    /// A change to the source files means every enabled pipeline
+    /// needs to start again from the beginning.
+    fn source_changed(&self, required: bool) {
+        self.pipeline_progress.source_changed(
+            self.configuration.pipeline(),
+            self.configuration.test_mode().build_modes(),
+            required,
+        );
+    }
+
+    /// Returns a new attempt at the job if it failed for a transient reason and
+    /// its `RetryPolicy` allows another go. Compile errors and test failures
+    /// are never retried.
+    fn retry_job(&self, job: &CompletedJob) -> Option<PendingJob> {
+        let policy = self.configuration.job_retry_policy(job.kind().job_type());
+        let shadow_copy_directory = self.configuration.destination.destination_directory();
+        let delay = policy.delay_before_retry(job, shadow_copy_directory)?;
+        let retry_job = PendingJob::retry_of(job, delay);
+
+        warn!(
+            "{} failed with a transient error, retrying as {} (attempt {} of {}) in {:?}",
+            job,
+            retry_job.id(),
+            retry_job.attempt(),
+            policy.max_attempts,
+            delay
+        );
+
+        Some(retry_job)
     }
 
     /// Convenince method to add a new build job.
@@ -216,92 +934,1729 @@ impl JobEngine {
         );
 
         pending_jobs_guard.push_back(job);
-
-        // Tell everybody listening (really it's just us with one thread) that there
-        // is now a job in the pending queue.
-        self.job_added_signal.notify_all();
     }
 
-    /// Sets the various state flags based on the job and its completion status.
+    /// Sets the pipeline flags based on the job and its completion status.
+    /// Anything other than `CompletionStatus::Ok` (errors and timeouts) counts as a failure.
     fn set_engine_state_flags(&self, job: &CompletedJob) {
-        match (job.kind(), job.completion_status()) {
-            (JobKind::ShadowCopy(_), CompletionStatus::Ok) => {
-                self.build_tests_required.set_true();
-            }
-            (JobKind::ShadowCopy(_), CompletionStatus::Error(_)) => {
-                self.build_tests_required.set_false();
-            }
-
-            (JobKind::FileSync(_), CompletionStatus::Ok) => {
-                self.build_tests_required.set_true();
-            }
-            (JobKind::FileSync(_), CompletionStatus::Error(_)) => {}
+        if job.completion_status() == CompletionStatus::Unknown {
+            return;
+        }
 
-            (JobKind::BuildAllTests(_), CompletionStatus::Ok) => {
-                self.build_tests_required.set_false();
-                self.list_tests_required.set_true();
+        match (job.kind(), job.succeeded()) {
+            (JobKind::ShadowCopy(_), true) => {
+                self.source_changed(true);
             }
-            (JobKind::BuildAllTests(_), CompletionStatus::Error(_)) => {
+            (JobKind::ShadowCopy(_), false) => {
                 // To prevent recursion, we need to wait till we get another file copy.
-                self.build_tests_required.set_false();
+                self.source_changed(false);
             }
 
-            // Having a built crate available is just a convenience. It doesn't affect
-            // the main flow of build tests -> list tests -> run tests.
-            (JobKind::BuildWorkspace(_), CompletionStatus::Ok) => {}
-            (JobKind::BuildWorkspace(_), CompletionStatus::Error(_)) => {}
-
-            (JobKind::ListAllTests(_), CompletionStatus::Ok) => {
-                self.list_tests_required.set_false();
-                self.run_tests_required.set_true();
-            }
-            (JobKind::ListAllTests(_), CompletionStatus::Error(_)) => {
-                // To prevent recursion, we need to wait till we get another file copy.
-                self.list_tests_required.set_false();
+            (JobKind::FileSync(_), true) => {
+                self.source_changed(true);
             }
+            (JobKind::FileSync(_), false) => {}
 
-            (JobKind::RunTests(_), CompletionStatus::Ok) => {
-                self.run_tests_required.set_false();
-            }
-            (JobKind::RunTests(_), CompletionStatus::Error(_)) => {
-                // To prevent recursion, we need to wait till we get another file copy.
-                self.run_tests_required.set_false();
-            }
+            // Having a built crate available is just a convenience. It doesn't affect
+            // the pipeline, and neither do any other jobs that weren't created by it.
+            _ => {}
+        }
 
-            (_, CompletionStatus::Unknown) => {}
+        // If the job failed and its stage's policy is to stop, nothing
+        // further happens until we get another file copy.
+        if let Some(stage) = job.stage() {
+            self.pipeline_progress
+                .stage_completed(self.configuration.pipeline(), stage, job.succeeded());
         }
     }
 }
 
-/// Atomic reference counted bool flag.
-/// It is safe to use and call this from multiple threads.
-#[derive(Debug, Default, Clone)]
-struct BoolFlag {
-    flag: Arc<AtomicBool>,
-}
+#[cfg(test)]
+mod engine_tests {
+    use super::*;
+    use crate::{
+        configuration::{CompilationMode, ConfigurationBuilder},
+        job_history::RetentionPolicy,
+        jobs::{
+            FileSyncJob, JobStatus, JobType, ProcessExit, RetryPolicy, RunTestsJob,
+            ScriptedProcess, ScriptedProcessRunner, ShadowCopyJob,
+        },
+        pipeline::{FailurePolicy, Pipeline},
+        source_directory_watcher::FileSyncEvent,
+        state::{DocTestLocation, TestState, TestSummary},
+        test_selection::{TestFilter, TestSelection, TestTarget},
+        test_settings::{Backtrace, TestSettings},
+    };
+    use std::{fs, time::Instant};
+    use tempfile::TempDir;
+
+    const TEST_LIST: &str =
+        "  Running /abc-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n";
+
+    struct Fixture {
+        source: TempDir,
+        _destination: TempDir,
+        configuration: Configuration,
+        runner: Arc<ScriptedProcessRunner>,
+        engine: JobEngine,
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            self.engine.shutdown(ShutdownMode::Cancel);
+        }
+    }
 
-impl BoolFlag {
-    fn is_true(&self) -> bool {
-        self.flag.load(Ordering::SeqCst)
+    impl Fixture {
+        fn new() -> Self {
+            Self::with_configuration(|builder| builder)
+        }
+
+        fn with_configuration<F>(configure: F) -> Self
+        where
+            F: FnOnce(ConfigurationBuilder) -> ConfigurationBuilder,
+        {
+            let source = TempDir::new().unwrap();
+            let destination = TempDir::new().unwrap();
+
+            let builder = Configuration::builder(source.path())
+                .shadow_copy_to(destination.path())
+                .retry_policy(RetryPolicy {
+                    max_attempts: 3,
+                    backoff: Duration::from_millis(0),
+                });
+            let configuration = configure(builder).build();
+
+            let runner = Arc::new(ScriptedProcessRunner::new());
+            let engine = JobEngine::with_process_runner(
+                configuration.clone(),
+                State::new(configuration.clone()),
+                runner.clone(),
+            );
+
+            Self {
+                source,
+                _destination: destination,
+                configuration,
+                runner,
+                engine,
+            }
+        }
+
+        /// Kicks off the pipeline, in the same way as starting rtest does.
+        fn start_pipeline(&self) {
+            let job = ShadowCopyJob::new(self.configuration.destination.clone());
+            self.engine.add_job(job);
+        }
+
+        fn wait_until(&self, condition: impl Fn(&EngineSnapshot) -> bool) -> EngineSnapshot {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                let snapshot = self.engine.snapshot();
+                if condition(&snapshot) {
+                    return snapshot;
+                }
+
+                assert!(Instant::now() < deadline, "Timed out, {:?}", snapshot);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn wait_until_idle(&self) -> EngineSnapshot {
+            self.wait_until(|s| {
+                s.pending.is_empty() && s.executing.is_none() && !s.completed.is_empty()
+            })
+        }
+
+        /// Returns, for each cargo command that was run, whether it contained `arg`.
+        fn commands_containing(&self, arg: &str) -> Vec<bool> {
+            self.runner
+                .commands()
+                .iter()
+                .map(|c| c.args.iter().any(|a| a == arg))
+                .collect()
+        }
     }
 
-    fn is_false(&self) -> bool {
-        !self.is_true()
+    fn completed_types(snapshot: &EngineSnapshot) -> Vec<JobType> {
+        snapshot.completed.iter().map(|j| j.job_type).collect()
     }
 
-    fn get(&self, value: bool) -> bool {
-        self.flag.load(Ordering::SeqCst)
+    fn is_ok(job: &JobSnapshot) -> bool {
+        job.status == JobStatus::Completed(CompletionStatus::Ok)
     }
 
-    fn set(&self, value: bool) {
-        self.flag.store(value, Ordering::SeqCst);
+    #[test]
+    fn pipeline_builds_then_lists_then_runs_tests() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(TEST_LIST));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        assert_eq!(
+            completed_types(&snapshot),
+            vec![
+                JobType::ShadowCopy,
+                JobType::BuildAllTests,
+                JobType::ListAllTests,
+                JobType::RunTests
+            ]
+        );
+        assert!(snapshot.completed.iter().all(is_ok));
+        assert_eq!(
+            fixture.commands_containing("--no-run"),
+            vec![true, false, false]
+        );
+        assert_eq!(
+            fixture.commands_containing("--list"),
+            vec![false, true, false]
+        );
+        assert_eq!(
+            fixture.commands_containing("--release"),
+            vec![false, false, false]
+        );
     }
 
-    fn set_true(&self) {
-        self.set(true);
+    #[test]
+    fn resource_usage_is_recorded_and_totalled() {
+        let fixture = Fixture::new();
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("Compiling abc\n"));
+        fixture.runner.push(ScriptedProcess::succeeds(TEST_LIST));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        let build = &snapshot.completed[1];
+        let usage = build.resource_usage.as_ref().unwrap();
+        assert_eq!(usage.processes, 1);
+        assert_eq!(usage.exit, Some(ProcessExit::Code(0)));
+        assert_eq!(usage.output_bytes, "Compiling abc\n".len());
+        assert!(snapshot.completed[0].resource_usage.is_none());
+
+        let totals = fixture.engine.resource_totals();
+        assert_eq!(totals[&JobType::BuildAllTests].jobs, 1);
+        assert_eq!(
+            totals[&JobType::ListAllTests].output_bytes,
+            TEST_LIST.len() as u64
+        );
+        assert_eq!(
+            totals[&JobType::ShadowCopy].user_time,
+            Duration::from_secs(0)
+        );
     }
 
-    fn set_false(&self) {
-        self.set(false);
+    #[test]
+    fn test_results_update_the_state() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(TEST_LIST));
+        fixture.runner.push(ScriptedProcess::fails(
+            "  Running /abc-9bdf7ee7378a8684\nrunning 1 test\ntest tests::a ... FAILED\n",
+        ));
+
+        let state = &fixture.engine.state;
+        fixture.start_pipeline();
+        fixture.wait_until_idle();
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "abc", "tests::a"),
+            Some(TestState::Failed)
+        );
+
+        let selection =
+            TestSelection::all().with_filter(TestFilter::package("abc").with_test("tests::a"));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684\nrunning 1 test\ntest tests::a ... ok\n",
+        ));
+        fixture.engine.add_job(RunTestsJob::with_selection(
+            fixture.configuration.destination.clone(),
+            BuildMode::Debug,
+            selection,
+        ));
+        fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "abc", "tests::a"),
+            Some(TestState::Passed)
+        );
+    }
+
+    const TWO_TEST_LIST: &str =
+        "  Running /abc-9bdf7ee7378a8684\ntests::a: test\ntests::b: test\n2 tests, 0 benchmarks\n";
+
+    /// Runs the pipeline once with tests::a failing and tests::b passing, then
+    /// sets the `run_strategy` and queues the responses for a second run.
+    fn fixture_after_failing_run(run_strategy: RunStrategy, responses: &[&str]) -> Fixture {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        fixture.runner.push(ScriptedProcess::fails(
+            "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... FAILED\ntest tests::b ... ok\n",
+        ));
+        fixture.start_pipeline();
+        fixture.wait_until_idle();
+
+        fixture.engine.set_run_strategy(run_strategy);
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        for response in responses {
+            let process = if response.contains("FAILED") {
+                ScriptedProcess::fails(*response)
+            } else {
+                ScriptedProcess::succeeds(*response)
+            };
+            fixture.runner.push(process);
+        }
+
+        fixture
+    }
+
+    /// Returns the arguments after "--" of the test runs after the first.
+    fn rerun_test_args(fixture: &Fixture) -> Vec<String> {
+        fixture
+            .runner
+            .commands()
+            .iter()
+            .skip(3)
+            .filter(|c| !c.args.iter().any(|a| a == "--no-run" || a == "--list"))
+            .map(|c| {
+                let idx = c.args.iter().position(|a| a == "--").unwrap();
+                c.args[idx + 1..].join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn failed_tests_that_pass_when_rerun_are_flaky() {
+        let fixture = Fixture::with_configuration(|b| b.flaky_reruns(2));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        for response in &[
+            "test tests::a ... FAILED\ntest tests::b ... FAILED\n",
+            "test tests::a ... ok\ntest tests::b ... FAILED\n",
+            "test tests::a ... FAILED\ntest tests::b ... FAILED\n",
+        ] {
+            fixture.runner.push(ScriptedProcess::fails(format!(
+                "  Running /abc-9bdf7ee7378a8684\n{}",
+                response
+            )));
+        }
+        fixture.start_pipeline();
+        fixture.wait_until_idle();
+
+        assert_eq!(
+            rerun_test_args(&fixture),
+            vec!["--show-output --test-threads=1 --color never --exact tests::a tests::b"; 2]
+        );
+
+        let state = &fixture.engine.state;
+        let flaky = state
+            .test_state(BuildMode::Debug, "abc", "tests::a")
+            .unwrap();
+        assert_eq!(
+            flaky,
+            TestState::Flaky {
+                passed: 1,
+                failed: 2
+            }
+        );
+        assert_eq!(flaky.pass_ratio(), Some(1.0 / 3.0));
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "abc", "tests::b"),
+            Some(TestState::Failed)
+        );
+        let failed = state.failed_tests(BuildMode::Debug);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].basename, "abc");
+        assert_eq!(failed[0].tests, vec!["tests::b"]);
+    }
+
+    #[test]
+    fn test_durations_are_tracked_across_runs() {
+        let fixture = Fixture::new();
+        let run_output = |a, b, binary| {
+            format!(
+                "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok <{}s>\n\
+                 test tests::b ... ok <{}s>\n\ntest result: ok. 2 passed; 0 failed; \
+                 0 ignored; 0 measured; 0 filtered out; finished in {}s\n",
+                a, b, binary
+            )
+        };
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(run_output("0.1", "0.01", "0.2")));
+        fixture.start_pipeline();
+        let completed = fixture.wait_until_idle().completed.len();
+
+        for (a, b, binary) in &[
+            ("0.1", "0.01", "0.2"),
+            ("0.1", "0.01", "0.2"),
+            ("0.1", "0.05", "0.3"),
+        ] {
+            fixture
+                .runner
+                .push(ScriptedProcess::succeeds(run_output(a, b, binary)));
+            fixture.engine.add_job(RunTestsJob::new(
+                fixture.configuration.destination.clone(),
+                BuildMode::Debug,
+            ));
+        }
+        fixture.wait_until(|s| s.completed.len() == completed + 3 && s.executing.is_none());
+
+        let state = &fixture.engine.state;
+        let slowest = state.slowest_tests(BuildMode::Debug, 1);
+        assert_eq!(slowest.len(), 1);
+        assert_eq!(slowest[0].test, "tests::a");
+        assert_eq!(slowest[0].stats.mean, Duration::from_millis(100));
+        assert_eq!(slowest[0].stats.runs, 4);
+
+        let binaries = state.slowest_binaries(BuildMode::Debug, 10);
+        assert_eq!(binaries.len(), 1);
+        assert_eq!(binaries[0].crate_basename, "abc");
+        assert_eq!(binaries[0].stats.last, Duration::from_millis(300));
+        assert_eq!(binaries[0].stats.mean, Duration::from_millis(225));
+        assert_eq!(binaries[0].stats.p95, Duration::from_millis(300));
+
+        let regressions = state.duration_regressions(BuildMode::Debug);
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].test, "tests::b");
+        assert_eq!(regressions[0].last, Duration::from_millis(50));
+        assert_eq!(regressions[0].previous_mean, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn doc_tests_are_listed_and_run() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n\
+             Doc-tests abc\nsrc/lib.rs - add (line 3): test\n1 test, 0 benchmarks\n",
+        ));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok\n",
+        ));
+        fixture.start_pipeline();
+        let completed = fixture.wait_until_idle().completed.len();
+
+        fixture.runner.push(ScriptedProcess::fails(
+            "   Doc-tests abc\nrunning 1 test\ntest src/lib.rs - add (line 3) ... FAILED\n",
+        ));
+        fixture.engine.run_doc_tests(BuildMode::Debug);
+        fixture.wait_until(|s| s.completed.len() == completed + 1 && s.executing.is_none());
+
+        let args = fixture.runner.commands().last().unwrap().args.join(" ");
+        assert!(args.starts_with("test --no-fail-fast --doc -- "));
+
+        let tests = fixture.engine.state.tests(BuildMode::Debug);
+        assert_eq!(
+            tests,
+            vec![
+                TestSummary {
+                    crate_basename: "abc".into(),
+                    name: "src/lib.rs - add (line 3)".into(),
+                    doc_test: Some(DocTestLocation {
+                        item: "add".into(),
+                        file_name: "src/lib.rs".into(),
+                        line_number: 3,
+                    }),
+                    state: TestState::Failed,
+                    num_times_executed: 1,
+                    from_previous_session: false,
+                },
+                TestSummary {
+                    crate_basename: "abc".into(),
+                    name: "tests::a".into(),
+                    doc_test: None,
+                    state: TestState::Passed,
+                    num_times_executed: 1,
+                    from_previous_session: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_states_are_updated_as_tests_complete_and_unfinished_tests_are_stale() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684
+test tests::a ... ok
+test tests::b ... ok
+",
+        ));
+        fixture.start_pipeline();
+        let completed = fixture.wait_until_idle().completed.len();
+
+        let test_states = || {
+            fixture
+                .engine
+                .state
+                .tests(BuildMode::Debug)
+                .into_iter()
+                .map(|t| (t.name, t.state, t.num_times_executed))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            test_states(),
+            vec![
+                ("tests::a".to_string(), TestState::Passed, 1),
+                ("tests::b".to_string(), TestState::Passed, 1),
+            ]
+        );
+
+        // The binary crashes after the first test, so the second never completes.
+        fixture.runner.push(ScriptedProcess::killed_by(
+            "  Running /abc-9bdf7ee7378a8684
+running 2 tests
+test tests::a ... ok
+",
+            6,
+        ));
+        let destination = fixture.configuration.destination.clone();
+        fixture
+            .engine
+            .add_job(RunTestsJob::new(destination.clone(), BuildMode::Debug));
+        fixture.wait_until(|s| s.completed.len() == completed + 1 && s.executing.is_none());
+        assert_eq!(
+            test_states(),
+            vec![
+                ("tests::a".to_string(), TestState::Passed, 2),
+                ("tests::b".to_string(), TestState::Stale, 1),
+            ]
+        );
+
+        // Tests that weren't selected keep their state.
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684
+running 1 test
+test tests::b ... ok
+",
+        ));
+        let filter = TestFilter::workspace().with_test("tests::b");
+        let selection = TestSelection::all().with_filter(filter);
+        fixture.engine.add_job(RunTestsJob::with_selection(
+            destination,
+            BuildMode::Debug,
+            selection,
+        ));
+        fixture.wait_until(|s| s.completed.len() == completed + 2 && s.executing.is_none());
+        assert_eq!(
+            test_states(),
+            vec![
+                ("tests::a".to_string(), TestState::Passed, 2),
+                ("tests::b".to_string(), TestState::Passed, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn running_only_the_ignored_tests_leaves_the_others_alone() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684
+test tests::a ... ok
+test tests::b ... ignored
+",
+        ));
+        fixture.start_pipeline();
+        let completed = fixture.wait_until_idle().completed.len();
+
+        let test_states = || {
+            fixture
+                .engine
+                .state
+                .tests(BuildMode::Debug)
+                .into_iter()
+                .map(|t| (t.name, t.state))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            test_states(),
+            vec![
+                ("tests::a".to_string(), TestState::Passed),
+                ("tests::b".to_string(), TestState::Ignored),
+            ]
+        );
+
+        // libtest doesn't mention the tests that are not ignored.
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684
+test tests::b ... ok
+",
+        ));
+        fixture.engine.run_ignored_tests(BuildMode::Debug);
+        fixture.wait_until(|s| s.completed.len() == completed + 1 && s.executing.is_none());
+        assert_eq!(
+            test_states(),
+            vec![
+                ("tests::a".to_string(), TestState::Passed),
+                ("tests::b".to_string(), TestState::Passed),
+            ]
+        );
+
+        // The ignored test is still known to be ignored after it has passed.
+        fixture.runner.push(ScriptedProcess::killed_by(
+            "  Running /abc-9bdf7ee7378a8684
+",
+            6,
+        ));
+        fixture.engine.run_ignored_tests(BuildMode::Debug);
+        fixture.wait_until(|s| s.completed.len() == completed + 2 && s.executing.is_none());
+        assert_eq!(
+            test_states(),
+            vec![
+                ("tests::a".to_string(), TestState::Passed),
+                ("tests::b".to_string(), TestState::Stale),
+            ]
+        );
+    }
+
+    #[test]
+    fn outcome_history_shows_when_a_test_started_failing() {
+        let fixture = Fixture::new();
+        let git_dir = fixture.source.path().join(".git");
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let set_revision = |revision: &str| {
+            fs::write(git_dir.join("refs/heads/main"), revision).unwrap();
+        };
+
+        set_revision("1111");
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok\ntest tests::b ... ok\n",
+        ));
+        fixture.start_pipeline();
+        let completed = fixture.wait_until_idle().completed.len();
+
+        set_revision("2222");
+        fixture.runner.push(ScriptedProcess::fails(
+            "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok\ntest tests::b ... FAILED\n",
+        ));
+        let destination = fixture.configuration.destination.clone();
+        fixture
+            .engine
+            .add_job(RunTestsJob::new(destination.clone(), BuildMode::Debug));
+        let snapshot =
+            fixture.wait_until(|s| s.completed.len() == completed + 1 && s.executing.is_none());
+        let failing_job_id = snapshot.completed[completed].id.clone();
+
+        set_revision("3333");
+        fixture.runner.push(ScriptedProcess::fails(
+            "  Running /abc-9bdf7ee7378a8684\ntest tests::b ... FAILED\n",
+        ));
+        let filter = TestFilter::workspace().with_test("tests::b");
+        fixture.engine.add_job(RunTestsJob::with_selection(
+            destination,
+            BuildMode::Debug,
+            TestSelection::all().with_filter(filter),
+        ));
+        fixture.wait_until(|s| s.completed.len() == completed + 2 && s.executing.is_none());
+
+        let state = &fixture.engine.state;
+        let first_failure = state
+            .first_failure_after_pass(BuildMode::Debug, "abc", "tests::b")
+            .unwrap();
+        assert_eq!(first_failure.outcome, TestState::Failed);
+        assert_eq!(first_failure.build_mode, BuildMode::Debug);
+        assert_eq!(first_failure.job_id, failing_job_id);
+        assert_eq!(first_failure.source_revision.as_deref(), Some("2222"));
+
+        let revisions = state
+            .last_results(BuildMode::Debug, "abc", "tests::b", 2)
+            .into_iter()
+            .map(|r| r.source_revision.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(revisions, vec!["3333", "2222"]);
+        assert_eq!(
+            state.pass_rate(BuildMode::Debug, "abc", "tests::b"),
+            Some(1.0 / 3.0)
+        );
+
+        assert_eq!(
+            state
+                .last_results(BuildMode::Debug, "abc", "tests::a", 10)
+                .len(),
+            2
+        );
+        assert_eq!(
+            state.pass_rate(BuildMode::Debug, "abc", "tests::a"),
+            Some(1.0)
+        );
+        assert!(state
+            .first_failure_after_pass(BuildMode::Debug, "abc", "tests::a")
+            .is_none());
+    }
+
+    #[test]
+    fn state_is_saved_and_loaded_by_the_next_session() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(TWO_TEST_LIST));
+        fixture.runner.push(ScriptedProcess::fails(
+            "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok\ntest tests::b ... FAILED\n",
+        ));
+        fixture.start_pipeline();
+        fixture.wait_until_idle();
+
+        let state_file = fixture.configuration.state_file();
+        assert!(state_file.is_file());
+
+        let state = State::load(fixture.configuration.clone());
+        let tests = state.tests(BuildMode::Debug);
+        let states = tests
+            .iter()
+            .map(|t| (t.name.as_str(), t.state, t.num_times_executed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                ("tests::a", TestState::Passed, 1),
+                ("tests::b", TestState::Failed, 1)
+            ]
+        );
+        assert!(tests.iter().all(|t| t.from_previous_session));
+        let last_results = state.last_results(BuildMode::Debug, "abc", "tests::b", 10);
+        assert_eq!(last_results.len(), 1);
+        assert!(last_results[0].previous_session);
+
+        // A file written by a different version of rtest is ignored.
+        let json = fs::read_to_string(&state_file).unwrap();
+        let json = json.replacen("\"version\":1", "\"version\":999", 1);
+        fs::write(&state_file, json).unwrap();
+        let state = State::load(fixture.configuration.clone());
+        assert!(state.tests(BuildMode::Debug).is_empty());
+    }
+
+    #[test]
+    fn failed_only_reruns_just_the_failed_tests() {
+        let fixture = fixture_after_failing_run(
+            RunStrategy::FailedOnly,
+            &["  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok\n"],
+        );
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| s.completed.len() == 8 && s.executing.is_none());
+
+        assert_eq!(
+            rerun_test_args(&fixture),
+            vec!["--show-output --test-threads=1 --color never --exact tests::a"]
+        );
+        assert_eq!(
+            fixture
+                .engine
+                .state
+                .test_state(BuildMode::Debug, "abc", "tests::a"),
+            Some(TestState::Passed)
+        );
+    }
+
+    #[test]
+    fn failed_first_runs_the_rest_when_the_failures_are_fixed() {
+        let fixture = fixture_after_failing_run(
+            RunStrategy::FailedFirst,
+            &[
+                "  Running /abc-9bdf7ee7378a8684\ntest tests::a ... ok\n",
+                "  Running /abc-9bdf7ee7378a8684\ntest tests::b ... ok\n",
+            ],
+        );
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| s.completed.len() == 9 && s.executing.is_none());
+
+        assert_eq!(
+            rerun_test_args(&fixture),
+            vec![
+                "--show-output --test-threads=1 --color never --exact tests::a",
+                "--show-output --test-threads=1 --color never --exact --skip tests::a",
+            ]
+        );
+        assert!(snapshot.completed.iter().skip(4).all(is_ok));
+    }
+
+    #[test]
+    fn failed_first_stops_when_the_failures_remain() {
+        let fixture = fixture_after_failing_run(
+            RunStrategy::FailedFirst,
+            &["  Running /abc-9bdf7ee7378a8684\ntest tests::a ... FAILED\n"],
+        );
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| s.completed.len() == 8 && s.executing.is_none());
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            rerun_test_args(&fixture),
+            vec!["--show-output --test-threads=1 --color never --exact tests::a"]
+        );
+        assert_eq!(fixture.engine.snapshot().completed.len(), 8);
+    }
+
+    /// Creates a workspace of three packages, where b depends on a, and
+    /// returns the `cargo metadata` output for it.
+    fn create_workspace(fixture: &Fixture) -> String {
+        let source = fixture.source.path();
+        for package in &["a", "b", "c"] {
+            fs::create_dir_all(source.join(package).join("src")).unwrap();
+            fs::write(source.join(package).join("src").join("lib.rs"), "").unwrap();
+        }
+        fs::write(source.join("Cargo.toml"), "[workspace]\n").unwrap();
+
+        let cwd = fixture
+            .configuration
+            .destination
+            .cwd()
+            .display()
+            .to_string();
+        let package = |name: &str, dependencies: &str| {
+            format!(
+                r#"{{"name":"{}","manifest_path":"{}/{}/Cargo.toml","dependencies":[{}],"targets":[{{"name":"{}","kind":["lib"]}}]}}"#,
+                name, cwd, name, dependencies, name
+            )
+        };
+        format!(
+            "warning: some cargo warning\n{{\"packages\":[{},{},{}],\"version\":1}}\n",
+            package("a", ""),
+            package("b", r#"{"name":"a"},{"name":"serde"}"#),
+            package("c", "")
+        )
+    }
+
+    fn sync_file(fixture: &Fixture, path: &str) {
+        let path = fixture.source.path().join(path);
+        let event = FileSyncEvent::FileUpdate(path);
+        let job = FileSyncJob::new(fixture.configuration.destination.clone(), event);
+        fixture.engine.add_job(job);
+    }
+
+    /// Returns the packages passed to each command, skipping the first `skip` commands.
+    fn command_packages(fixture: &Fixture, skip: usize) -> Vec<String> {
+        fixture
+            .runner
+            .commands()
+            .iter()
+            .skip(skip)
+            .map(|c| {
+                c.args
+                    .iter()
+                    .zip(c.args.iter().skip(1))
+                    .filter(|(arg, _)| *arg == "--package")
+                    .map(|(_, package)| package.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_change_to_a_package_tests_it_and_its_dependents() {
+        let fixture = Fixture::new();
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+        assert!(fixture.commands_containing("metadata")[0]);
+        assert_eq!(command_packages(&fixture, 1), vec!["", "", ""]);
+
+        sync_file(&fixture, "a/src/lib.rs");
+        fixture.wait_until(|s| s.completed.len() == 9 && s.executing.is_none());
+        assert_eq!(command_packages(&fixture, 4), vec!["a b", "a b", "a", "b"]);
+
+        // Only the changes since the last test run are tested.
+        sync_file(&fixture, "c/src/lib.rs");
+        fixture.wait_until(|s| s.completed.len() == 13 && s.executing.is_none());
+        assert_eq!(command_packages(&fixture, 8), vec!["c", "c", "c"]);
+    }
+
+    const SAME_NAME_LIST: &str =
+        "  Running /a-9bdf7ee7378a8684\ntests::same: test\n1 test, 0 benchmarks\n\
+           Running /b-0490fca25dc32581\ntests::same: test\n1 test, 0 benchmarks\n\
+           Running /c-52bd6dd1bcb8b4a5\ntests::c: test\n1 test, 0 benchmarks\n";
+
+    /// Creates a workspace and runs its tests once, with tests::same failing in
+    /// a but passing in b, and tests::c failing in c. The `run_strategy` is set
+    /// for the next run.
+    fn fixture_after_failing_run_in_workspace(run_strategy: RunStrategy) -> Fixture {
+        let fixture = Fixture::new();
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(SAME_NAME_LIST));
+        fixture.runner.push(ScriptedProcess::fails(
+            "  Running /a-9bdf7ee7378a8684\ntest tests::same ... FAILED\n\
+               Running /b-0490fca25dc32581\ntest tests::same ... ok\n\
+               Running /c-52bd6dd1bcb8b4a5\ntest tests::c ... FAILED\n",
+        ));
+        fixture.start_pipeline();
+        fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+
+        fixture.engine.set_run_strategy(run_strategy);
+        fixture
+    }
+
+    /// Returns the arguments of the test runs after the first `skip` commands.
+    fn test_run_args(fixture: &Fixture, skip: usize) -> Vec<String> {
+        fixture
+            .runner
+            .commands()
+            .iter()
+            .skip(skip)
+            .filter(|c| !c.args.iter().any(|a| a == "--no-run" || a == "--list"))
+            .map(|c| c.args.join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn failed_only_reruns_the_failures_in_the_changed_packages() {
+        let fixture = fixture_after_failing_run_in_workspace(RunStrategy::FailedOnly);
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(SAME_NAME_LIST));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running /a-9bdf7ee7378a8684\ntest tests::same ... ok\n",
+        ));
+
+        // b has a test of the same name, which passed, and c isn't affected.
+        sync_file(&fixture, "a/src/lib.rs");
+        fixture.wait_until(|s| s.completed.len() == 9 && s.executing.is_none());
+        assert_eq!(
+            test_run_args(&fixture, 4),
+            vec![
+                "test --no-fail-fast --package a --lib -- \
+                 --show-output --test-threads=1 --color never --exact tests::same"
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_first_skips_the_failures_only_in_their_own_packages() {
+        let fixture = fixture_after_failing_run_in_workspace(RunStrategy::FailedFirst);
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(SAME_NAME_LIST));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| s.completed.len() == 11 && s.executing.is_none());
+        assert!(snapshot.completed.iter().skip(5).all(is_ok));
+        assert_eq!(
+            test_run_args(&fixture, 5),
+            vec![
+                "test --no-fail-fast --package a --lib -- \
+                 --show-output --test-threads=1 --color never --exact tests::same",
+                "test --no-fail-fast --package c --lib -- \
+                 --show-output --test-threads=1 --color never --exact tests::c",
+                "test --no-fail-fast --package a -- \
+                 --show-output --test-threads=1 --color never --exact --skip tests::same",
+                "test --no-fail-fast --package c -- \
+                 --show-output --test-threads=1 --color never --exact --skip tests::c",
+                "test --no-fail-fast --workspace --exclude a --exclude c -- \
+                 --show-output --test-threads=1 --color never",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_change_to_a_manifest_reloads_the_workspace_and_tests_everything() {
+        let fixture = Fixture::new();
+        let metadata = create_workspace(&fixture);
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(metadata.clone()));
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        sync_file(&fixture, "Cargo.toml");
+        fixture.wait_until(|s| s.completed.len() == 10 && s.executing.is_none());
+
+        assert!(fixture.commands_containing("metadata")[4]);
+        assert_eq!(command_packages(&fixture, 5), vec!["", "", ""]);
+    }
+
+    #[test]
+    fn loading_the_workspace_can_be_cancelled() {
+        let fixture = Fixture::new();
+        let metadata = create_workspace(&fixture);
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(metadata).with_delay(Duration::from_secs(30)));
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| {
+            s.executing
+                .as_ref()
+                .is_some_and(|j| j.job_type == JobType::LoadWorkspace)
+        });
+        let start = Instant::now();
+        fixture.engine.cancel();
+        let snapshot = fixture.wait_until_idle();
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        assert_eq!(snapshot.completed[1].job_type, JobType::LoadWorkspace);
+        assert_eq!(
+            snapshot.completed[1].status,
+            JobStatus::Completed(CompletionStatus::Cancelled)
+        );
+
+        // Without the workspace, the pipeline carries on and tests everything.
+        assert_eq!(command_packages(&fixture, 1), vec!["", "", ""]);
+    }
+
+    #[test]
+    fn direct_test_runner_runs_the_binaries_and_cargo_for_doc_tests() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.test_runner(TestRunner::Direct).test_binary_threads(1)
+        });
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running target/debug/deps/a-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n\
+             Running unittests src/lib.rs (target/debug/deps/b-0490fca25dc32581)\n\
+             tests::b: test\n1 test, 0 benchmarks\n",
+        ));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "running 1 test\ntest tests::a ... ok\n",
+        ));
+        fixture.runner.push(ScriptedProcess::fails(
+            "running 1 test\ntest tests::b ... FAILED\n",
+        ));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+        assert!(!is_ok(&snapshot.completed[4]));
+
+        let cwd = fixture.configuration.destination.cwd();
+        let commands = fixture.runner.commands();
+        let runs = &commands[3..];
+        assert_eq!(runs.len(), 3);
+        assert_eq!(
+            runs[0].program,
+            cwd.join("target/debug/deps/a-9bdf7ee7378a8684")
+                .to_string_lossy()
+        );
+        assert_eq!(runs[0].cwd, cwd.join("a"));
+        assert_eq!(
+            runs[0].args.join(" "),
+            "--show-output --test-threads=1 --color never"
+        );
+        assert_eq!(runs[1].cwd, cwd.join("b"));
+        assert_eq!(runs[2].program, "cargo");
+        assert_eq!(
+            runs[2].args.join(" "),
+            "test --no-fail-fast --doc -- --show-output --test-threads=1 --color never"
+        );
+
+        let state = &fixture.engine.state;
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "a", "tests::a"),
+            Some(TestState::Passed)
+        );
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "b", "tests::b"),
+            Some(TestState::Failed)
+        );
+    }
+
+    #[test]
+    fn direct_test_runner_falls_back_to_cargo_for_an_unknown_binary() {
+        let fixture = Fixture::with_configuration(|b| b.test_runner(TestRunner::Direct));
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running target/debug/deps/a-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n\
+             Running target/debug/deps/zzz-0490fca25dc32581\ntests::z: test\n1 test, 0 benchmarks\n",
+        ));
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+
+        let commands = fixture.runner.commands();
+        assert_eq!(commands.len(), 4);
+        assert_eq!(commands[3].program, "cargo");
+        assert_eq!(
+            fixture.engine.direct_run_fallback().unwrap(),
+            "Cannot find the package of test binary target/debug/deps/zzz-0490fca25dc32581"
+        );
+    }
+
+    #[test]
+    fn isolated_test_runner_records_crashes_and_the_output_of_each_test() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.test_runner(TestRunner::Isolated).test_binary_threads(1)
+        });
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running target/debug/deps/a-9bdf7ee7378a8684\ntests::a: test\ntests::b: test\n\
+             2 tests, 0 benchmarks\n",
+        ));
+        fixture
+            .runner
+            .push(ScriptedProcess::killed_by("running 1 test\n", 11));
+        let passing_output = "running 1 test\ntest tests::b ... ok\n";
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds(passing_output));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+        assert!(!is_ok(&snapshot.completed[4]));
+
+        let test_args = fixture.runner.commands()[3..]
+            .iter()
+            .map(|c| c.args.join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            test_args,
+            vec![
+                "--show-output --test-threads=1 --color never --exact tests::a",
+                "--show-output --test-threads=1 --color never --exact tests::b",
+                "test --no-fail-fast --doc -- --show-output --test-threads=1 --color never",
+            ]
+        );
+
+        // The crash doesn't stop the next test from being run and reported.
+        let state = &fixture.engine.state;
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "a", "tests::a"),
+            Some(TestState::Crashed(Some(ProcessExit::Signal(11))))
+        );
+        assert_eq!(
+            state.test_state(BuildMode::Debug, "a", "tests::b"),
+            Some(TestState::Passed)
+        );
+        assert_eq!(
+            state.test_output(BuildMode::Debug, "a", "tests::b"),
+            Some(passing_output.to_string())
+        );
+        let failed = state.failed_tests(BuildMode::Debug);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].tests, vec!["tests::a"]);
+    }
+
+    #[test]
+    fn packages_with_their_own_test_settings_are_run_separately() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.test_settings(TestSettings {
+                threads: 2,
+                backtrace: Some(Backtrace::Full),
+                ..Default::default()
+            })
+            .package_test_settings(
+                "b",
+                TestSettings {
+                    nocapture: true,
+                    env: vec![("DB".into(), "test".into())],
+                    extra_args: vec!["--include-ignored".into()],
+                    ..Default::default()
+                },
+            )
+        });
+
+        fixture.engine.add_job(RunTestsJob::new(
+            fixture.configuration.destination.clone(),
+            BuildMode::Debug,
+        ));
+        fixture.wait_until_idle();
+
+        let commands = fixture
+            .runner
+            .commands()
+            .into_iter()
+            .map(|c| (c.args.join(" "), c.env))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![
+                (
+                    "test --no-fail-fast --package b -- --nocapture --test-threads=1 \
+                     --color never --include-ignored"
+                        .to_string(),
+                    vec![("DB".to_string(), "test".to_string())]
+                ),
+                (
+                    "test --no-fail-fast --workspace --exclude b -- --show-output \
+                     --test-threads=2 --color never"
+                        .to_string(),
+                    vec![("RUST_BACKTRACE".to_string(), "full".to_string())]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignored_tests_can_be_run_on_their_own_or_with_the_others() {
+        let fixture = Fixture::new();
+
+        fixture.engine.run_ignored_tests(BuildMode::Debug);
+        fixture.engine.set_ignored_tests(IgnoredTests::Include);
+        fixture.engine.add_job(RunTestsJob::with_selection(
+            fixture.configuration.destination.clone(),
+            BuildMode::Debug,
+            TestSelection::all().with_ignored(fixture.engine.ignored_tests()),
+        ));
+        fixture.wait_until(|s| s.completed.len() == 2);
+
+        let commands = fixture
+            .runner
+            .commands()
+            .into_iter()
+            .map(|c| c.args.join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![
+                "test --no-fail-fast -- --show-output --test-threads=1 --color never --ignored",
+                "test --no-fail-fast -- --show-output --test-threads=1 --color never \
+                 --include-ignored",
+            ]
+        );
+    }
+
+    #[test]
+    fn stress_tests_run_until_the_first_failure() {
+        let fixture = Fixture::new();
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("test tests::a ... ok\n"));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("test tests::a ... ok\n"));
+        fixture
+            .runner
+            .push(ScriptedProcess::fails("test tests::a ... FAILED\n"));
+
+        let options = StressOptions::iterations(5)
+            .with_max_threads(2)
+            .stopping_on_failure();
+        let filter = TestFilter::package("abc").with_test("tests::a");
+        fixture
+            .engine
+            .stress_test(BuildMode::Debug, filter, options);
+        let snapshot = fixture.wait_until_idle();
+
+        let commands = fixture
+            .runner
+            .commands()
+            .into_iter()
+            .map(|c| c.args.join(" "))
+            .collect::<Vec<_>>();
+        let command = |threads| {
+            format!(
+                "test --no-fail-fast --package abc -- --show-output --test-threads={} \
+                 --color never --exact tests::a",
+                threads
+            )
+        };
+        assert_eq!(commands, vec![command(1), command(2), command(1)]);
+
+        let job = &snapshot.completed[0];
+        assert_eq!(job.job_type, JobType::StressTest);
+        assert!(!is_ok(job));
+        let output = fixture.engine.job_output(&job.id, 0).unwrap();
+        assert_eq!(
+            output.text,
+            "test tests::a ... ok\ntest tests::a ... ok\ntest tests::a ... FAILED\n\
+             3 iterations: 2 passed, 1 failed\n"
+        );
+    }
+
+    #[test]
+    fn selected_tests_are_run_with_the_narrowest_invocations() {
+        let fixture = Fixture::new();
+        let selection = TestSelection::all()
+            .with_filter(
+                TestFilter::package("abc")
+                    .with_target(TestTarget::Lib)
+                    .with_test("tests::a"),
+            )
+            .with_filter(TestFilter::package("def").with_target(TestTarget::Doc))
+            .with_filter(
+                TestFilter::package("abc")
+                    .with_target(TestTarget::Lib)
+                    .with_test("tests::b"),
+            )
+            .with_filter(TestFilter::package("def"));
+
+        fixture.engine.add_job(RunTestsJob::with_selection(
+            fixture.configuration.destination.clone(),
+            BuildMode::Release,
+            selection,
+        ));
+        let snapshot = fixture.wait_until_idle();
+        assert!(snapshot.completed[0]
+            .description
+            .starts_with("Run abc --lib tests::a"));
+
+        let commands = fixture
+            .runner
+            .commands()
+            .into_iter()
+            .map(|c| c.args.join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![
+                "test --no-fail-fast --package abc --lib --release -- --show-output \
+                 --test-threads=1 --color never --exact tests::a tests::b",
+                "test --no-fail-fast --package def --release -- --show-output \
+                 --test-threads=1 --color never",
+            ]
+        );
+    }
+
+    #[test]
+    fn pipeline_runs_debug_then_release_when_testing_both() {
+        let fixture = Fixture::with_configuration(|b| b.test_mode(CompilationMode::Both));
+
+        fixture.start_pipeline();
+        fixture.wait_until_idle();
+
+        assert_eq!(
+            fixture.commands_containing("--release"),
+            vec![false, false, false, true, true, true]
+        );
+    }
+
+    #[test]
+    fn failed_build_stops_the_pipeline_and_is_not_retried() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::fails(
+            "error[E0425]: cannot find value `x` in this scope\nerror: could not compile `abc`",
+        ));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        assert_eq!(
+            completed_types(&snapshot),
+            vec![JobType::ShadowCopy, JobType::BuildAllTests]
+        );
+        assert!(!is_ok(&snapshot.completed[1]));
+        assert_eq!(fixture.runner.commands().len(), 1);
+    }
+
+    #[test]
+    fn failed_build_continues_the_pipeline_if_configured() {
+        let fixture = Fixture::with_configuration(|b| {
+            let mut pipeline = Pipeline::default();
+            pipeline.stage_mut("build-tests").unwrap().on_failure = FailurePolicy::Continue;
+            b.pipeline(pipeline)
+        });
+        fixture
+            .runner
+            .push(ScriptedProcess::fails("error: could not compile `abc`"));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        assert_eq!(snapshot.completed.len(), 4);
+        assert_eq!(fixture.runner.commands().len(), 3);
+    }
+
+    #[test]
+    fn transient_build_failure_is_retried() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::fails(
+            "error: failed to open: /abc/target/debug/.cargo-lock\n\nCaused by:\n  Resource temporarily unavailable (os error 11)",
+        ));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        let builds = snapshot
+            .completed
+            .iter()
+            .filter(|j| j.job_type == JobType::BuildAllTests)
+            .collect::<Vec<_>>();
+        assert_eq!(builds.len(), 2);
+        assert_eq!(builds[0].attempt, 1);
+        assert!(!is_ok(builds[0]));
+        assert_eq!(builds[1].attempt, 2);
+        assert!(is_ok(builds[1]));
+        assert_eq!(fixture.runner.commands().len(), 4);
+    }
+
+    #[test]
+    fn missing_cargo_file_in_the_target_directory_is_retried() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::fails(
+            "error: failed to read `/abc/target/debug/.fingerprint/abc-9bdf7ee7378a8684/lib-abc`\n\nCaused by:\n  No such file or directory (os error 2)",
+        ));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        let builds = snapshot
+            .completed
+            .iter()
+            .filter(|j| j.job_type == JobType::BuildAllTests)
+            .collect::<Vec<_>>();
+        assert_eq!(builds.len(), 2);
+        assert!(is_ok(builds[1]));
+    }
+
+    #[test]
+    fn build_script_that_cannot_find_a_file_is_not_retried() {
+        let fixture = Fixture::new();
+        fixture.runner.push(ScriptedProcess::fails(
+            "error: failed to run custom build command for `abc v0.1.0 (/abc)`\n\nCaused by:\n  process didn't exit successfully: `/abc/target/debug/build/abc-9bdf7ee7378a8684/build-script-build` (exit status: 101)\n  --- stderr\n  thread 'main' panicked at 'failed to read schema.json: No such file or directory (os error 2)', build.rs:4:5",
+        ));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        assert_eq!(
+            completed_types(&snapshot),
+            vec![JobType::ShadowCopy, JobType::BuildAllTests]
+        );
+        assert!(!is_ok(&snapshot.completed[1]));
+        assert_eq!(fixture.runner.commands().len(), 1);
+    }
+
+    #[test]
+    fn timed_out_build_stops_the_pipeline() {
+        let fixture =
+            Fixture::with_configuration(|b| b.build_timeout(Some(Duration::from_millis(50))));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("").with_delay(Duration::from_secs(30)));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until_idle();
+
+        assert_eq!(
+            snapshot.completed[1].status,
+            JobStatus::Completed(CompletionStatus::TimedOut)
+        );
+        assert_eq!(fixture.runner.commands().len(), 1);
+    }
+
+    #[test]
+    fn timeout_applies_to_the_whole_job_rather_than_each_process() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.test_runner(TestRunner::Direct)
+                .test_binary_threads(1)
+                .run_timeout(Some(Duration::from_millis(300)))
+        });
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running target/debug/deps/a-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n\
+             Running unittests src/lib.rs (target/debug/deps/b-0490fca25dc32581)\n\
+             tests::b: test\n1 test, 0 benchmarks\n",
+        ));
+        // Each binary finishes well within the limit, but not both of them.
+        for _ in 0..2 {
+            fixture.runner.push(
+                ScriptedProcess::succeeds("running 1 test\n")
+                    .with_delay(Duration::from_millis(200)),
+            );
+        }
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| s.completed.len() == 5 && s.executing.is_none());
+        assert_eq!(
+            snapshot.completed[4].status,
+            JobStatus::Completed(CompletionStatus::TimedOut)
+        );
+
+        // The doc tests are not started once the job has run out of time.
+        let commands = fixture.runner.commands();
+        assert_eq!(commands.len(), 5);
+        assert_eq!(
+            commands[4].cwd,
+            fixture.configuration.destination.cwd().join("b")
+        );
+    }
+
+    #[test]
+    fn parallel_run_starts_no_binaries_once_one_has_stopped() {
+        for status in &[CompletionStatus::TimedOut, CompletionStatus::Cancelled] {
+            let fixture = Fixture::with_configuration(|b| {
+                b.test_runner(TestRunner::Direct).test_binary_threads(2)
+            });
+            let metadata = create_workspace(&fixture);
+            fixture.runner.push(ScriptedProcess::succeeds(metadata));
+            fixture.runner.push(ScriptedProcess::succeeds(""));
+            fixture.runner.push(ScriptedProcess::succeeds(
+                "  Running target/debug/deps/a-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n\
+                 Running target/debug/deps/b-0490fca25dc32581\ntests::b: test\n1 test, 0 benchmarks\n\
+                 Running target/debug/deps/c-52bd6dd1bcb8b4a5\ntests::c: test\n1 test, 0 benchmarks\n",
+            ));
+            // One of the first two binaries stops while the other is still
+            // running. The third must not be started when the other finishes.
+            fixture.runner.push(
+                ScriptedProcess::stopped(status.clone()).with_delay(Duration::from_millis(50)),
+            );
+            fixture.runner.push(
+                ScriptedProcess::succeeds("running 1 test\n")
+                    .with_delay(Duration::from_millis(200)),
+            );
+
+            fixture.start_pipeline();
+            let snapshot = fixture.wait_until_idle();
+            assert_eq!(
+                snapshot.completed[4].status,
+                JobStatus::Completed(status.clone())
+            );
+            assert_eq!(fixture.runner.commands().len(), 5);
+        }
+    }
+
+    #[test]
+    fn cancelling_shutdown_stops_the_executing_job() {
+        let fixture = Fixture::new();
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("").with_delay(Duration::from_secs(30)));
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| {
+            s.executing
+                .as_ref()
+                .is_some_and(|j| j.job_type == JobType::BuildAllTests)
+        });
+
+        let start = Instant::now();
+        fixture.engine.shutdown(ShutdownMode::Cancel);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let snapshot = fixture.engine.snapshot();
+        assert!(snapshot.pending.is_empty());
+        assert!(snapshot.executing.is_none());
+        assert_eq!(
+            snapshot.completed[1].status,
+            JobStatus::Completed(CompletionStatus::Cancelled)
+        );
+    }
+
+    #[test]
+    fn paused_engine_does_not_start_jobs() {
+        let fixture = Fixture::new();
+        fixture.engine.pause();
+        fixture.start_pipeline();
+
+        let snapshot = fixture.wait_until(|s| s.paused && s.pending.len() == 1);
+        assert!(snapshot.executing.is_none());
+        thread::sleep(Duration::from_millis(50));
+        assert!(fixture.engine.snapshot().completed.is_empty());
+
+        fixture.engine.restart();
+        let snapshot = fixture.wait_until_idle();
+        assert!(!snapshot.paused);
+        assert_eq!(snapshot.completed.len(), 4);
+    }
+
+    #[test]
+    fn cancel_stops_the_executing_job_only() {
+        let fixture = Fixture::with_configuration(|b| b.test_mode(CompilationMode::Both));
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("").with_delay(Duration::from_secs(30)));
+
+        fixture.start_pipeline();
+        fixture.wait_until(|s| {
+            s.executing
+                .as_ref()
+                .is_some_and(|j| j.job_type == JobType::BuildAllTests)
+        });
+        fixture.engine.cancel();
+
+        // The release pipeline is unaffected by the failure of the debug build.
+        let snapshot = fixture.wait_until_idle();
+        assert_eq!(
+            snapshot.completed[1].status,
+            JobStatus::Completed(CompletionStatus::Cancelled)
+        );
+        assert_eq!(
+            fixture.commands_containing("--release"),
+            vec![false, true, true, true]
+        );
+    }
+
+    #[test]
+    fn job_output_follows_the_executing_job() {
+        let fixture = Fixture::new();
+        fixture.runner.push(
+            ScriptedProcess::succeeds("Compiling abc\nFinished\n")
+                .with_delay(Duration::from_millis(200)),
+        );
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| {
+            s.executing
+                .as_ref()
+                .is_some_and(|j| j.job_type == JobType::BuildAllTests)
+        });
+        let build_id = snapshot.executing.unwrap().id;
+
+        let live = fixture.engine.job_output(&build_id, 0).unwrap();
+        assert!(!live.complete);
+
+        fixture.wait_until_idle();
+        let output = fixture.engine.job_output(&build_id, 0).unwrap();
+        assert!(output.complete);
+        assert_eq!(output.text, "Compiling abc\nFinished\n");
+
+        let tail = fixture
+            .engine
+            .job_output(&build_id, "Compiling abc\n".len())
+            .unwrap();
+        assert_eq!(tail.text, "Finished\n");
+        assert_eq!(tail.next_offset, output.next_offset);
+    }
+
+    #[test]
+    fn job_output_can_be_followed_across_the_completion_of_a_parallel_run() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.test_runner(TestRunner::Direct).test_binary_threads(2)
+        });
+        let metadata = create_workspace(&fixture);
+        fixture.runner.push(ScriptedProcess::succeeds(metadata));
+        fixture.runner.push(ScriptedProcess::succeeds(""));
+        fixture.runner.push(ScriptedProcess::succeeds(
+            "  Running target/debug/deps/a-9bdf7ee7378a8684\ntests::a: test\n1 test, 0 benchmarks\n\
+             Running unittests src/lib.rs (target/debug/deps/b-0490fca25dc32581)\n\
+             tests::b: test\n1 test, 0 benchmarks\n",
+        ));
+        // The binaries run at the same time, so the output of the first one to
+        // start is written after that of the second, unlike in the job's output.
+        fixture.runner.push(
+            ScriptedProcess::succeeds("slow output\n").with_delay(Duration::from_millis(300)),
+        );
+        fixture
+            .runner
+            .push(ScriptedProcess::succeeds("quick output\n"));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| {
+            s.executing
+                .as_ref()
+                .is_some_and(|j| j.job_type == JobType::RunTests)
+        });
+        let run_id = snapshot.executing.unwrap().id;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let live = loop {
+            let live = fixture.engine.job_output(&run_id, 0).unwrap();
+            if live.text.contains("quick output") || Instant::now() > deadline {
+                break live;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert!(!live.complete);
+
+        fixture.wait_until_idle();
+        let tail = fixture
+            .engine
+            .job_output(&run_id, live.next_offset)
+            .unwrap();
+        assert!(tail.complete);
+        assert_eq!(tail.text, "slow output\n");
+
+        let output = fixture.engine.job_output(&run_id, 0).unwrap();
+        assert_eq!(output.text, live.text + &tail.text);
+        assert_eq!(output.next_offset, tail.next_offset);
+    }
+
+    #[test]
+    fn job_output_is_read_from_the_store_after_eviction() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.job_retention_policy(RetentionPolicy {
+                max_jobs: Some(1),
+                max_age: None,
+                max_output_bytes: None,
+            })
+        });
+        fixture.runner.push(
+            ScriptedProcess::succeeds("Compiling abc\n").with_delay(Duration::from_millis(50)),
+        );
+        fixture.runner.push(ScriptedProcess::succeeds(TEST_LIST));
+
+        fixture.start_pipeline();
+        let snapshot = fixture.wait_until(|s| {
+            s.executing
+                .as_ref()
+                .is_some_and(|j| j.job_type == JobType::BuildAllTests)
+        });
+        let build_id = snapshot.executing.unwrap().id;
+
+        let snapshot = fixture.wait_until_idle();
+        assert_eq!(completed_types(&snapshot), vec![JobType::RunTests]);
+
+        let output = fixture.engine.job_output(&build_id, 0).unwrap();
+        assert!(output.complete);
+        assert_eq!(output.text, "Compiling abc\n");
+
+        let unknown = ShadowCopyJob::new(fixture.configuration.destination.clone());
+        assert!(fixture.engine.job_output(unknown.id(), 0).is_none());
+    }
+
+    #[test]
+    fn idle_engine_deletes_jobs_that_are_too_old() {
+        let fixture = Fixture::with_configuration(|b| {
+            b.job_retention_policy(RetentionPolicy {
+                max_jobs: None,
+                max_age: Some(chrono::Duration::milliseconds(100)),
+                max_output_bytes: None,
+            })
+        });
+        fixture.start_pipeline();
+        fixture.wait_until(|s| !s.completed.is_empty());
+
+        // Nothing else happens, but the jobs still expire.
+        fixture.wait_until(|s| s.completed.is_empty() && s.pending.is_empty());
+        assert!(!fixture.runner.commands().is_empty());
+    }
+
+    #[test]
+    fn jobs_added_after_shutdown_are_ignored() {
+        let fixture = Fixture::new();
+        fixture.engine.shutdown(ShutdownMode::Drain);
+
+        fixture.start_pipeline();
+
+        let snapshot = fixture.engine.snapshot();
+        assert!(snapshot.pending.is_empty());
+        assert!(snapshot.completed.is_empty());
+        assert!(fixture.runner.commands().is_empty());
     }
 }
@@ -0,0 +1,508 @@
+use crate::{
+    jobs::{CompletedJob, CompletionStatus, Job, JobId, JobType, ResourceTotals, ResourceUsage},
+    utils::plural_s,
+};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Specifies how many completed jobs are kept in memory, and for how long
+/// any are kept. A job is evicted (moved to the `JobStore`) if it breaks
+/// the count or size limit, and deleted if it breaks the age limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// The maximum number of jobs to keep in memory.
+    pub max_jobs: Option<usize>,
+
+    /// The maximum age of a job, measured from when it completed. Older
+    /// jobs are deleted from memory and from every session in the store.
+    pub max_age: Option<Duration>,
+
+    /// The maximum number of bytes of job output to keep in memory.
+    /// The most recent job is always kept, even if it exceeds this limit
+    /// on its own.
+    pub max_output_bytes: Option<usize>,
+}
+
+/// The list of completed jobs. Jobs are kept in memory, in the order they
+/// completed, until the `RetentionPolicy` says they should be evicted, at
+/// which point they are written to the `JobStore`.
+#[derive(Debug)]
+pub struct JobHistory {
+    policy: RetentionPolicy,
+    store: JobStore,
+    jobs: VecDeque<CompletedJob>,
+    output_bytes: usize,
+    resource_totals: HashMap<JobType, ResourceTotals>,
+}
+
+impl JobHistory {
+    /// Creates an empty history. The store may hold records from earlier
+    /// sessions, so any that are too old are deleted straight away.
+    pub fn new(policy: RetentionPolicy, store: JobStore) -> Self {
+        if let Some(max_age) = policy.max_age {
+            store.remove_older_than(Utc::now() - max_age);
+        }
+
+        Self {
+            policy,
+            store,
+            jobs: VecDeque::new(),
+            output_bytes: 0,
+            resource_totals: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of jobs held in memory.
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Returns the jobs held in memory, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &CompletedJob> {
+        self.jobs.iter()
+    }
+
+    /// Returns the store that evicted jobs are written to.
+    pub fn store(&self) -> &JobStore {
+        &self.store
+    }
+
+    /// Returns the resources used by every job that has completed
+    /// in this session, including evicted jobs, totalled by type.
+    pub fn resource_totals(&self) -> &HashMap<JobType, ResourceTotals> {
+        &self.resource_totals
+    }
+
+    /// Adds a newly completed job to the history, evicting older
+    /// jobs if necessary.
+    pub fn push(&mut self, job: CompletedJob) {
+        self.resource_totals
+            .entry(job.kind().job_type())
+            .or_default()
+            .add(&job);
+        self.output_bytes += output_bytes(&job);
+        self.jobs.push_back(job);
+        self.apply_retention_policy();
+    }
+
+    /// Returns how long it will be until the oldest job is too old to keep,
+    /// or `None` if there is no age limit or no jobs. The engine calls
+    /// `apply_retention_policy` then, so that an idle session expires it.
+    pub fn time_until_next_expiry(&self) -> Option<std::time::Duration> {
+        let max_age = self.policy.max_age?;
+        let oldest = self.jobs.front()?;
+        let expiry = oldest.completed_date() + max_age;
+        Some((expiry - Utc::now()).to_std().unwrap_or_default())
+    }
+
+    /// Evicts the jobs that break the count or size limit, and deletes
+    /// the ones that are too old, along with any older ones in the store.
+    pub fn apply_retention_policy(&mut self) {
+        let cutoff = self.policy.max_age.map(|max| Utc::now() - max);
+        let mut expired = false;
+
+        while let Some(oldest) = self.jobs.front() {
+            let too_many = self
+                .policy
+                .max_jobs
+                .is_some_and(|max| self.jobs.len() > max);
+            let too_old = cutoff.is_some_and(|cutoff| oldest.completed_date() <= cutoff);
+            let too_big = self.jobs.len() > 1
+                && self
+                    .policy
+                    .max_output_bytes
+                    .is_some_and(|max| self.output_bytes > max);
+
+            if !(too_many || too_old || too_big) {
+                break;
+            }
+
+            let evicted = self.jobs.pop_front().unwrap();
+            self.output_bytes -= output_bytes(&evicted);
+            if too_old {
+                expired = true;
+            } else {
+                self.store.save(&JobRecord::from(&evicted));
+            }
+        }
+
+        // The jobs in the store completed before those in memory.
+        if let Some(cutoff) = cutoff.filter(|_| expired) {
+            self.store.remove_older_than(cutoff);
+        }
+    }
+}
+
+/// The number of bytes of output that `job` holds in memory. The transcript
+/// is a second copy of the output, so it counts too.
+fn output_bytes(job: &CompletedJob) -> usize {
+    job.output().len() + job.transcript().len()
+}
+
+/// The on-disk form of a completed job. The `JobKind` is reduced to its
+/// type and description because the record has to be readable in later
+/// sessions, when the original destination directory may well have gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: JobId,
+    pub job_type: JobType,
+    pub description: String,
+    pub creation_date: DateTime<Utc>,
+    pub start_date: DateTime<Utc>,
+    pub completed_date: DateTime<Utc>,
+    pub status: CompletionStatus,
+    pub output: String,
+    /// Missing from records written before resource usage was recorded.
+    #[serde(default)]
+    pub resource_usage: Option<ResourceUsage>,
+    /// See `CompletedJob::transcript`. Missing from records written
+    /// before the transcript was kept.
+    #[serde(default)]
+    pub transcript: String,
+}
+
+impl From<&CompletedJob> for JobRecord {
+    fn from(job: &CompletedJob) -> Self {
+        Self {
+            id: job.id().clone(),
+            job_type: job.kind().job_type(),
+            description: job.kind().to_string(),
+            creation_date: job.creation_date(),
+            start_date: job.start_date(),
+            completed_date: job.completed_date(),
+            status: job.completion_status(),
+            output: job.kind().output().to_string(),
+            resource_usage: job.resource_usage().cloned(),
+            transcript: job.transcript().to_string(),
+        }
+    }
+}
+
+/// An on-disk store of `JobRecord`s. Each run of rtest is a separate session
+/// with its own directory, named after the time the session started and the
+/// process id, so that records from earlier sessions (and their output) can
+/// still be viewed. Job ids start again from 1 in every process, so two
+/// instances of rtest started at the same time must not share a directory.
+///
+/// The layout is `<root>/<session>/job-<id>.json`.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    root: PathBuf,
+    session_directory: PathBuf,
+}
+
+impl JobStore {
+    /// Creates a store for a new session under `root`. Nothing is created
+    /// on disk until the first record is saved.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        let root = root.into();
+        let session = format!(
+            "{}-{}",
+            Utc::now().format("%Y%m%d-%H%M%S"),
+            std::process::id()
+        );
+        let session_directory = root.join(session);
+        Self {
+            root,
+            session_directory,
+        }
+    }
+
+    /// Returns the directory that the current session's records are written to.
+    pub fn session_directory(&self) -> &Path {
+        &self.session_directory
+    }
+
+    /// Saves a record into the current session. Failures are logged but
+    /// otherwise ignored, losing some history is not fatal.
+    pub fn save(&self, record: &JobRecord) {
+        if let Err(err) = self.try_save(record) {
+            error!(
+                "Error saving {} to {}, err = {}",
+                record.id,
+                self.session_directory.display(),
+                err
+            );
+        }
+    }
+
+    fn try_save(&self, record: &JobRecord) -> io::Result<()> {
+        fs::create_dir_all(&self.session_directory)?;
+        let path = Self::record_path(&self.session_directory, &record.id);
+        let json = serde_json::to_string(record)?;
+        fs::write(&path, json)?;
+        info!("Saved {} to {}", record.id, path.display());
+        Ok(())
+    }
+
+    /// Loads a record from the current session.
+    pub fn load(&self, id: &JobId) -> io::Result<JobRecord> {
+        Self::load_file(&Self::record_path(&self.session_directory, id))
+    }
+
+    /// Returns the directories of all the sessions in the store,
+    /// oldest first. This includes the current session, if anything
+    /// has been saved in it yet.
+    pub fn sessions(&self) -> io::Result<Vec<PathBuf>> {
+        if !self.root.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut sessions = fs::read_dir(&self.root)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>();
+
+        sessions.sort();
+        Ok(sessions)
+    }
+
+    /// Loads all the records in a session directory (as returned
+    /// by `sessions`), ordered by completion date.
+    pub fn load_session(&self, session_directory: &Path) -> io::Result<Vec<JobRecord>> {
+        let mut records = Vec::new();
+        for entry in fs::read_dir(session_directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                records.push(Self::load_file(&path)?);
+            }
+        }
+
+        records.sort_by_key(|r| r.completed_date);
+        Ok(records)
+    }
+
+    /// Deletes the records that completed at or before `cutoff` from every
+    /// session, and the session directories that this leaves empty. Failures
+    /// are logged but otherwise ignored.
+    pub fn remove_older_than(&self, cutoff: DateTime<Utc>) {
+        if let Err(err) = self.try_remove_older_than(cutoff) {
+            error!(
+                "Error removing old jobs from {}, err = {}",
+                self.root.display(),
+                err
+            );
+        }
+    }
+
+    fn try_remove_older_than(&self, cutoff: DateTime<Utc>) -> io::Result<()> {
+        for session_directory in self.sessions()? {
+            let mut removed = 0;
+            let mut remaining = 0;
+            for entry in fs::read_dir(&session_directory)? {
+                let path = entry?.path();
+                match Self::completed_date(&path) {
+                    Some(date) if date <= cutoff => {
+                        fs::remove_file(&path)?;
+                        removed += 1;
+                    }
+                    _ => remaining += 1,
+                }
+            }
+
+            if removed > 0 {
+                info!(
+                    "Removed {} old job{} from {}",
+                    removed,
+                    plural_s(removed),
+                    session_directory.display()
+                );
+            }
+            if removed > 0 && remaining == 0 {
+                fs::remove_dir(&session_directory)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns when the job in the record at `path` completed, or `None`
+    /// if it is not a record that can be read.
+    fn completed_date(path: &Path) -> Option<DateTime<Utc>> {
+        #[derive(Deserialize)]
+        struct CompletedDate {
+            completed_date: DateTime<Utc>,
+        }
+
+        if path.extension().is_none_or(|ext| ext != "json") {
+            return None;
+        }
+        let json = fs::read_to_string(path).ok()?;
+        let record: CompletedDate = serde_json::from_str(&json).ok()?;
+        Some(record.completed_date)
+    }
+
+    fn load_file(path: &Path) -> io::Result<JobRecord> {
+        let json = fs::read_to_string(path)?;
+        let record = serde_json::from_str(&json)?;
+        Ok(record)
+    }
+
+    fn record_path(session_directory: &Path, id: &JobId) -> PathBuf {
+        session_directory.join(format!("job-{}.json", id.value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::BuildMode,
+        jobs::{BuildAllTestsJob, ExecutionContext, ScriptedProcess, ScriptedProcessRunner},
+        shadow_copy_destination::ShadowCopyDestination,
+    };
+    use std::{sync::Arc, thread};
+    use tempfile::TempDir;
+
+    const NO_LIMITS: RetentionPolicy = RetentionPolicy {
+        max_jobs: None,
+        max_age: None,
+        max_output_bytes: None,
+    };
+
+    fn completed_job(output: &str) -> CompletedJob {
+        let runner = Arc::new(ScriptedProcessRunner::new());
+        runner.push(ScriptedProcess::succeeds(output));
+        let context = ExecutionContext::new(None).with_process_runner(runner);
+        let destination = ShadowCopyDestination::without_copying(PathBuf::from("."));
+        BuildAllTestsJob::new(destination, BuildMode::Debug).execute(&context)
+    }
+
+    /// A record of a job that completed `age` ago.
+    fn record(age: Duration) -> JobRecord {
+        let mut record = JobRecord::from(&completed_job(""));
+        record.completed_date = Utc::now() - age;
+        record
+    }
+
+    fn saved_ids(store: &JobStore) -> Vec<usize> {
+        let mut ids = Vec::new();
+        for session in store.sessions().unwrap() {
+            for record in store.load_session(&session).unwrap() {
+                ids.push(record.id.value());
+            }
+        }
+        ids.sort();
+        ids
+    }
+
+    fn ids(history: &JobHistory) -> Vec<usize> {
+        history.iter().map(|job| job.id().value()).collect()
+    }
+
+    #[test]
+    fn jobs_beyond_the_count_limit_are_moved_to_the_store() {
+        let root = TempDir::new().unwrap();
+        let policy = RetentionPolicy {
+            max_jobs: Some(2),
+            ..NO_LIMITS
+        };
+        let mut history = JobHistory::new(policy, JobStore::new(root.path()));
+        let jobs = (0..3).map(|_| completed_job("output")).collect::<Vec<_>>();
+        let job_ids = jobs.iter().map(|job| job.id().value()).collect::<Vec<_>>();
+        for job in jobs {
+            history.push(job);
+        }
+
+        assert_eq!(ids(&history), job_ids[1..]);
+        assert_eq!(saved_ids(history.store()), job_ids[..1]);
+        assert_eq!(history.resource_totals()[&JobType::BuildAllTests].jobs, 3);
+    }
+
+    #[test]
+    fn jobs_beyond_the_size_limit_are_moved_to_the_store_except_the_latest() {
+        let root = TempDir::new().unwrap();
+        let job_bytes = output_bytes(&completed_job("0123456789"));
+        let policy = RetentionPolicy {
+            max_output_bytes: Some(2 * job_bytes),
+            ..NO_LIMITS
+        };
+        let mut history = JobHistory::new(policy, JobStore::new(root.path()));
+        let jobs = (0..3)
+            .map(|_| completed_job("0123456789"))
+            .collect::<Vec<_>>();
+        let job_ids = jobs.iter().map(|job| job.id().value()).collect::<Vec<_>>();
+        for job in jobs {
+            history.push(job);
+        }
+        assert_eq!(ids(&history), job_ids[1..]);
+        assert_eq!(saved_ids(history.store()), job_ids[..1]);
+
+        // A job that is over the limit on its own is kept until the next one.
+        let big_job = completed_job(&"0123456789".repeat(3));
+        let big_id = big_job.id().value();
+        history.push(big_job);
+        assert_eq!(ids(&history), vec![big_id]);
+        assert_eq!(saved_ids(history.store()), job_ids);
+    }
+
+    #[test]
+    fn session_directory_is_unique_to_the_process() {
+        let root = TempDir::new().unwrap();
+        let store = JobStore::new(root.path());
+        let name = store.session_directory().file_name().unwrap();
+        assert!(name
+            .to_string_lossy()
+            .ends_with(&format!("-{}", std::process::id())));
+    }
+
+    #[test]
+    fn old_records_are_removed_from_every_session_when_the_history_is_created() {
+        let root = TempDir::new().unwrap();
+        let old_session = root.path().join("20200101-000000-1");
+        let old_store = JobStore {
+            root: root.path().to_path_buf(),
+            session_directory: old_session.clone(),
+        };
+        old_store.save(&record(Duration::days(3)));
+        let expired_store = JobStore {
+            root: root.path().to_path_buf(),
+            session_directory: root.path().join("20200102-000000-2"),
+        };
+        let recent = record(Duration::minutes(5));
+        expired_store.save(&record(Duration::days(2)));
+        expired_store.save(&recent);
+
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::days(1)),
+            ..NO_LIMITS
+        };
+        let history = JobHistory::new(policy, JobStore::new(root.path()));
+
+        // The session that has nothing left in it is removed.
+        assert!(!old_session.exists());
+        assert_eq!(saved_ids(history.store()), vec![recent.id.value()]);
+    }
+
+    #[test]
+    fn jobs_that_are_too_old_are_deleted_rather_than_saved() {
+        let root = TempDir::new().unwrap();
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::milliseconds(50)),
+            ..NO_LIMITS
+        };
+        let mut history = JobHistory::new(policy, JobStore::new(root.path()));
+        assert_eq!(history.time_until_next_expiry(), None);
+
+        history.push(completed_job("output"));
+        let wait = history.time_until_next_expiry().unwrap();
+        assert!(wait <= std::time::Duration::from_millis(50));
+
+        thread::sleep(wait);
+        history.apply_retention_policy();
+        assert!(history.is_empty());
+        assert_eq!(history.time_until_next_expiry(), None);
+        assert!(saved_ids(history.store()).is_empty());
+    }
+}
@@ -1,9 +1,11 @@
 use crate::{
     configuration::BuildMode,
-    jobs::{gather_process_stdout, CompletionStatus, JobId, JobKind, PendingJob},
+    jobs::{
+        process::run_process, CompletionStatus, ExecutionContext, JobId, JobKind, PendingJob,
+        ProcessCommand,
+    },
     shadow_copy_destination::ShadowCopyDestination,
 };
-use duct::cmd;
 use log::info;
 use std::fmt::Display;
 
@@ -16,28 +18,66 @@ use std::fmt::Display;
 pub struct BuildAllTestsJob {
     destination: ShadowCopyDestination,
     build_mode: BuildMode,
+    /// The packages to build, or empty for the whole workspace.
+    packages: Vec<String>,
     output: String,
 }
 
 impl Display for BuildAllTestsJob {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Build tests in {:?} mode", self.build_mode)
+        if self.packages.is_empty() {
+            write!(f, "Build tests in {:?} mode", self.build_mode)
+        } else {
+            let packages = self.packages.join(", ");
+            write!(
+                f,
+                "Build tests for {} in {:?} mode",
+                packages, self.build_mode
+            )
+        }
     }
 }
 
 impl BuildAllTestsJob {
     pub fn new(destination_directory: ShadowCopyDestination, build_mode: BuildMode) -> PendingJob {
+        Self::for_packages(destination_directory, build_mode, Vec::new())
+    }
+
+    /// Creates a job for just the specified `packages`, or
+    /// the whole workspace if there are none.
+    pub fn for_packages(
+        destination_directory: ShadowCopyDestination,
+        build_mode: BuildMode,
+        packages: Vec<String>,
+    ) -> PendingJob {
         let kind = JobKind::BuildAllTests(BuildAllTestsJob {
             destination: destination_directory,
             build_mode,
+            packages,
             output: Default::default(),
         });
 
         kind.into()
     }
 
+    pub fn build_mode(&self) -> BuildMode {
+        self.build_mode
+    }
+
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
     #[must_use = "Don't ignore the completion status, caller needs to store it"]
-    pub fn execute(&mut self, parent_job_id: JobId) -> CompletionStatus {
+    pub fn execute(
+        &mut self,
+        parent_job_id: JobId,
+        context: &ExecutionContext,
+    ) -> CompletionStatus {
         let cwd = self.destination.cwd();
         info!("{} Building tests in {}", parent_job_id, cwd.display());
 
@@ -49,14 +89,15 @@ impl BuildAllTestsJob {
         if self.build_mode == BuildMode::Release {
             args.push("--release");
         }
+        for package in &self.packages {
+            args.push("--package");
+            args.push(package);
+        }
 
-        let cmd = cmd("cargo", args).stderr_to_stdout().dir(cwd);
-
-        self.output = match gather_process_stdout(cmd, "Build tests", parent_job_id) {
-            Ok(output) => output,
-            Err(err) => return err.to_string().into(),
-        };
+        let cmd = ProcessCommand::cargo("Build tests", args, cwd);
 
-        CompletionStatus::Ok
+        let run = run_process(cmd, &parent_job_id, context, &mut |_| {});
+        self.output = run.output;
+        run.status
     }
 }
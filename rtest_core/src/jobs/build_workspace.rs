@@ -1,9 +1,11 @@
 use crate::{
     configuration::BuildMode,
-    jobs::{gather_process_stdout, CompletionStatus, JobId, JobKind, PendingJob},
+    jobs::{
+        process::run_process, CompletionStatus, ExecutionContext, JobId, JobKind, PendingJob,
+        ProcessCommand,
+    },
     shadow_copy_destination::ShadowCopyDestination,
 };
-use duct::cmd;
 use log::info;
 use std::fmt::Display;
 
@@ -35,8 +37,20 @@ impl BuildWorkspaceJob {
         kind.into()
     }
 
+    pub fn build_mode(&self) -> BuildMode {
+        self.build_mode
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
     #[must_use = "Don't ignore the completion status, caller needs to store it"]
-    pub fn execute(&mut self, parent_job_id: JobId) -> CompletionStatus {
+    pub fn execute(
+        &mut self,
+        parent_job_id: JobId,
+        context: &ExecutionContext,
+    ) -> CompletionStatus {
         let cwd = self.destination.cwd();
         info!(
             "{} Building crate or workspace in {}",
@@ -52,13 +66,10 @@ impl BuildWorkspaceJob {
             args.push("--release");
         }
 
-        let cmd = cmd("cargo", args).stderr_to_stdout().dir(cwd);
-
-        self.output = match gather_process_stdout(cmd, "Build crate or workspace", parent_job_id) {
-            Ok(output) => output,
-            Err(err) => return err.to_string().into(),
-        };
+        let cmd = ProcessCommand::cargo("Build crate or workspace", args, cwd);
 
-        CompletionStatus::Ok
+        let run = run_process(cmd, &parent_job_id, context, &mut |_| {});
+        self.output = run.output;
+        run.status
     }
 }
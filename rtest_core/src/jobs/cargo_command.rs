@@ -0,0 +1,89 @@
+use crate::{
+    configuration::BuildMode,
+    jobs::{
+        process::run_process, CompletionStatus, ExecutionContext, JobId, JobKind, PendingJob,
+        ProcessCommand,
+    },
+    shadow_copy_destination::ShadowCopyDestination,
+};
+use log::info;
+use std::fmt::Display;
+
+/// Runs an arbitrary cargo command, such as `cargo clippy --all-targets`, in the
+/// destination directory. This is used for pipeline stages that don't need any
+/// special handling of their output.
+#[derive(Debug, Clone)]
+pub struct CargoCommandJob {
+    destination: ShadowCopyDestination,
+    build_mode: BuildMode,
+    /// A short name for the command, used in log messages.
+    name: String,
+    /// The arguments to cargo. The first one is the cargo subcommand.
+    args: Vec<String>,
+    output: String,
+}
+
+impl Display for CargoCommandJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Run {} in {:?} mode", self.name, self.build_mode)
+    }
+}
+
+impl CargoCommandJob {
+    pub fn new(
+        destination: ShadowCopyDestination,
+        build_mode: BuildMode,
+        name: String,
+        args: Vec<String>,
+    ) -> PendingJob {
+        let kind = JobKind::CargoCommand(CargoCommandJob {
+            destination,
+            build_mode,
+            name,
+            args,
+            output: Default::default(),
+        });
+
+        kind.into()
+    }
+
+    pub fn build_mode(&self) -> BuildMode {
+        self.build_mode
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    #[must_use = "Don't ignore the completion status, caller needs to store it"]
+    pub fn execute(
+        &mut self,
+        parent_job_id: JobId,
+        context: &ExecutionContext,
+    ) -> CompletionStatus {
+        let cwd = self.destination.cwd();
+        info!(
+            "{} Running cargo {} in {}",
+            parent_job_id,
+            self.args.join(" "),
+            cwd.display()
+        );
+
+        // "--release" has to go straight after the subcommand, any later and
+        // it might end up after a "--" and be passed to the wrong program.
+        let mut args = self.args.clone();
+        if self.build_mode == BuildMode::Release && !args.is_empty() {
+            args.insert(1, "--release".into());
+        }
+
+        let cmd = ProcessCommand::cargo(&self.name, args, cwd);
+
+        let run = run_process(cmd, &parent_job_id, context, &mut |_| {});
+        self.output = run.output;
+        run.status
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
     shadow_copy_destination::ShadowCopyDestination,
     source_directory_watcher::FileSyncEvent,
 };
-use std::fmt::Display;
+use std::{fmt::Display, path::Path};
 
 #[derive(Debug, Clone)]
 pub struct FileSyncJob {
@@ -42,6 +42,13 @@ impl FileSyncJob {
         kind.into()
     }
 
+    /// Returns the path, in the source directory, that was synced.
+    pub fn source_path(&self) -> &Path {
+        match &self.file_sync_event {
+            FileSyncEvent::FileUpdate(path) | FileSyncEvent::Remove(path) => path,
+        }
+    }
+
     #[must_use = "Don't ignore the completion status, caller needs to store it"]
     pub fn execute(&mut self) -> CompletionStatus {
         match &self.file_sync_event {
@@ -1,24 +1,44 @@
 mod build_all_tests;
 mod build_workspace;
+mod cargo_command;
 mod file_sync;
 mod list_all_tests;
+mod load_workspace;
+mod output;
+mod process;
+mod resource_usage;
+mod retry;
 mod run_tests;
+mod scripted_process;
 mod shadow_copy;
+mod stress_test;
 
 pub use build_all_tests::BuildAllTestsJob;
 pub use build_workspace::BuildWorkspaceJob;
+pub use cargo_command::CargoCommandJob;
 pub use file_sync::FileSyncJob;
 pub use list_all_tests::ListAllTestsJob;
-pub use run_tests::RunTestsJob;
+pub use load_workspace::LoadWorkspaceJob;
+pub use output::{JobOutput, LiveOutput};
+pub use process::{ProcessCommand, ProcessRun, ProcessRunner, SystemProcessRunner};
+pub use resource_usage::{ProcessExit, ResourceTotals, ResourceUsage};
+pub use retry::{is_transient_failure, RetryPolicy};
+pub use run_tests::{DirectRun, IsolatedTestRun, RunTestsJob, TestProgress};
+pub use scripted_process::{ScriptedProcess, ScriptedProcessRunner};
 pub use shadow_copy::ShadowCopyJob;
+pub use stress_test::{StressFailure, StressOptions, StressTestJob};
 
+use crate::{bool_flag::BoolFlag, pipeline::StageRef, test_settings::TestExecutionSettings};
 use chrono::{DateTime, Utc};
-use log::{info, warn};
 use logging_timer::{finish, stimer, Level};
+use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
-    process::Command,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 pub trait Job: Display {
@@ -31,6 +51,12 @@ pub struct PendingJob {
     id: JobId,
     kind: JobKind,
     creation_date: DateTime<Utc>,
+    /// The attempt number, starting at 1. Greater than 1 for retries.
+    attempt: u32,
+    /// A retried job is not started until this time has passed.
+    not_before: Option<DateTime<Utc>>,
+    /// The pipeline stage that the job performs, if any.
+    stage: Option<StageRef>,
 }
 
 impl Display for PendingJob {
@@ -45,6 +71,9 @@ impl From<JobKind> for PendingJob {
             id: JobId::new(),
             kind,
             creation_date: Utc::now(),
+            attempt: 1,
+            not_before: None,
+            stage: None,
         }
     }
 }
@@ -60,14 +89,48 @@ impl Job for PendingJob {
 }
 
 impl PendingJob {
-    pub fn execute(self) -> CompletedJob {
-        let tmr = stimer!(Level::Info; "execute()", "{}", self.id);
+    /// Creates a new attempt at a failed job, to be started after `delay`.
+    /// The new attempt has its own id.
+    pub fn retry_of(job: &CompletedJob, delay: Duration) -> Self {
+        let delay = chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
 
-        let executing_job: ExecutingJob = self.into();
-        let completed_job = executing_job.execute();
+        Self {
+            id: JobId::new(),
+            kind: job.kind.clone(),
+            creation_date: Utc::now(),
+            attempt: job.attempt + 1,
+            not_before: Some(Utc::now() + delay),
+            stage: job.stage,
+        }
+    }
 
-        finish!(tmr, "completed with status={:?}", completed_job.status);
-        completed_job
+    /// Marks the job as performing a stage of the pipeline.
+    pub fn with_stage(mut self, stage: StageRef) -> Self {
+        self.stage = Some(stage);
+        self
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns true if the job can be started now. Only
+    /// retried jobs have to wait.
+    pub fn is_ready(&self) -> bool {
+        self.not_before.is_none_or(|t| Utc::now() >= t)
+    }
+
+    /// Returns how long it will be until the job can be started,
+    /// or `None` if it can be started now.
+    pub fn time_until_ready(&self) -> Option<Duration> {
+        let not_before = self.not_before?;
+        (not_before - Utc::now()).to_std().ok()
+    }
+
+    /// Executes the job. See `ExecutionContext` for the ways in which
+    /// the execution can be cut short.
+    pub fn execute(self, context: &ExecutionContext) -> CompletedJob {
+        ExecutingJob::from(self).execute(context)
     }
 }
 
@@ -77,6 +140,9 @@ pub struct ExecutingJob {
     kind: JobKind,
     creation_date: DateTime<Utc>,
     start_date: DateTime<Utc>,
+    attempt: u32,
+    stage: Option<StageRef>,
+    live_output: LiveOutput,
 }
 
 impl Display for ExecutingJob {
@@ -92,6 +158,9 @@ impl From<PendingJob> for ExecutingJob {
             kind: pending_job.kind,
             creation_date: pending_job.creation_date,
             start_date: Utc::now(),
+            attempt: pending_job.attempt,
+            stage: pending_job.stage,
+            live_output: Default::default(),
         }
     }
 }
@@ -107,10 +176,25 @@ impl Job for ExecutingJob {
 }
 
 impl ExecutingJob {
-    fn execute(mut self) -> CompletedJob {
+    /// Returns the output written so far by the job's child processes.
+    /// It is only filled in if the job is executed with an `ExecutionContext`
+    /// that shares it, see `ExecutionContext::with_live_output`.
+    pub fn live_output(&self) -> &LiveOutput {
+        &self.live_output
+    }
+
+    /// Executes the job. See `ExecutionContext` for the ways in which
+    /// the execution can be cut short.
+    pub fn execute(mut self, context: &ExecutionContext) -> CompletedJob {
+        let tmr = stimer!(Level::Info; "execute()", "{}", self.id);
+
         // Execute the job-specific data.
-        let status = self.kind.execute(self.id().clone());
-        CompletedJob::new(self, status)
+        let status = self.kind.execute(self.id().clone(), context);
+        let transcript = context.live_output().text();
+        let completed_job = CompletedJob::new(self, status, context.resource_usage(), transcript);
+
+        finish!(tmr, "completed with status={:?}", completed_job.status);
+        completed_job
     }
 }
 
@@ -122,6 +206,10 @@ pub struct CompletedJob {
     start_date: DateTime<Utc>,
     completed_date: DateTime<Utc>,
     status: CompletionStatus,
+    attempt: u32,
+    stage: Option<StageRef>,
+    resource_usage: Option<ResourceUsage>,
+    transcript: String,
 }
 
 impl Job for CompletedJob {
@@ -141,7 +229,12 @@ impl Display for CompletedJob {
 }
 
 impl CompletedJob {
-    pub fn new(executing_job: ExecutingJob, status: CompletionStatus) -> Self {
+    pub fn new(
+        executing_job: ExecutingJob,
+        status: CompletionStatus,
+        resource_usage: Option<ResourceUsage>,
+        transcript: String,
+    ) -> Self {
         Self {
             id: executing_job.id,
             kind: executing_job.kind,
@@ -149,6 +242,10 @@ impl CompletedJob {
             start_date: executing_job.start_date,
             completed_date: Utc::now(),
             status,
+            attempt: executing_job.attempt,
+            stage: executing_job.stage,
+            resource_usage,
+            transcript,
         }
     }
 
@@ -156,17 +253,133 @@ impl CompletedJob {
         self.status.clone()
     }
 
+    /// Returns the output (stdout and stderr) of the job's child processes.
+    pub fn output(&self) -> &str {
+        self.kind.output()
+    }
+
+    /// Returns the output in the order it was written while the job was
+    /// executing, see `ExecutingJob::live_output`. This is not always the
+    /// same as `output`, which some jobs trim or put in a different order,
+    /// so offsets into the live output are only valid in this.
+    pub fn transcript(&self) -> &str {
+        &self.transcript
+    }
+
+    pub fn creation_date(&self) -> DateTime<Utc> {
+        self.creation_date
+    }
+
+    pub fn start_date(&self) -> DateTime<Utc> {
+        self.start_date
+    }
+
+    pub fn completed_date(&self) -> DateTime<Utc> {
+        self.completed_date
+    }
+
+    /// Returns the attempt number, starting at 1. Greater than 1 for retries.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the resources used by the job's child processes. Jobs
+    /// that don't run a child process return `None`.
+    pub fn resource_usage(&self) -> Option<&ResourceUsage> {
+        self.resource_usage.as_ref()
+    }
+
+    /// Returns the pipeline stage that the job performed, if any.
+    pub fn stage(&self) -> Option<StageRef> {
+        self.stage
+    }
+
     pub fn succeeded(&self) -> bool {
         self.status == CompletionStatus::Ok
     }
 }
 
+/// A summary of a job at a point in time, without any of the job's data
+/// or output. See `JobEngine::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobSnapshot {
+    pub id: JobId,
+    pub job_type: JobType,
+    pub description: String,
+    pub attempt: u32,
+    pub creation_date: DateTime<Utc>,
+    pub start_date: Option<DateTime<Utc>>,
+    pub completed_date: Option<DateTime<Utc>>,
+    pub status: JobStatus,
+    pub resource_usage: Option<ResourceUsage>,
+}
+
+/// Where a job is in its lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Pending,
+    Executing,
+    Completed(CompletionStatus),
+}
+
+impl From<&PendingJob> for JobSnapshot {
+    fn from(job: &PendingJob) -> Self {
+        Self {
+            id: job.id.clone(),
+            job_type: job.kind.job_type(),
+            description: job.kind.to_string(),
+            attempt: job.attempt,
+            creation_date: job.creation_date,
+            start_date: None,
+            completed_date: None,
+            status: JobStatus::Pending,
+            resource_usage: None,
+        }
+    }
+}
+
+impl From<&ExecutingJob> for JobSnapshot {
+    fn from(job: &ExecutingJob) -> Self {
+        Self {
+            id: job.id.clone(),
+            job_type: job.kind.job_type(),
+            description: job.kind.to_string(),
+            attempt: job.attempt,
+            creation_date: job.creation_date,
+            start_date: Some(job.start_date),
+            completed_date: None,
+            status: JobStatus::Executing,
+            resource_usage: None,
+        }
+    }
+}
+
+impl From<&CompletedJob> for JobSnapshot {
+    fn from(job: &CompletedJob) -> Self {
+        Self {
+            id: job.id.clone(),
+            job_type: job.kind.job_type(),
+            description: job.kind.to_string(),
+            attempt: job.attempt,
+            creation_date: job.creation_date,
+            start_date: Some(job.start_date),
+            completed_date: Some(job.completed_date),
+            status: JobStatus::Completed(job.status.clone()),
+            resource_usage: job.resource_usage.clone(),
+        }
+    }
+}
+
 /// Specifies the completion status of a Job.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompletionStatus {
     Unknown,
     Ok,
     Error(String),
+    /// The job took longer than its configured timeout and was killed.
+    TimedOut,
+    /// The job was cancelled before it completed.
+    Cancelled,
 }
 
 impl<S: Into<String>> From<S> for CompletionStatus {
@@ -195,6 +408,15 @@ pub enum JobKind {
     ListAllTests(ListAllTestsJob),
 
     RunTests(RunTestsJob),
+
+    /// Run an arbitrary cargo command.
+    CargoCommand(CargoCommandJob),
+
+    /// Run the same tests repeatedly.
+    StressTest(StressTestJob),
+
+    /// Find the packages in the workspace, using cargo metadata.
+    LoadWorkspace(LoadWorkspaceJob),
 }
 
 impl Display for JobKind {
@@ -206,29 +428,215 @@ impl Display for JobKind {
             JobKind::BuildAllTests(build_tests_job) => build_tests_job.fmt(f),
             JobKind::ListAllTests(list_tests_job) => list_tests_job.fmt(f),
             JobKind::RunTests(run_tests_job) => run_tests_job.fmt(f),
+            JobKind::CargoCommand(cargo_command_job) => cargo_command_job.fmt(f),
+            JobKind::StressTest(stress_test_job) => stress_test_job.fmt(f),
+            JobKind::LoadWorkspace(load_workspace_job) => load_workspace_job.fmt(f),
         }
     }
 }
 
 impl JobKind {
+    /// Returns the `JobType` of this kind of job.
+    pub fn job_type(&self) -> JobType {
+        match self {
+            JobKind::ShadowCopy(_) => JobType::ShadowCopy,
+            JobKind::FileSync(_) => JobType::FileSync,
+            JobKind::BuildAllTests(_) => JobType::BuildAllTests,
+            JobKind::BuildWorkspace(_) => JobType::BuildWorkspace,
+            JobKind::ListAllTests(_) => JobType::ListAllTests,
+            JobKind::RunTests(_) => JobType::RunTests,
+            JobKind::CargoCommand(_) => JobType::CargoCommand,
+            JobKind::StressTest(_) => JobType::StressTest,
+            JobKind::LoadWorkspace(_) => JobType::LoadWorkspace,
+        }
+    }
+
+    /// Returns the output (stdout and stderr) gathered from the job's child
+    /// process. Jobs that don't run a child process have no output.
+    pub fn output(&self) -> &str {
+        match self {
+            JobKind::ShadowCopy(_) => "",
+            JobKind::FileSync(_) => "",
+            JobKind::BuildWorkspace(build_crate_job) => build_crate_job.output(),
+            JobKind::BuildAllTests(build_tests_job) => build_tests_job.output(),
+            JobKind::ListAllTests(list_tests_job) => list_tests_job.output(),
+            JobKind::RunTests(run_tests_job) => run_tests_job.output(),
+            JobKind::CargoCommand(cargo_command_job) => cargo_command_job.output(),
+            JobKind::StressTest(stress_test_job) => stress_test_job.output(),
+            JobKind::LoadWorkspace(load_workspace_job) => load_workspace_job.output(),
+        }
+    }
+
+    /// Executes the job. The timeout in the `context` only applies to jobs that run
+    /// child processes, the file copying jobs always run to completion.
     #[must_use = "Don't ignore the completion status, caller needs to store it"]
-    fn execute(&mut self, parent_job_id: JobId) -> CompletionStatus {
+    fn execute(&mut self, parent_job_id: JobId, context: &ExecutionContext) -> CompletionStatus {
         match self {
-            JobKind::ShadowCopy(shadow_copy_job) => shadow_copy_job.execute(),
+            JobKind::ShadowCopy(shadow_copy_job) => shadow_copy_job.execute(context),
             JobKind::FileSync(file_sync_job) => file_sync_job.execute(),
-            JobKind::BuildWorkspace(build_crate_job) => build_crate_job.execute(parent_job_id),
-            JobKind::BuildAllTests(build_tests_job) => build_tests_job.execute(parent_job_id),
-            JobKind::ListAllTests(list_tests_job) => list_tests_job.execute(parent_job_id),
-            JobKind::RunTests(run_tests_job) => run_tests_job.execute(parent_job_id),
+            JobKind::BuildWorkspace(build_crate_job) => {
+                build_crate_job.execute(parent_job_id, context)
+            }
+            JobKind::BuildAllTests(build_tests_job) => {
+                build_tests_job.execute(parent_job_id, context)
+            }
+            JobKind::ListAllTests(list_tests_job) => list_tests_job.execute(parent_job_id, context),
+            JobKind::RunTests(run_tests_job) => run_tests_job.execute(parent_job_id, context),
+            JobKind::CargoCommand(cargo_command_job) => {
+                cargo_command_job.execute(parent_job_id, context)
+            }
+            JobKind::StressTest(stress_test_job) => stress_test_job.execute(parent_job_id, context),
+            JobKind::LoadWorkspace(load_workspace_job) => {
+                load_workspace_job.execute(parent_job_id, context)
+            }
         }
     }
 }
 
+/// A fieldless version of `JobKind`. Used as a key for settings that apply to
+/// every job of a particular kind, such as timeouts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JobType {
+    ShadowCopy,
+    FileSync,
+    BuildAllTests,
+    BuildWorkspace,
+    ListAllTests,
+    RunTests,
+    CargoCommand,
+    StressTest,
+    LoadWorkspace,
+}
+
+/// Controls how a job is executed, as opposed to what it does (which is
+/// determined by the `JobKind`). Clones share the same cancellation flag,
+/// so the engine can keep a clone and use it to cancel the job from
+/// another thread.
+#[derive(Debug, Clone)]
+pub struct ExecutionContext {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    cancelled: BoolFlag,
+    process_runner: Arc<dyn ProcessRunner>,
+    resource_usage: Arc<Mutex<Option<ResourceUsage>>>,
+    live_output: LiveOutput,
+    test_settings: Arc<TestExecutionSettings>,
+    test_progress: Option<Arc<dyn TestProgress>>,
+}
+
+impl Default for ExecutionContext {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl ExecutionContext {
+    /// Creates a new context for a job that is about to start. If the job is
+    /// still running a child process `timeout` after that then the process is
+    /// killed and the job completes with a status of `CompletionStatus::TimedOut`.
+    /// The limit is for the whole job, however many processes it runs.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancelled: Default::default(),
+            process_runner: Arc::new(SystemProcessRunner),
+            resource_usage: Default::default(),
+            live_output: Default::default(),
+            test_settings: Default::default(),
+            test_progress: None,
+        }
+    }
+
+    /// Replaces the runner used to start child processes.
+    pub fn with_process_runner(mut self, process_runner: Arc<dyn ProcessRunner>) -> Self {
+        self.process_runner = process_runner;
+        self
+    }
+
+    pub fn process_runner(&self) -> &dyn ProcessRunner {
+        &*self.process_runner
+    }
+
+    /// Replaces the buffer that the output of child processes is copied
+    /// into as it is written.
+    pub fn with_live_output(mut self, live_output: LiveOutput) -> Self {
+        self.live_output = live_output;
+        self
+    }
+
+    pub fn live_output(&self) -> &LiveOutput {
+        &self.live_output
+    }
+
+    /// Replaces the settings that tests are run with.
+    pub fn with_test_settings(mut self, test_settings: Arc<TestExecutionSettings>) -> Self {
+        self.test_settings = test_settings;
+        self
+    }
+
+    pub fn test_settings(&self) -> &TestExecutionSettings {
+        &self.test_settings
+    }
+
+    /// Sets where the outcomes of the tests are reported as they are run.
+    pub fn with_test_progress(mut self, test_progress: Arc<dyn TestProgress>) -> Self {
+        self.test_progress = Some(test_progress);
+        self
+    }
+
+    fn without_test_progress(mut self) -> Self {
+        self.test_progress = None;
+        self
+    }
+
+    pub fn test_progress(&self) -> Option<&dyn TestProgress> {
+        self.test_progress.as_deref()
+    }
+
+    /// Adds the resources used by a child process to the total for the job.
+    pub fn record_resource_usage(&self, usage: &ResourceUsage) {
+        let mut guard = self.resource_usage.lock().unwrap();
+        guard.get_or_insert_with(Default::default).merge(usage);
+    }
+
+    /// Returns the total resources used by the job's child processes,
+    /// or `None` if it has not run any.
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.resource_usage.lock().unwrap().clone()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns when the job's time is up, if it has a timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns true if the job has used up all the time it is allowed.
+    pub fn is_timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Cancels the job. Any child process is killed and the job completes
+    /// with a status of `CompletionStatus::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.set_true();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.is_true()
+    }
+}
+
 /// Every Job has a unique id.
 /// Note that cloning theoretically creates a duplicate Id. In reality, this only happens
 /// inside the engine when it is executing the job and when we are passing them down
 /// the call stack so they can be printed out. It's not a problem in practice.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct JobId {
     id: usize,
 }
@@ -247,82 +655,10 @@ impl JobId {
             id: ID.fetch_add(1, Ordering::SeqCst),
         }
     }
-}
 
-/// The output data of a process, converted to a slightly
-/// friendlier string form.
-#[derive(Debug, Clone)]
-pub struct ProcessOutput {
-    exit_status: std::process::ExitStatus,
-    stdout: String,
-    stderr: String,
-}
-
-impl From<std::process::Output> for ProcessOutput {
-    fn from(output: std::process::Output) -> Self {
-        Self {
-            exit_status: output.status,
-            stdout: String::from_utf8_lossy(&output.stdout).into(),
-            stderr: String::from_utf8_lossy(&output.stderr).into(),
-        }
+    /// Returns the numeric value of the id. Ids are only unique within
+    /// one run of rtest.
+    pub fn value(&self) -> usize {
+        self.id
     }
 }
-
-impl ProcessOutput {
-    pub fn success(&self) -> bool {
-        self.exit_status.success()
-    }
-}
-
-fn gather_process_output(
-    mut command: Command,
-    description: &str,
-    parent_job_id: JobId,
-) -> Result<ProcessOutput, String> {
-    let output: ProcessOutput = match command.output() {
-        Ok(result) => result.into(),
-        Err(e) => return Err(format!("{} process start failed, err={}", description, e)),
-    };
-
-    let msg = format!(
-        "{} {} {}. ExitStatus={:?}, stdout={} bytes, stderr={} bytes",
-        parent_job_id,
-        description,
-        if output.exit_status.success() {
-            "succeeded"
-        } else {
-            "failed"
-        },
-        output.exit_status,
-        output.stdout.len(),
-        output.stderr.len()
-    );
-
-    if output.success() {
-        info!("{}", msg);
-        Ok(output)
-    } else {
-        warn!("{}", msg);
-        Err(msg)
-    }
-}
-
-/// Gathers the stdout of a duct command. If you want to gather both
-/// the stdout and the stderr, call `stderr_to_stdout` on your command
-/// before calling this function.
-fn gather_process_stdout(
-    cmd: duct::Expression,
-    description: &str,
-    parent_job_id: JobId,
-) -> std::io::Result<String> {
-    let output = cmd.read()?;
-
-    info!(
-        "{} {} succeeded, stdout={} bytes",
-        parent_job_id,
-        description,
-        output.len()
-    );
-
-    Ok(output)
-}
@@ -0,0 +1,85 @@
+use crate::{
+    jobs::{process::run_process, CompletionStatus, ExecutionContext, JobId, JobKind, PendingJob},
+    shadow_copy_destination::ShadowCopyDestination,
+    utils::plural_s,
+    workspace::Workspace,
+};
+use log::{info, warn};
+use std::fmt::Display;
+
+/// Runs `cargo metadata` to find the packages in the workspace and the
+/// dependencies between them. The engine uses the `Workspace` to work
+/// out which packages are affected by a change.
+#[derive(Debug, Clone)]
+pub struct LoadWorkspaceJob {
+    destination: ShadowCopyDestination,
+    output: String,
+    workspace: Option<Workspace>,
+}
+
+impl Display for LoadWorkspaceJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Load the workspace in {:?}", self.destination.cwd())
+    }
+}
+
+impl LoadWorkspaceJob {
+    pub fn new(destination: ShadowCopyDestination) -> PendingJob {
+        let kind = JobKind::LoadWorkspace(LoadWorkspaceJob {
+            destination,
+            output: Default::default(),
+            workspace: None,
+        });
+
+        kind.into()
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Returns the workspace, or `None` if the job failed or hasn't been executed.
+    pub fn workspace(&self) -> Option<&Workspace> {
+        self.workspace.as_ref()
+    }
+
+    #[must_use = "Don't ignore the completion status, caller needs to store it"]
+    pub fn execute(
+        &mut self,
+        parent_job_id: JobId,
+        context: &ExecutionContext,
+    ) -> CompletionStatus {
+        let cwd = self.destination.cwd();
+        let command = Workspace::metadata_command(cwd);
+        let run = run_process(command, &parent_job_id, context, &mut |_| {});
+        self.output = run.output;
+
+        if run.status != CompletionStatus::Ok {
+            warn!(
+                "{} Cannot get cargo metadata, status = {:?}",
+                parent_job_id, run.status
+            );
+            return run.status;
+        }
+
+        match Workspace::from_metadata(&self.output, cwd) {
+            Ok(workspace) => {
+                info!(
+                    "{} Found {} package{} in the workspace",
+                    parent_job_id,
+                    workspace.packages().len(),
+                    plural_s(workspace.packages().len())
+                );
+                self.workspace = Some(workspace);
+                CompletionStatus::Ok
+            }
+            Err(err) => {
+                warn!(
+                    "{} Cannot parse cargo metadata, err = {}",
+                    parent_job_id, err
+                );
+                format!("Cannot parse cargo metadata, err = {}", err).into()
+            }
+        }
+    }
+}
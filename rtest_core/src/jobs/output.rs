@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+
+/// The output (stdout and stderr) of a job that is still executing. Lines
+/// are appended as the job's child processes write them, so the output can
+/// be followed while the job runs. Clones share the same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct LiveOutput {
+    buffer: Arc<Mutex<String>>,
+}
+
+impl LiveOutput {
+    /// Appends a line of output. A newline is added to the end of the line.
+    pub fn push_line(&self, line: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.push_str(line);
+        buffer.push('\n');
+    }
+
+    /// Returns all of the output written so far.
+    pub fn text(&self) -> String {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    /// Returns the output from byte `offset` onwards, and the offset of the
+    /// end of the output, which should be used as the `offset` next time.
+    pub fn read_from(&self, offset: usize) -> (String, usize) {
+        let buffer = self.buffer.lock().unwrap();
+        (tail(&buffer, offset).to_string(), buffer.len())
+    }
+}
+
+/// The output of a job, as returned by `JobEngine::job_output`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobOutput {
+    /// The output from the requested offset onwards.
+    pub text: String,
+
+    /// The offset of the end of the output. Pass this as the offset
+    /// in the next request to receive only the output written since.
+    pub next_offset: usize,
+
+    /// True if the job has completed, so no more output will be written.
+    pub complete: bool,
+}
+
+impl JobOutput {
+    /// Creates the output of a job that has completed.
+    pub fn completed(output: &str, offset: usize) -> Self {
+        Self {
+            text: tail(output, offset).to_string(),
+            next_offset: output.len(),
+            complete: true,
+        }
+    }
+
+    /// Creates the output of a job that is pending or executing.
+    pub fn live(output: &LiveOutput, offset: usize) -> Self {
+        let (text, next_offset) = output.read_from(offset);
+        Self {
+            text,
+            next_offset,
+            complete: false,
+        }
+    }
+}
+
+/// Returns `s` from byte `offset` onwards, or an empty string if
+/// `offset` is past the end or is not on a character boundary.
+fn tail(s: &str, offset: usize) -> &str {
+    s.get(offset..).unwrap_or_default()
+}
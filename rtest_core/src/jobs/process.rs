@@ -0,0 +1,403 @@
+use crate::jobs::{CompletionStatus, ExecutionContext, JobId, ProcessExit, ResourceUsage};
+use log::{info, warn};
+use std::{
+    fmt::Debug,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a running process checks whether it has been cancelled.
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The result of running a child process. The `output` is always
+/// available, even if the process failed or was killed, because
+/// that is usually where the explanation for the failure is.
+#[derive(Debug, Clone)]
+pub struct ProcessRun {
+    pub output: String,
+    pub status: CompletionStatus,
+    pub usage: ResourceUsage,
+}
+
+/// A description of a child process for a `ProcessRunner` to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    /// Environment variables to set in addition to those inherited.
+    pub env: Vec<(String, String)>,
+    /// A short description of the process, for example "Build tests",
+    /// used in log and error messages.
+    pub description: String,
+}
+
+impl ProcessCommand {
+    /// Creates a command that runs cargo with the specified arguments.
+    pub fn cargo<I, S>(description: &str, args: I, cwd: &Path) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            program: "cargo".into(),
+            args: args.into_iter().map(Into::into).collect(),
+            cwd: cwd.to_path_buf(),
+            env: Vec::new(),
+            description: description.into(),
+        }
+    }
+
+    /// Creates a command that runs `program` directly.
+    pub fn program<I, S>(description: &str, program: &Path, args: I, cwd: &Path) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            program: program.to_string_lossy().into_owned(),
+            ..Self::cargo(description, args, cwd)
+        }
+    }
+
+    /// Adds an environment variable to set in the process.
+    pub fn with_env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// Runs child processes on behalf of jobs. All jobs go through the runner in
+/// their `ExecutionContext`, which means the real runner (`SystemProcessRunner`)
+/// can be swapped for a fake such as `ScriptedProcessRunner` in tests.
+pub trait ProcessRunner: Debug + Send + Sync {
+    /// Runs `command`, passing each line of its combined stdout and stderr to
+    /// `on_line` as it arrives. Implementations must stop the process if the
+    /// `context` times out or is cancelled.
+    fn run(
+        &self,
+        command: &ProcessCommand,
+        parent_job_id: &JobId,
+        context: &ExecutionContext,
+        on_line: &mut dyn FnMut(&str),
+    ) -> ProcessRun;
+}
+
+/// Runs a command using the `ProcessRunner` in the `context`. The output is
+/// copied to the `context`'s live output as it is written, and the resources
+/// used are added to the `context`.
+pub fn run_process(
+    command: ProcessCommand,
+    parent_job_id: &JobId,
+    context: &ExecutionContext,
+    on_line: &mut dyn FnMut(&str),
+) -> ProcessRun {
+    let live_output = context.live_output();
+    let mut on_line = |line: &str| {
+        live_output.push_line(line);
+        on_line(line);
+    };
+
+    let run = context
+        .process_runner()
+        .run(&command, parent_job_id, context, &mut on_line);
+    context.record_resource_usage(&run.usage);
+    run
+}
+
+/// The real `ProcessRunner`, which starts child processes using `std::process::Command`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemProcessRunner;
+
+impl ProcessRunner for SystemProcessRunner {
+    /// Runs the command, gathering both its stdout and its stderr.
+    ///
+    /// The output is read line by line on a separate thread, and each line is
+    /// passed to `on_line` as it arrives. If the process is still running at
+    /// the `context`'s deadline, or the `context` is cancelled, then it and any
+    /// processes it has started (such as test binaries started by `cargo test`)
+    /// are killed and the status is `CompletionStatus::TimedOut` or
+    /// `CompletionStatus::Cancelled` respectively.
+    fn run(
+        &self,
+        command: &ProcessCommand,
+        parent_job_id: &JobId,
+        context: &ExecutionContext,
+        on_line: &mut dyn FnMut(&str),
+    ) -> ProcessRun {
+        let description = command.description.as_str();
+
+        // An earlier process may have used up all of the job's time.
+        if context.is_timed_out() {
+            warn!(
+                "{} {} not started, the job has timed out",
+                parent_job_id, description
+            );
+            return ProcessRun {
+                output: String::new(),
+                status: CompletionStatus::TimedOut,
+                usage: Default::default(),
+            };
+        }
+
+        let (mut child, reader) = match spawn(command) {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                return ProcessRun {
+                    output: String::new(),
+                    status: format!("{} process start failed, err={}", description, err).into(),
+                    usage: Default::default(),
+                }
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        let reader_thread = thread::Builder::new()
+            .name("PROCESS_READER".into())
+            .spawn(move || {
+                // Compilers and tests can output anything, so don't insist on UTF-8.
+                let mut reader = BufReader::new(reader);
+                let mut buf = Vec::new();
+                loop {
+                    buf.clear();
+                    match reader.read_until(b'\n', &mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {
+                            let line = String::from_utf8_lossy(&buf);
+                            let line = line.trim_end_matches(&['\r', '\n'][..]);
+                            if sender.send(line.to_string()).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            })
+            .expect("Cannot create PROCESS_READER thread");
+
+        let deadline = context.deadline();
+        let mut output = String::new();
+        let mut stopped_status = None;
+
+        loop {
+            if context.is_cancelled() {
+                stopped_status = Some(CompletionStatus::Cancelled);
+                break;
+            }
+
+            // Wake up periodically to check for cancellation.
+            let mut wait = CANCELLATION_POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    stopped_status = Some(CompletionStatus::TimedOut);
+                    break;
+                }
+                wait = wait.min(deadline - now);
+            }
+
+            match receiver.recv_timeout(wait) {
+                Ok(line) => {
+                    on_line(&line);
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        if let Some(status) = stopped_status {
+            if status == CompletionStatus::TimedOut {
+                warn!(
+                    "{} {} timed out, the job's limit is {:?}, killing the process tree",
+                    parent_job_id,
+                    description,
+                    context.timeout().unwrap()
+                );
+            } else {
+                warn!(
+                    "{} {} was cancelled, killing the process tree",
+                    parent_job_id, description
+                );
+            }
+
+            kill_process_tree(&mut child);
+            let mut usage = wait_for_exit(&mut child).unwrap_or_default();
+            reader_thread
+                .join()
+                .expect("PROCESS_READER thread panicked");
+
+            // Pick up anything that was written before the process died.
+            for line in receiver.try_iter() {
+                output.push_str(&line);
+                output.push('\n');
+            }
+
+            usage.output_bytes = output.len();
+            return ProcessRun {
+                output,
+                status,
+                usage,
+            };
+        }
+
+        reader_thread
+            .join()
+            .expect("PROCESS_READER thread panicked");
+
+        let mut usage = match wait_for_exit(&mut child) {
+            Ok(usage) => usage,
+            Err(err) => {
+                return ProcessRun {
+                    status: format!("{} {} wait failed, err={}", parent_job_id, description, err)
+                        .into(),
+                    usage: ResourceUsage {
+                        output_bytes: output.len(),
+                        ..Default::default()
+                    },
+                    output,
+                }
+            }
+        };
+        usage.output_bytes = output.len();
+
+        let status = if usage.exit == Some(ProcessExit::Code(0)) {
+            info!(
+                "{} {} succeeded, stdout={} bytes, user={:?}, sys={:?}, maxrss={} KB",
+                parent_job_id,
+                description,
+                output.len(),
+                usage.user_time,
+                usage.system_time,
+                usage.max_rss_kb
+            );
+            CompletionStatus::Ok
+        } else {
+            let msg = format!(
+                "{} {} failed. Exit={:?}, stdout={} bytes",
+                parent_job_id,
+                description,
+                usage.exit,
+                output.len()
+            );
+            warn!("{}", msg);
+            msg.into()
+        };
+
+        ProcessRun {
+            output,
+            status,
+            usage,
+        }
+    }
+}
+
+/// Starts the process with its stdout and stderr both connected to the
+/// write end of the returned pipe.
+fn spawn(command: &ProcessCommand) -> io::Result<(Child, os_pipe::PipeReader)> {
+    let (reader, writer) = os_pipe::pipe()?;
+
+    let mut cmd = Command::new(&command.program);
+    cmd.args(&command.args)
+        .current_dir(&command.cwd)
+        .envs(command.env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(writer.try_clone()?)
+        .stderr(writer);
+    in_new_process_group(&mut cmd);
+    let child = cmd.spawn()?;
+
+    // `cmd` holds our copies of the write end of the pipe. They have to be
+    // closed, otherwise the reader will never see the end of the output.
+    drop(cmd);
+
+    Ok((child, reader))
+}
+
+/// Places the child in a new process group, so that it and all of its own
+/// children can be killed in one go.
+#[cfg(unix)]
+fn in_new_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn in_new_process_group(_cmd: &mut Command) {}
+
+/// Kills the process and all of its descendants.
+#[cfg(unix)]
+fn kill_process_tree(child: &mut Child) {
+    // Because of `in_new_process_group` the process group id is the same as the pid.
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+/// Kills the process. Grandchild processes are not killed on this platform.
+#[cfg(not(unix))]
+fn kill_process_tree(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Waits for the process to exit, and returns how it exited and the resources
+/// it used. `wait4` is used rather than `Child::wait` because it also returns
+/// the rusage of the process.
+#[cfg(unix)]
+fn wait_for_exit(child: &mut Child) -> io::Result<ResourceUsage> {
+    let pid = child.id() as libc::pid_t;
+    let mut status: libc::c_int = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    while unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    let exit = if libc::WIFEXITED(status) {
+        Some(ProcessExit::Code(libc::WEXITSTATUS(status)))
+    } else if libc::WIFSIGNALED(status) {
+        Some(ProcessExit::Signal(libc::WTERMSIG(status)))
+    } else {
+        None
+    };
+
+    // Linux reports the maximum RSS in kilobytes, macOS in bytes.
+    let max_rss = rusage.ru_maxrss.max(0) as u64;
+    let max_rss_kb = if cfg!(target_os = "macos") {
+        max_rss / 1024
+    } else {
+        max_rss
+    };
+
+    Ok(ResourceUsage {
+        processes: 1,
+        exit,
+        user_time: to_duration(rusage.ru_utime),
+        system_time: to_duration(rusage.ru_stime),
+        max_rss_kb,
+        output_bytes: 0,
+    })
+}
+
+#[cfg(unix)]
+fn to_duration(tv: libc::timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec.max(0) as u64) + Duration::from_micros(tv.tv_usec.max(0) as u64)
+}
+
+#[cfg(not(unix))]
+fn wait_for_exit(child: &mut Child) -> io::Result<ResourceUsage> {
+    let status = child.wait()?;
+
+    Ok(ResourceUsage {
+        processes: 1,
+        exit: status.code().map(ProcessExit::Code),
+        ..Default::default()
+    })
+}
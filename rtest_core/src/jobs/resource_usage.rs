@@ -0,0 +1,89 @@
+use crate::jobs::{CompletedJob, Job};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How a child process exited.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProcessExit {
+    /// The process exited normally with this exit code.
+    Code(i32),
+    /// The process was killed by this signal (Unix only).
+    Signal(i32),
+}
+
+/// The resources used by the child processes of a job. The CPU times and peak
+/// RSS include any processes that the child waited for, such as the `rustc`
+/// processes started by cargo. They are only available on Unix, and are zero
+/// on other platforms.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// The number of child processes that the job ran.
+    pub processes: usize,
+
+    /// How the most recent child process exited, if known.
+    pub exit: Option<ProcessExit>,
+
+    pub user_time: Duration,
+    pub system_time: Duration,
+
+    /// The peak resident set size in kilobytes. This is the largest
+    /// of the values for the individual processes, not their sum.
+    pub max_rss_kb: u64,
+
+    /// The number of bytes written to stdout and stderr.
+    pub output_bytes: usize,
+}
+
+impl ResourceUsage {
+    /// Adds the usage of another process run by the same job.
+    pub fn merge(&mut self, other: &ResourceUsage) {
+        self.processes += other.processes;
+        self.exit = other.exit.or(self.exit);
+        self.user_time += other.user_time;
+        self.system_time += other.system_time;
+        self.max_rss_kb = self.max_rss_kb.max(other.max_rss_kb);
+        self.output_bytes += other.output_bytes;
+    }
+
+    pub fn cpu_time(&self) -> Duration {
+        self.user_time + self.system_time
+    }
+}
+
+/// The resources used by all the jobs of one `JobType`. Used to find out
+/// where the time goes: linking, running tests, copying files and so on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceTotals {
+    /// The number of jobs that have completed.
+    pub jobs: usize,
+
+    /// The total elapsed time of the jobs, from start to completion.
+    pub wall_time: Duration,
+
+    pub user_time: Duration,
+    pub system_time: Duration,
+
+    /// The largest peak resident set size of any of the jobs, in kilobytes.
+    pub max_rss_kb: u64,
+
+    pub output_bytes: u64,
+}
+
+impl ResourceTotals {
+    /// Adds a completed job to the totals.
+    pub fn add(&mut self, job: &CompletedJob) {
+        self.jobs += 1;
+        self.wall_time += (job.completed_date() - job.start_date())
+            .to_std()
+            .unwrap_or_default();
+
+        if let Some(usage) = job.resource_usage() {
+            self.user_time += usage.user_time;
+            self.system_time += usage.system_time;
+            self.max_rss_kb = self.max_rss_kb.max(usage.max_rss_kb);
+            self.output_bytes += usage.output_bytes as u64;
+        } else {
+            self.output_bytes += job.kind().output().len() as u64;
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use crate::jobs::{CompletedJob, CompletionStatus, Job};
+use std::{
+    path::{Path, MAIN_SEPARATOR},
+    time::Duration,
+};
+
+/// Fragments of cargo and OS error messages that indicate a failure which
+/// is likely to go away on its own if we just try again.
+const TRANSIENT_ERRORS: &[&str] = &[
+    // Another cargo process (an IDE, or the user in a terminal) is holding a lock.
+    "failed to acquire package cache lock",
+    "Resource temporarily unavailable",
+    "being used by another process",
+    "Text file busy",
+];
+
+/// Fragments of OS error messages for a file or directory that does not exist.
+/// This is usually a genuine failure, such as a build script or a test that
+/// can't find one of its files, see `is_transient_missing_file`.
+const MISSING_FILE_ERRORS: &[&str] = &[
+    "No such file or directory",
+    "cannot find the file specified",
+    "cannot find the path specified",
+];
+
+/// The start of the messages that cargo writes when it cannot use a file.
+const CARGO_FILE_ERRORS: &[&str] = &["error: failed to open", "error: failed to read"];
+
+/// Fragments that prove the failure is a genuine one. A job whose output
+/// contains any of these is never retried, even if a transient error
+/// also appears in the output.
+const PERMANENT_ERRORS: &[&str] = &[
+    // Compilation errors.
+    "error[E",
+    "could not compile",
+    "error: aborting due to",
+    // The tests ran, so any failure is down to the tests themselves.
+    "test result:",
+];
+
+/// Specifies whether a failed job should be tried again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of times the job is executed, including the
+    /// first attempt. A value of 1 (or 0) means the job is never retried.
+    pub max_attempts: u32,
+
+    /// The time to wait before the first retry. The wait is doubled for
+    /// each subsequent retry.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::from_secs(0),
+        }
+    }
+
+    /// Returns the time to wait before retrying `job`, or `None` if it
+    /// should not be retried. Only jobs that failed with an error that
+    /// looks transient are retried, timeouts and cancellations never are.
+    /// See `is_transient_failure` for the `shadow_copy_directory`.
+    pub fn delay_before_retry(
+        &self,
+        job: &CompletedJob,
+        shadow_copy_directory: Option<&Path>,
+    ) -> Option<Duration> {
+        if job.attempt() >= self.max_attempts {
+            return None;
+        }
+
+        let output = job.kind().output();
+        if !is_transient_failure(output, &job.completion_status(), shadow_copy_directory) {
+            return None;
+        }
+
+        let multiplier = 2_u32.saturating_pow(job.attempt() - 1);
+        Some(
+            self.backoff
+                .checked_mul(multiplier)
+                .unwrap_or(Duration::MAX),
+        )
+    }
+}
+
+/// Decides whether a job failure is transient by looking at the job's
+/// output and the message in its completion status. `shadow_copy_directory`
+/// is the directory that the source is being copied to, if it is.
+pub fn is_transient_failure(
+    output: &str,
+    status: &CompletionStatus,
+    shadow_copy_directory: Option<&Path>,
+) -> bool {
+    let msg = match status {
+        CompletionStatus::Error(msg) => msg,
+        _ => return false,
+    };
+
+    if PERMANENT_ERRORS.iter().any(|e| output.contains(e)) {
+        return false;
+    }
+
+    TRANSIENT_ERRORS
+        .iter()
+        .any(|e| output.contains(e) || msg.contains(e))
+        || is_transient_missing_file(output, shadow_copy_directory)
+}
+
+/// A missing file is only transient if it is one that cargo failed to open or
+/// read, and it is in a directory that changes under cargo's feet: the target
+/// directory, where another cargo process may be replacing it, or the shadow
+/// copy, where it may be being synced.
+fn is_transient_missing_file(output: &str, shadow_copy_directory: Option<&Path>) -> bool {
+    if !MISSING_FILE_ERRORS.iter().any(|e| output.contains(e)) {
+        return false;
+    }
+
+    let target_directory = format!("{s}target{s}", s = MAIN_SEPARATOR);
+    let shadow_copy_directory = shadow_copy_directory.map(|dir| dir.to_string_lossy());
+
+    output.lines().map(str::trim_start).any(|line| {
+        CARGO_FILE_ERRORS.iter().any(|e| line.starts_with(e))
+            && (line.contains(&target_directory)
+                || shadow_copy_directory
+                    .as_ref()
+                    .is_some_and(|dir| line.contains(dir.as_ref())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::BuildMode,
+        jobs::{
+            BuildAllTestsJob, ExecutionContext, PendingJob, ScriptedProcess, ScriptedProcessRunner,
+        },
+        shadow_copy_destination::ShadowCopyDestination,
+    };
+    use std::{path::PathBuf, sync::Arc};
+
+    const LOCK_ERROR: &str = "error: failed to acquire package cache lock\n";
+
+    fn error() -> CompletionStatus {
+        CompletionStatus::Error("Job #1 Build failed. Exit=Code(101)".into())
+    }
+
+    fn execute(job: PendingJob, output: &str) -> CompletedJob {
+        let runner = Arc::new(ScriptedProcessRunner::new());
+        runner.push(ScriptedProcess::fails(output));
+        let context = ExecutionContext::new(None).with_process_runner(runner);
+        job.execute(&context)
+    }
+
+    #[test]
+    fn transient_errors_are_found_in_the_output_or_the_status() {
+        assert!(is_transient_failure(LOCK_ERROR, &error(), None));
+        let status = CompletionStatus::Error("Text file busy (os error 26)".into());
+        assert!(is_transient_failure("", &status, None));
+        assert!(!is_transient_failure(
+            "error: linking failed\n",
+            &error(),
+            None
+        ));
+    }
+
+    #[test]
+    fn only_errors_are_transient() {
+        assert!(!is_transient_failure(
+            LOCK_ERROR,
+            &CompletionStatus::Ok,
+            None
+        ));
+        assert!(!is_transient_failure(
+            LOCK_ERROR,
+            &CompletionStatus::TimedOut,
+            None
+        ));
+        assert!(!is_transient_failure(
+            LOCK_ERROR,
+            &CompletionStatus::Cancelled,
+            None
+        ));
+    }
+
+    #[test]
+    fn permanent_errors_outweigh_transient_ones() {
+        let output = format!("{}error[E0425]: cannot find value `x`\n", LOCK_ERROR);
+        assert!(!is_transient_failure(&output, &error(), None));
+        let output = format!("{}test result: FAILED. 1 passed; 1 failed\n", LOCK_ERROR);
+        assert!(!is_transient_failure(&output, &error(), None));
+    }
+
+    #[test]
+    fn missing_files_are_transient_only_if_cargo_is_opening_a_changing_one() {
+        let target = format!(
+            "error: failed to open: {s}w{s}target{s}debug{s}.fingerprint: \
+             No such file or directory (os error 2)\n",
+            s = MAIN_SEPARATOR
+        );
+        assert!(is_transient_failure(&target, &error(), None));
+
+        let shadow_copy_directory = PathBuf::from("/tmp/shadow");
+        let shadow_copy = "error: failed to read `/tmp/shadow/Cargo.toml`\n\n\
+                           Caused by:\n  No such file or directory (os error 2)\n";
+        assert!(is_transient_failure(
+            shadow_copy,
+            &error(),
+            Some(&shadow_copy_directory)
+        ));
+        assert!(!is_transient_failure(shadow_copy, &error(), None));
+
+        let test_data = "thread 'tests::a' panicked at 'data.json: \
+                         No such file or directory (os error 2)'\n";
+        assert!(!is_transient_failure(
+            test_data,
+            &error(),
+            Some(&shadow_copy_directory)
+        ));
+    }
+
+    #[test]
+    fn backoff_doubles_until_the_attempts_run_out() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        };
+        let destination = ShadowCopyDestination::without_copying(PathBuf::from("."));
+        let job = execute(
+            BuildAllTestsJob::new(destination, BuildMode::Debug),
+            LOCK_ERROR,
+        );
+        assert_eq!(
+            policy.delay_before_retry(&job, None),
+            Some(Duration::from_millis(100))
+        );
+
+        let job = execute(
+            PendingJob::retry_of(&job, Duration::from_secs(0)),
+            LOCK_ERROR,
+        );
+        assert_eq!(
+            policy.delay_before_retry(&job, None),
+            Some(Duration::from_millis(200))
+        );
+
+        let job = execute(
+            PendingJob::retry_of(&job, Duration::from_secs(0)),
+            LOCK_ERROR,
+        );
+        assert_eq!(job.attempt(), 3);
+        assert_eq!(policy.delay_before_retry(&job, None), None);
+    }
+
+    #[test]
+    fn a_failure_that_is_not_transient_is_never_retried() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        };
+        let destination = ShadowCopyDestination::without_copying(PathBuf::from("."));
+        let job = execute(
+            BuildAllTestsJob::new(destination, BuildMode::Debug),
+            "error: could not compile `a`\n",
+        );
+        assert_eq!(policy.delay_before_retry(&job, None), None);
+        assert_eq!(RetryPolicy::never().delay_before_retry(&job, None), None);
+    }
+}
@@ -1,60 +1,793 @@
 use crate::{
     configuration::BuildMode,
-    jobs::{gather_process_stdout, CompletionStatus, JobId, JobKind, PendingJob},
+    jobs::{
+        process::run_process, CompletionStatus, ExecutionContext, JobId, JobKind, PendingJob,
+        ProcessCommand, ProcessExit, ProcessRun,
+    },
     shadow_copy_destination::ShadowCopyDestination,
+    test_selection::{
+        cargo_test_args, libtest_args, IgnoredTests, TestBinaries, TestFilter, TestSelection,
+    },
+    test_settings::TestExecutionSettings,
+};
+use cargo_test_parser::{
+    parse_long_running_test, parse_test_outcome, parse_test_run, parse_test_run_line, CrateName,
+    TestOutcome, TestResult, TestRunLine, TestRunResults,
+};
+use log::{info, warn};
+use std::{
+    fmt::{Debug, Display},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
 };
-use duct::cmd;
-use log::info;
-use std::fmt::Display;
 
 #[derive(Debug, Clone)]
 pub struct RunTestsJob {
     destination: ShadowCopyDestination,
     build_mode: BuildMode,
+    selection: TestSelection,
+    /// Set if this is the first half of a `RunStrategy::FailedFirst` run, which
+    /// runs the tests that failed last time. These are the tests to run next.
+    remaining_tests: Option<TestSelection>,
+    /// Set if the test binaries are run directly rather than by `cargo test`.
+    direct_run: Option<DirectRun>,
+    /// The number of times the tests are run to find out whether they are
+    /// flaky, see `flaky_reruns`. Zero for an ordinary run.
+    reruns: usize,
     output: String,
+    /// The names of the tests that libtest reported as having been running
+    /// for a long time. These are likely to be hung.
+    long_running_tests: Vec<String>,
+    /// The tests that were run in processes of their own.
+    isolated_runs: Vec<IsolatedTestRun>,
+}
+
+/// How to run the test binaries directly, see `TestRunner::Direct`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectRun {
+    pub binaries: TestBinaries,
+    /// The number of processes to run at the same time.
+    pub threads: usize,
+    /// If true each test is run in a process of its own,
+    /// see `TestRunner::Isolated`.
+    pub isolate: bool,
+}
+
+/// The result of a test that was run in a process of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsolatedTestRun {
+    /// The path of the test binary, see `CrateName::full_name`.
+    pub binary: String,
+    pub test: String,
+    /// The result reported by libtest, or `None` if the process
+    /// died before the test completed.
+    pub result: Option<TestResult>,
+    /// How the process exited, if known.
+    pub exit: Option<ProcessExit>,
+    /// Everything the process wrote, which is the output of just this test.
+    pub output: String,
+}
+
+/// Receives the outcomes of the tests as a `RunTestsJob` runs them, rather
+/// than when the job completes, see `ExecutionContext::with_test_progress`.
+/// The tests in a test binary, or the doc tests of a crate, are identified
+/// by the `crate_name` and `is_doc_tests` from libtest's output.
+pub trait TestProgress: Debug + Send + Sync {
+    /// The tests have started to run. The `selected` tests are exact names,
+    /// or empty if every test was selected, and the `skipped` tests are not run.
+    /// With `IgnoredTests::Only` just the `#[ignore]` tests among them are run.
+    fn tests_started(
+        &self,
+        build_mode: BuildMode,
+        crate_name: &CrateName<'_>,
+        is_doc_tests: bool,
+        selected: &[String],
+        skipped: &[String],
+        ignored: IgnoredTests,
+    );
+
+    fn test_completed(
+        &self,
+        build_mode: BuildMode,
+        crate_name: &CrateName<'_>,
+        is_doc_tests: bool,
+        outcome: &TestOutcome<'_>,
+    );
+
+    /// No more outcomes will be reported for the tests, either because
+    /// they have all completed or because the process stopped early.
+    fn tests_finished(&self, build_mode: BuildMode, crate_name: &CrateName<'_>, is_doc_tests: bool);
+}
+
+/// Follows the output of a process that runs tests, passing each
+/// outcome on to the `TestProgress` as soon as it is written.
+struct ProgressTracker<'a> {
+    progress: Option<&'a dyn TestProgress>,
+    build_mode: BuildMode,
+    selected: &'a [String],
+    skipped: &'a [String],
+    ignored: IgnoredTests,
+    /// The line that started the current section of the output,
+    /// which is parsed again to get the crate name.
+    section: Option<String>,
+}
+
+/// A process that runs the tests in a test binary.
+#[derive(Debug)]
+struct BinaryCommand {
+    /// The line cargo would print before running the binary, which is added
+    /// to the output so that it can be parsed like the output of `cargo test`.
+    header: String,
+    binary: String,
+    command: ProcessCommand,
+    /// The exact names of the tests that the process runs, or empty if it
+    /// runs all of them.
+    tests: Vec<String>,
+    /// The tests that the process skips.
+    skipped: Vec<String>,
+    /// The test, if the process runs just one test in isolation.
+    isolated_test: Option<String>,
 }
 
 impl Display for RunTestsJob {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Run tests in {:?} mode", self.build_mode)
+        if self.reruns > 0 {
+            write!(
+                f,
+                "Rerun {} {} times in {:?} mode",
+                self.selection, self.reruns, self.build_mode
+            )
+        } else if self.selection.is_all() {
+            write!(f, "Run tests in {:?} mode", self.build_mode)
+        } else {
+            write!(f, "Run {} in {:?} mode", self.selection, self.build_mode)
+        }
     }
 }
 
 impl RunTestsJob {
     pub fn new(destination: ShadowCopyDestination, build_mode: BuildMode) -> PendingJob {
+        Self::with_selection(destination, build_mode, TestSelection::all())
+    }
+
+    /// Creates a job that runs just the `selection` of tests.
+    pub fn with_selection(
+        destination: ShadowCopyDestination,
+        build_mode: BuildMode,
+        selection: TestSelection,
+    ) -> PendingJob {
+        Self::with_direct_run(destination, build_mode, selection, None)
+    }
+
+    /// Creates a job that runs the `selection` of tests by running the test
+    /// binaries directly, or using `cargo test` if `direct_run` is `None`.
+    pub fn with_direct_run(
+        destination: ShadowCopyDestination,
+        build_mode: BuildMode,
+        selection: TestSelection,
+        direct_run: Option<DirectRun>,
+    ) -> PendingJob {
+        let kind = JobKind::RunTests(RunTestsJob {
+            destination,
+            build_mode,
+            selection,
+            remaining_tests: None,
+            direct_run,
+            reruns: 0,
+            output: Default::default(),
+            long_running_tests: Default::default(),
+            isolated_runs: Default::default(),
+        });
+
+        kind.into()
+    }
+
+    pub fn build_mode(&self) -> BuildMode {
+        self.build_mode
+    }
+
+    /// Creates a job that runs just the tests that `failed` last time, which
+    /// are selected by package where it is known. If they all pass then `remaining_tests_job` creates a job for the rest of
+    /// the `selection`. Both jobs use the same `direct_run`.
+    pub fn failed_first(
+        destination: ShadowCopyDestination,
+        build_mode: BuildMode,
+        failed: Vec<TestFilter>,
+        selection: TestSelection,
+        direct_run: Option<DirectRun>,
+    ) -> PendingJob {
+        // The failed tests are only skipped in their own packages.
+        let remaining_tests = failed
+            .iter()
+            .flat_map(|filter| filter.tests.iter().map(move |test| (&filter.package, test)))
+            .fold(selection, |selection, (package, test)| match package {
+                Some(package) => selection.with_skipped_in(package.clone(), test.clone()),
+                None => selection.with_skipped(test.clone()),
+            });
+
+        let ignored = remaining_tests.ignored();
+        let selection = failed
+            .into_iter()
+            .fold(TestSelection::all(), TestSelection::with_filter)
+            .with_ignored(ignored);
+
         let kind = JobKind::RunTests(RunTestsJob {
             destination,
             build_mode,
+            selection,
+            remaining_tests: Some(remaining_tests),
+            direct_run,
+            reruns: 0,
             output: Default::default(),
+            long_running_tests: Default::default(),
+            isolated_runs: Default::default(),
         });
 
         kind.into()
     }
 
+    /// Returns true if this job runs the previously failing tests of a
+    /// `RunStrategy::FailedFirst` run.
+    pub fn is_failed_first(&self) -> bool {
+        self.remaining_tests.is_some()
+    }
+
+    /// For a `failed_first` job, returns a job that runs the rest of the
+    /// tests. Returns `None` for any other job.
+    pub fn remaining_tests_job(&self) -> Option<PendingJob> {
+        let selection = self.remaining_tests.clone()?;
+        Some(Self::with_direct_run(
+            self.destination.clone(),
+            self.build_mode,
+            selection,
+            self.direct_run.clone(),
+        ))
+    }
+
+    /// Creates a job that runs the tests that just `failed` another `reruns`
+    /// times, to find out whether they are flaky rather than broken. The
+    /// outcomes of all the runs are in the output, one after another.
+    pub fn flaky_reruns(
+        destination: ShadowCopyDestination,
+        build_mode: BuildMode,
+        failed: Vec<String>,
+        selection: &TestSelection,
+        reruns: usize,
+        direct_run: Option<DirectRun>,
+    ) -> PendingJob {
+        let filter = TestFilter {
+            tests: failed,
+            ..TestFilter::workspace()
+        };
+
+        let kind = JobKind::RunTests(RunTestsJob {
+            destination,
+            build_mode,
+            selection: TestSelection::all()
+                .with_filter(filter)
+                .with_ignored(selection.ignored()),
+            remaining_tests: None,
+            direct_run,
+            reruns,
+            output: Default::default(),
+            long_running_tests: Default::default(),
+            isolated_runs: Default::default(),
+        });
+
+        kind.into()
+    }
+
+    /// Returns the number of times a `flaky_reruns` job runs the tests,
+    /// or zero for any other job.
+    pub fn reruns(&self) -> usize {
+        self.reruns
+    }
+
+    pub fn selection(&self) -> &TestSelection {
+        &self.selection
+    }
+
+    pub fn direct_run(&self) -> Option<&DirectRun> {
+        self.direct_run.as_ref()
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Parses the output and returns the outcome of each test that was run.
+    /// Only the selected tests are run, so only they have outcomes.
+    pub fn parse_results(&self) -> Vec<TestRunResults<'_>> {
+        parse_test_run(&self.output)
+    }
+
+    /// Returns the names of the tests that failed or crashed in this run,
+    /// sorted and without duplicates.
+    pub fn failed_tests(&self) -> Vec<String> {
+        let results = self.parse_results();
+        let mut failed = results
+            .iter()
+            .filter(|r| !r.is_doc_tests)
+            .flat_map(|r| r.outcomes.iter())
+            .filter(|outcome| outcome.result == TestResult::Failed)
+            .map(|outcome| outcome.name.to_string())
+            .chain(
+                self.isolated_runs
+                    .iter()
+                    .filter(|run| run.result.is_none())
+                    .map(|run| run.test.clone()),
+            )
+            .collect::<Vec<_>>();
+
+        failed.sort();
+        failed.dedup();
+        failed
+    }
+
+    /// Returns the names of the tests that libtest warned were
+    /// taking a long time to run.
+    pub fn long_running_tests(&self) -> &[String] {
+        &self.long_running_tests
+    }
+
+    /// Returns the results of the tests that were run in processes of their
+    /// own. Their outcomes are also in the `output`, except for crashes.
+    pub fn isolated_runs(&self) -> &[IsolatedTestRun] {
+        &self.isolated_runs
+    }
+
     #[must_use = "Don't ignore the completion status, caller needs to store it"]
-    pub fn execute(&mut self, parent_job_id: JobId) -> CompletionStatus {
+    pub fn execute(
+        &mut self,
+        parent_job_id: JobId,
+        context: &ExecutionContext,
+    ) -> CompletionStatus {
         let cwd = self.destination.cwd();
-        info!("{} Listing Running in {}", parent_job_id, cwd.display());
-
-        // cargo test --no-fail-fast -- --show-output --test-threads=1 --color never
-        let mut args = Vec::new();
-        args.push("test");
-        args.push("--no-fail-fast");
-        args.push("--");
-        args.push("--show-output");
-        args.push("--test-threads=1");
-        args.push("--color");
-        args.push("never");
-
-        let cmd = cmd("cargo", args).stderr_to_stdout().dir(cwd);
-
-        self.output = match gather_process_stdout(cmd, "Run all tests", parent_job_id) {
-            Ok(output) => output,
-            Err(err) => return err.to_string().into(),
+        info!(
+            "{} Running {} in {}",
+            parent_job_id,
+            self.selection,
+            cwd.display()
+        );
+
+        self.output.clear();
+        self.long_running_tests.clear();
+        self.isolated_runs.clear();
+
+        // The reruns of flaky tests are applied all at once when the job
+        // completes, see `State::update_flaky_results`.
+        let quiet_context;
+        let context = if self.reruns > 0 {
+            quiet_context = context.clone().without_test_progress();
+            &quiet_context
+        } else {
+            context
+        };
+
+        let mut status = CompletionStatus::Ok;
+        for _ in 0..self.reruns.max(1) {
+            let run_status = self.run_once(&parent_job_id, context);
+            if let Some(stopped) = combine_status(&mut status, run_status) {
+                return stopped;
+            }
+        }
+
+        status
+    }
+
+    /// Runs the selected tests once, appending to the output.
+    fn run_once(&mut self, parent_job_id: &JobId, context: &ExecutionContext) -> CompletionStatus {
+        // Each invocation is run even if an earlier one has failing tests, so
+        // that every selected test gets a result. The first failure is reported.
+        let mut status = CompletionStatus::Ok;
+        let cwd = self.destination.cwd();
+        let settings = context.test_settings();
+        let invocations = match self.direct_run.take() {
+            Some(direct_run) => {
+                let (commands, doc_invocations) = self.binary_commands(&direct_run, settings);
+                let doc_invocations = split_by_package(
+                    doc_invocations,
+                    settings,
+                    &self.selection,
+                    Some(&direct_run.binaries.doc_test_packages),
+                );
+                let runs = run_in_parallel(
+                    commands,
+                    direct_run.threads,
+                    self.build_mode,
+                    self.selection.ignored(),
+                    parent_job_id,
+                    context,
+                );
+                self.direct_run = Some(direct_run);
+
+                for (cmd, run, long_running_tests) in runs {
+                    self.output.push_str(&cmd.header);
+                    self.output.push('\n');
+                    self.output.push_str(&run.output);
+                    self.long_running_tests.extend(long_running_tests);
+                    let binary = cmd.binary;
+                    let isolated_run = cmd
+                        .isolated_test
+                        .and_then(|test| isolated_test_run(binary, test, &run));
+                    if let Some(isolated_run) = isolated_run {
+                        if isolated_run.result.is_none() {
+                            warn!(
+                                "{} Test {} crashed, exit={:?}",
+                                parent_job_id, isolated_run.test, isolated_run.exit
+                            );
+                        }
+                        self.isolated_runs.push(isolated_run);
+                    }
+
+                    if let Some(stopped) = combine_status(&mut status, run.status) {
+                        return stopped;
+                    }
+                }
+
+                doc_invocations
+            }
+            None => split_by_package(
+                self.selection.invocations(),
+                settings,
+                &self.selection,
+                None,
+            ),
         };
 
-        CompletionStatus::Ok
+        for (filter, exclude) in invocations {
+            let package_settings = settings.for_package(filter.package.as_deref());
+            let skipped = self.selection.skipped(filter.package.as_deref());
+            let args = cargo_test_args(
+                &filter,
+                &exclude,
+                &skipped,
+                self.selection.ignored(),
+                self.build_mode,
+                package_settings,
+            );
+            let cmd = package_settings.apply_env(ProcessCommand::cargo("Run tests", args, cwd));
+
+            let long_running_tests = &mut self.long_running_tests;
+            let mut tracker = ProgressTracker::new(
+                context.test_progress(),
+                self.build_mode,
+                &filter.tests,
+                &skipped,
+                self.selection.ignored(),
+            );
+            let run = run_process(cmd, parent_job_id, context, &mut |line| {
+                tracker.line(line);
+                if let Some(name) = long_running_test(line, parent_job_id) {
+                    long_running_tests.push(name);
+                }
+            });
+            tracker.finish();
+
+            self.output.push_str(&run.output);
+            if let Some(stopped) = combine_status(&mut status, run.status) {
+                return stopped;
+            }
+        }
+
+        status
+    }
+
+    /// Works out the commands that run the selected tests' binaries, either
+    /// one per binary or, when isolating tests, one per test. Also returns
+    /// the `cargo test` invocations for the doc tests.
+    fn binary_commands(
+        &self,
+        direct_run: &DirectRun,
+        settings: &TestExecutionSettings,
+    ) -> (Vec<BinaryCommand>, Vec<TestFilter>) {
+        let cwd = self.destination.cwd();
+        let ignored = self.selection.ignored();
+        let (runs, doc_invocations) = self.selection.binary_runs(&direct_run.binaries);
+
+        let mut commands = Vec::new();
+        for run in runs {
+            let binary = run.binary;
+            let program = cwd.join(&binary.path);
+            let package_directory = cwd.join(&binary.package_directory);
+            let package_settings = settings.for_package(Some(&binary.package));
+            let skipped = self.selection.skipped(Some(&binary.package));
+            let command = |args: Vec<String>| {
+                let command =
+                    ProcessCommand::program("Run test binary", &program, args, &package_directory)
+                        .with_env("CARGO_MANIFEST_DIR", package_directory.to_string_lossy())
+                        .with_env("CARGO_PKG_NAME", binary.package.as_str());
+                package_settings.apply_env(command)
+            };
+            let binary_command = |command, tests, skipped, isolated_test| BinaryCommand {
+                header: format!("     Running {}", binary.path.display()),
+                binary: binary.path.to_string_lossy().into_owned(),
+                command,
+                tests,
+                skipped,
+                isolated_test,
+            };
+
+            if !direct_run.isolate {
+                let args = libtest_args(package_settings, &run.tests, &skipped, ignored);
+                commands.push(binary_command(command(args), run.tests, skipped, None));
+                continue;
+            }
+
+            // Only the tests that are in the binary get a process.
+            let tests = binary
+                .tests
+                .iter()
+                .filter(|test| run.tests.is_empty() || run.tests.contains(test))
+                .filter(|test| !skipped.contains(test));
+            for test in tests {
+                let args = libtest_args(package_settings, std::slice::from_ref(test), &[], ignored);
+                commands.push(binary_command(
+                    command(args),
+                    vec![test.clone()],
+                    Vec::new(),
+                    Some(test.clone()),
+                ));
+            }
+        }
+
+        (commands, doc_invocations)
+    }
+}
+
+/// A completed run of a test binary, with the names of
+/// any tests that libtest warned about.
+type BinaryRunOutput = (BinaryCommand, ProcessRun, Vec<String>);
+
+/// Runs the `commands` on up to `threads` threads at a time. The results are
+/// returned in the same order as the `commands`, and the output of each run
+/// is kept separate, though the live output is interleaved line by line.
+fn run_in_parallel(
+    commands: Vec<BinaryCommand>,
+    threads: usize,
+    build_mode: BuildMode,
+    ignored: IgnoredTests,
+    parent_job_id: &JobId,
+    context: &ExecutionContext,
+) -> Vec<BinaryRunOutput> {
+    let num_commands = commands.len();
+    let queue = Arc::new(Mutex::new(commands.into_iter().enumerate()));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(num_commands)));
+    // Set once a run is cancelled or times out, after which no more binaries
+    // are started by any of the workers.
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let workers = (0..threads.max(1).min(num_commands))
+        .map(|_| {
+            let queue = queue.clone();
+            let results = results.clone();
+            let stopped = stopped.clone();
+            let parent_job_id = parent_job_id.clone();
+            let context = context.clone();
+
+            thread::Builder::new()
+                .name("TEST_BINARY".into())
+                .spawn(move || loop {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let next = queue.lock().unwrap().next();
+                    let (idx, cmd) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+
+                    context.live_output().push_line(&cmd.header);
+                    let mut tracker = ProgressTracker::new(
+                        context.test_progress(),
+                        build_mode,
+                        &cmd.tests,
+                        &cmd.skipped,
+                        ignored,
+                    );
+                    tracker.line(&cmd.header);
+                    let mut long_running_tests = Vec::new();
+                    let process = cmd.command.clone();
+                    let run = run_process(process, &parent_job_id, &context, &mut |line| {
+                        tracker.line(line);
+                        if let Some(name) = long_running_test(line, &parent_job_id) {
+                            long_running_tests.push(name);
+                        }
+                    });
+                    tracker.finish();
+
+                    // There is no point starting any more binaries.
+                    if run.status == CompletionStatus::Cancelled
+                        || run.status == CompletionStatus::TimedOut
+                    {
+                        stopped.store(true, Ordering::SeqCst);
+                    }
+                    results
+                        .lock()
+                        .unwrap()
+                        .push((idx, (cmd, run, long_running_tests)));
+                })
+                .expect("Cannot create TEST_BINARY thread")
+        })
+        .collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.join().expect("TEST_BINARY thread panicked");
     }
+
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    results.sort_by_key(|(idx, _)| *idx);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+impl<'a> ProgressTracker<'a> {
+    fn new(
+        progress: Option<&'a dyn TestProgress>,
+        build_mode: BuildMode,
+        selected: &'a [String],
+        skipped: &'a [String],
+        ignored: IgnoredTests,
+    ) -> Self {
+        Self {
+            progress,
+            build_mode,
+            selected,
+            skipped,
+            ignored,
+            section: None,
+        }
+    }
+
+    /// Passes on the meaning of a line of output, if it has one.
+    fn line(&mut self, line: &str) {
+        let progress = match self.progress {
+            Some(progress) => progress,
+            None => return,
+        };
+
+        match parse_test_run_line(line) {
+            Some(TestRunLine::Section {
+                crate_name,
+                is_doc_tests,
+            }) => {
+                self.finish();
+                progress.tests_started(
+                    self.build_mode,
+                    &crate_name,
+                    is_doc_tests,
+                    self.selected,
+                    self.skipped,
+                    self.ignored,
+                );
+                self.section = Some(line.to_string());
+            }
+            Some(TestRunLine::Outcome(outcome)) => {
+                if let Some((crate_name, is_doc_tests)) = self.current_section() {
+                    progress.test_completed(self.build_mode, &crate_name, is_doc_tests, &outcome);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Ends the current section, when the next one starts or the process exits.
+    fn finish(&mut self) {
+        if let (Some(progress), Some((crate_name, is_doc_tests))) =
+            (self.progress, self.current_section())
+        {
+            progress.tests_finished(self.build_mode, &crate_name, is_doc_tests);
+        }
+        self.section = None;
+    }
+
+    fn current_section(&self) -> Option<(CrateName<'_>, bool)> {
+        match parse_test_run_line(self.section.as_ref()?)? {
+            TestRunLine::Section {
+                crate_name,
+                is_doc_tests,
+            } => Some((crate_name, is_doc_tests)),
+            _ => None,
+        }
+    }
+}
+
+/// Splits each invocation for the whole workspace into one for each package
+/// that has settings or skipped tests of its own and one for the rest of the
+/// workspace, which excludes those packages. If `packages_with_tests` is known
+/// then only those packages get an invocation. Returns the invocations and
+/// their exclusions.
+fn split_by_package(
+    invocations: Vec<TestFilter>,
+    settings: &TestExecutionSettings,
+    selection: &TestSelection,
+    packages_with_tests: Option<&[String]>,
+) -> Vec<(TestFilter, Vec<String>)> {
+    let mut excluded = settings.packages.keys().cloned().collect::<Vec<_>>();
+    for package in selection.packages_with_skipped_tests() {
+        if !excluded.contains(&package) {
+            excluded.push(package);
+        }
+    }
+    let packages = excluded
+        .iter()
+        .filter(|p| packages_with_tests.is_none_or(|packages| packages.contains(p)))
+        .collect::<Vec<_>>();
+
+    let mut split = Vec::new();
+    for filter in invocations {
+        if filter.package.is_some() || excluded.is_empty() {
+            split.push((filter, Vec::new()));
+            continue;
+        }
+
+        for &package in &packages {
+            let package_filter = TestFilter {
+                package: Some(package.clone()),
+                ..filter.clone()
+            };
+            split.push((package_filter, Vec::new()));
+        }
+        split.push((filter, excluded.clone()));
+    }
+
+    split
+}
+
+/// Works out the result of a test that was run in a process of its own. If
+/// libtest didn't report an outcome for it then the process must have died,
+/// unless it exited successfully, in which case the test was filtered out
+/// (for example by `--ignored`) and `None` is returned.
+fn isolated_test_run(binary: String, test: String, run: &ProcessRun) -> Option<IsolatedTestRun> {
+    let result = run
+        .output
+        .lines()
+        .filter_map(parse_test_outcome)
+        .find(|outcome| outcome.name == test)
+        .map(|outcome| outcome.result);
+
+    if result.is_none() && run.usage.exit == Some(ProcessExit::Code(0)) {
+        return None;
+    }
+
+    Some(IsolatedTestRun {
+        binary,
+        test,
+        result,
+        exit: run.usage.exit,
+        output: run.output.clone(),
+    })
+}
+
+/// Updates the overall `status` of the job with the status of one of its
+/// processes. Returns the process's status if it was cancelled or timed
+/// out, in which case the job should stop with that status.
+fn combine_status(
+    status: &mut CompletionStatus,
+    process_status: CompletionStatus,
+) -> Option<CompletionStatus> {
+    match process_status {
+        CompletionStatus::Ok => {}
+        CompletionStatus::Cancelled | CompletionStatus::TimedOut => return Some(process_status),
+        failed if *status == CompletionStatus::Ok => *status = failed,
+        _ => {}
+    }
+
+    None
+}
+
+/// Returns the name of the test if `line` is libtest's warning
+/// that a test has been running for a long time.
+fn long_running_test(line: &str, parent_job_id: &JobId) -> Option<String> {
+    let test = parse_long_running_test(line)?;
+    warn!(
+        "{} Test {} has been running for over {} seconds, it may be hung",
+        parent_job_id, test.name, test.seconds
+    );
+    Some(test.name.to_string())
 }
 
 /*
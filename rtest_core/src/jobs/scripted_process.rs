@@ -0,0 +1,194 @@
+use crate::jobs::{
+    CompletionStatus, ExecutionContext, JobId, ProcessCommand, ProcessExit, ProcessRun,
+    ProcessRunner, ResourceUsage,
+};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a scripted process checks whether it has been cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The canned result of a process run by a `ScriptedProcessRunner`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptedProcess {
+    pub output: String,
+    pub exit_code: i32,
+    /// If set, the process is killed by this signal rather than exiting.
+    pub signal: Option<i32>,
+    /// How long the process "runs" for before it produces its output.
+    pub delay: Duration,
+    /// If set, the process is stopped with this status once the delay is up,
+    /// as if the job had been cancelled or had timed out at that moment.
+    pub stopped: Option<CompletionStatus>,
+}
+
+impl ScriptedProcess {
+    /// A process that exits with code 0.
+    pub fn succeeds<S: Into<String>>(output: S) -> Self {
+        Self {
+            output: output.into(),
+            exit_code: 0,
+            signal: None,
+            delay: Duration::from_secs(0),
+            stopped: None,
+        }
+    }
+
+    /// A process that exits with code 101, which is what cargo uses.
+    pub fn fails<S: Into<String>>(output: S) -> Self {
+        Self {
+            exit_code: 101,
+            ..Self::succeeds(output)
+        }
+    }
+
+    /// A process that is killed by `signal`, for example SIGSEGV.
+    pub fn killed_by<S: Into<String>>(output: S, signal: i32) -> Self {
+        Self {
+            signal: Some(signal),
+            ..Self::succeeds(output)
+        }
+    }
+
+    /// A process that is stopped with `status`, which is either
+    /// `CompletionStatus::Cancelled` or `CompletionStatus::TimedOut`.
+    pub fn stopped(status: CompletionStatus) -> Self {
+        Self {
+            stopped: Some(status),
+            ..Self::succeeds("")
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A fake `ProcessRunner` that doesn't start any processes. Instead each command
+/// is answered with the next `ScriptedProcess` in its queue (or a process that
+/// succeeds with no output, once the queue is empty). Every command that is
+/// started is recorded so that tests can check what was run.
+#[derive(Debug, Default)]
+pub struct ScriptedProcessRunner {
+    responses: Mutex<VecDeque<ScriptedProcess>>,
+    commands: Mutex<Vec<ProcessCommand>>,
+}
+
+impl ScriptedProcessRunner {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a response to the end of the queue.
+    pub fn push(&self, process: ScriptedProcess) {
+        self.responses.lock().unwrap().push_back(process);
+    }
+
+    /// Returns the commands that have been run, oldest first.
+    pub fn commands(&self) -> Vec<ProcessCommand> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl ProcessRunner for ScriptedProcessRunner {
+    fn run(
+        &self,
+        command: &ProcessCommand,
+        parent_job_id: &JobId,
+        context: &ExecutionContext,
+        on_line: &mut dyn FnMut(&str),
+    ) -> ProcessRun {
+        // Honour the context in the same way as a real process would, which
+        // isn't started at all if the job has already run out of time.
+        if context.is_timed_out() {
+            return ProcessRun {
+                output: String::new(),
+                status: CompletionStatus::TimedOut,
+                usage: Default::default(),
+            };
+        }
+
+        self.commands.lock().unwrap().push(command.clone());
+        let process = self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| ScriptedProcess::succeeds(""));
+
+        let start = Instant::now();
+        while start.elapsed() < process.delay {
+            let stopped_status = if context.is_cancelled() {
+                Some(CompletionStatus::Cancelled)
+            } else if context.is_timed_out() {
+                Some(CompletionStatus::TimedOut)
+            } else {
+                None
+            };
+
+            if let Some(status) = stopped_status {
+                return ProcessRun {
+                    output: String::new(),
+                    status,
+                    usage: ResourceUsage {
+                        processes: 1,
+                        ..Default::default()
+                    },
+                };
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        if let Some(status) = process.stopped {
+            return ProcessRun {
+                output: String::new(),
+                status,
+                usage: ResourceUsage {
+                    processes: 1,
+                    ..Default::default()
+                },
+            };
+        }
+
+        for line in process.output.lines() {
+            on_line(line);
+        }
+
+        let exit = match process.signal {
+            Some(signal) => ProcessExit::Signal(signal),
+            None => ProcessExit::Code(process.exit_code),
+        };
+
+        let status = if exit == ProcessExit::Code(0) {
+            CompletionStatus::Ok
+        } else {
+            format!(
+                "{} {} failed. Exit={:?}, stdout={} bytes",
+                parent_job_id,
+                command.description,
+                exit,
+                process.output.len()
+            )
+            .into()
+        };
+
+        let usage = ResourceUsage {
+            processes: 1,
+            exit: Some(exit),
+            output_bytes: process.output.len(),
+            ..Default::default()
+        };
+
+        ProcessRun {
+            output: process.output,
+            status,
+            usage,
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    jobs::{CompletionStatus, JobKind, PendingJob},
+    jobs::{CompletionStatus, ExecutionContext, JobKind, PendingJob},
     shadow_copy_destination::ShadowCopyDestination,
 };
 use ignore::WalkBuilder;
@@ -43,7 +43,7 @@ impl ShadowCopyJob {
     }
 
     #[must_use = "Don't ignore the completion status, caller needs to store it"]
-    pub fn execute(&mut self) -> CompletionStatus {
+    pub fn execute(&mut self, context: &ExecutionContext) -> CompletionStatus {
         let src = self.destination.source_directory();
         if !std::path::Path::is_dir(src) {
             return format!("Source directory {:?} is not a directory", src).into();
@@ -51,6 +51,14 @@ impl ShadowCopyJob {
 
         let walker = WalkBuilder::new(src).build();
         for result in walker {
+            if context.is_cancelled() {
+                info!(
+                    "Shadow copy cancelled after {} files",
+                    self.num_files_copied
+                );
+                return CompletionStatus::Cancelled;
+            }
+
             match result {
                 Ok(entry) => {
                     if !entry.path().is_dir() {
@@ -0,0 +1,295 @@
+use crate::{
+    configuration::BuildMode,
+    jobs::{
+        process::run_process, CompletionStatus, ExecutionContext, JobId, JobKind, PendingJob,
+        ProcessCommand,
+    },
+    shadow_copy_destination::ShadowCopyDestination,
+    test_selection::{cargo_test_args, IgnoredTests, TestFilter},
+};
+use log::{info, warn};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// Runs the same tests over and over again, to reproduce a failure that only
+/// happens some of the time, such as a race between threads. Every failing
+/// iteration's output is kept.
+#[derive(Debug, Clone)]
+pub struct StressTestJob {
+    destination: ShadowCopyDestination,
+    build_mode: BuildMode,
+    filter: TestFilter,
+    options: StressOptions,
+    output: String,
+    passed: usize,
+    failures: Vec<StressFailure>,
+}
+
+/// How long a `StressTestJob` runs for, and what changes between iterations.
+/// The job stops at whichever limit it reaches first. With no limits at all
+/// it runs until it is cancelled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StressOptions {
+    /// The maximum number of iterations.
+    pub iterations: Option<usize>,
+    /// No more iterations are started once the job has run for this long.
+    pub duration: Option<Duration>,
+    /// Stop at the first iteration that fails.
+    pub stop_on_failure: bool,
+    /// If set, `--test-threads` goes from 1 up to this many threads and
+    /// back to 1 again, changing with each iteration.
+    pub max_threads: Option<usize>,
+    /// Shuffles the order of the tests, using the iteration number as the
+    /// seed so that the order can be reproduced. libtest's `--shuffle-seed`
+    /// is unstable, so this needs a nightly toolchain.
+    pub shuffle: bool,
+}
+
+/// An iteration of a `StressTestJob` that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StressFailure {
+    /// The iteration number, starting from 1.
+    pub iteration: usize,
+    /// The number of threads the tests were run on.
+    pub threads: usize,
+    /// The seed the tests were shuffled with, if they were.
+    pub shuffle_seed: Option<usize>,
+    pub status: CompletionStatus,
+    pub output: String,
+}
+
+impl StressOptions {
+    /// Runs the tests `iterations` times.
+    pub fn iterations(iterations: usize) -> Self {
+        Self {
+            iterations: Some(iterations),
+            ..Default::default()
+        }
+    }
+
+    /// Runs the tests repeatedly for `duration`.
+    pub fn duration(duration: Duration) -> Self {
+        Self {
+            duration: Some(duration),
+            ..Default::default()
+        }
+    }
+
+    /// Runs the tests repeatedly until they fail.
+    pub fn until_failure() -> Self {
+        Self {
+            stop_on_failure: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn stopping_on_failure(mut self) -> Self {
+        self.stop_on_failure = true;
+        self
+    }
+
+    pub fn with_max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads.max(1));
+        self
+    }
+
+    pub fn with_shuffle(mut self) -> Self {
+        self.shuffle = true;
+        self
+    }
+
+    /// Returns the number of threads to use in `iteration`, if it varies.
+    fn threads(&self, iteration: usize) -> Option<usize> {
+        let max_threads = self.max_threads?;
+        if max_threads == 1 {
+            return Some(1);
+        }
+
+        let cycle = 2 * (max_threads - 1);
+        let step = (iteration - 1) % cycle;
+        Some(if step < max_threads {
+            step + 1
+        } else {
+            cycle - step + 1
+        })
+    }
+}
+
+impl Display for StressTestJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Stress test {} in {:?} mode",
+            self.filter, self.build_mode
+        )
+    }
+}
+
+impl StressTestJob {
+    /// Creates a job that runs the tests selected by `filter`, which
+    /// is usually just one test, repeatedly as specified by `options`.
+    pub fn new(
+        destination: ShadowCopyDestination,
+        build_mode: BuildMode,
+        filter: TestFilter,
+        options: StressOptions,
+    ) -> PendingJob {
+        let kind = JobKind::StressTest(StressTestJob {
+            destination,
+            build_mode,
+            filter,
+            options,
+            output: Default::default(),
+            passed: 0,
+            failures: Default::default(),
+        });
+
+        kind.into()
+    }
+
+    pub fn build_mode(&self) -> BuildMode {
+        self.build_mode
+    }
+
+    pub fn filter(&self) -> &TestFilter {
+        &self.filter
+    }
+
+    pub fn options(&self) -> &StressOptions {
+        &self.options
+    }
+
+    /// Returns the output of the failing iterations, followed by a summary.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Returns the number of iterations that passed.
+    pub fn passed(&self) -> usize {
+        self.passed
+    }
+
+    /// Returns the number of iterations that failed.
+    pub fn failed(&self) -> usize {
+        self.failures.len()
+    }
+
+    pub fn failures(&self) -> &[StressFailure] {
+        &self.failures
+    }
+
+    #[must_use = "Don't ignore the completion status, caller needs to store it"]
+    pub fn execute(
+        &mut self,
+        parent_job_id: JobId,
+        context: &ExecutionContext,
+    ) -> CompletionStatus {
+        let cwd = self.destination.cwd();
+        info!(
+            "{} Stress testing {} in {}",
+            parent_job_id,
+            self.filter,
+            cwd.display()
+        );
+
+        self.output.clear();
+        self.passed = 0;
+        self.failures.clear();
+
+        let start = Instant::now();
+        let default_settings = context
+            .test_settings()
+            .for_package(self.filter.package.as_deref());
+
+        let mut iteration = 0;
+        let stopped = loop {
+            if self.options.iterations.is_some_and(|n| iteration >= n)
+                || self.options.duration.is_some_and(|d| start.elapsed() >= d)
+            {
+                break None;
+            }
+            iteration += 1;
+
+            let mut settings = default_settings.clone();
+            if let Some(threads) = self.options.threads(iteration) {
+                settings.threads = threads;
+            }
+            let shuffle_seed = if self.options.shuffle {
+                settings.extra_args.push("-Zunstable-options".into());
+                settings
+                    .extra_args
+                    .push(format!("--shuffle-seed={}", iteration));
+                Some(iteration)
+            } else {
+                None
+            };
+
+            let args = cargo_test_args(
+                &self.filter,
+                &[],
+                &[],
+                IgnoredTests::Normal,
+                self.build_mode,
+                &settings,
+            );
+            let cmd = settings.apply_env(ProcessCommand::cargo("Stress test", args, cwd));
+            let run = run_process(cmd, &parent_job_id, context, &mut |_| {});
+
+            match run.status {
+                CompletionStatus::Ok => self.passed += 1,
+                CompletionStatus::Cancelled => break Some(CompletionStatus::Cancelled),
+                status => {
+                    warn!(
+                        "{} Iteration {} of the stress test failed, status = {:?}",
+                        parent_job_id, iteration, status
+                    );
+                    self.output.push_str(&format!(
+                        "Iteration {} failed (threads = {}):\n",
+                        iteration, settings.threads
+                    ));
+                    self.output.push_str(&run.output);
+                    self.output.push('\n');
+                    self.failures.push(StressFailure {
+                        iteration,
+                        threads: settings.threads,
+                        shuffle_seed,
+                        status: status.clone(),
+                        output: run.output,
+                    });
+
+                    // A test that hangs has reproduced the problem just as
+                    // much as one that fails, but it can't be run again.
+                    if status == CompletionStatus::TimedOut || self.options.stop_on_failure {
+                        break None;
+                    }
+                }
+            }
+        };
+
+        // The summary is also written to the live output, which has the output
+        // of every iteration, so that it ends the job's transcript too.
+        let summary = format!(
+            "{} iterations: {} passed, {} failed",
+            self.passed + self.failures.len(),
+            self.passed,
+            self.failures.len()
+        );
+        context.live_output().push_line(&summary);
+        self.output.push_str(&summary);
+        self.output.push('\n');
+        info!(
+            "{} Stress test completed, {} passed, {} failed",
+            parent_job_id,
+            self.passed,
+            self.failures.len()
+        );
+
+        match (stopped, self.failures.first()) {
+            (Some(status), _) => status,
+            (None, Some(failure)) => failure.status.clone(),
+            (None, None) => CompletionStatus::Ok,
+        }
+    }
+}
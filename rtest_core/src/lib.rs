@@ -1,9 +1,17 @@
+mod bool_flag;
 pub mod configuration;
+pub mod duration_stats;
 pub mod engine;
+pub mod job_history;
 #[path = "jobs/jobs.rs"]
 pub mod jobs;
+pub mod pipeline;
 pub mod shadow_copy_destination;
 pub mod source_directory_watcher;
+mod source_revision;
 pub mod state;
-mod thread_clutch;
+pub mod test_history;
+pub mod test_selection;
+pub mod test_settings;
 mod utils;
+pub mod workspace;
@@ -0,0 +1,371 @@
+use crate::{
+    bool_flag::BoolFlag,
+    configuration::BuildMode,
+    jobs::{BuildAllTestsJob, CargoCommandJob, ListAllTestsJob, PendingJob, RunTestsJob},
+    shadow_copy_destination::ShadowCopyDestination,
+    test_selection::TestSelection,
+};
+use std::{collections::HashMap, str::FromStr};
+
+/// The name of the trigger that means "run when the source files change"
+/// when parsing a stage with `PipelineStage::from_str`.
+const SOURCE_CHANGED: &str = "source-changed";
+
+/// The work done by a pipeline stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageAction {
+    /// Build the tests. See `BuildAllTestsJob`.
+    BuildAllTests,
+    /// List the tests. The engine uses the list to update the `State`.
+    /// See `ListAllTestsJob`.
+    ListAllTests,
+    /// Run the tests. See `RunTestsJob`.
+    RunTests,
+    /// Run a cargo command, for example `clippy --all-targets`. The first
+    /// argument is the cargo subcommand. See `CargoCommandJob`.
+    Cargo(Vec<String>),
+}
+
+/// Specifies when a stage needs to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageTrigger {
+    /// The stage runs whenever the source files change.
+    SourceChanged,
+    /// The stage runs after the named stage has completed. Whether it also runs
+    /// when that stage fails is determined by that stage's `FailurePolicy`.
+    After(String),
+}
+
+/// Specifies what happens to the stages that follow a stage when it fails.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stages triggered by this one don't run. Nothing more happens until
+    /// the source files change again.
+    Stop,
+    /// Stages triggered by this one run anyway.
+    Continue,
+}
+
+/// One step in the pipeline that runs after the source files change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineStage {
+    pub name: String,
+    pub action: StageAction,
+    pub trigger: StageTrigger,
+    pub on_failure: FailurePolicy,
+}
+
+impl PipelineStage {
+    /// Creates a new stage that stops the pipeline if it fails.
+    pub fn new<N: Into<String>>(name: N, action: StageAction, trigger: StageTrigger) -> Self {
+        Self {
+            name: name.into(),
+            action,
+            trigger,
+            on_failure: FailurePolicy::Stop,
+        }
+    }
+
+    pub fn with_failure_policy(mut self, on_failure: FailurePolicy) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// Creates the job that performs this stage. The build, list and run stages
+    /// are restricted to the `packages`, unless it is empty. Cargo command stages
+    /// always apply to the whole workspace, because not every cargo subcommand
+    /// accepts `--package`.
+    pub fn create_job(
+        &self,
+        destination: ShadowCopyDestination,
+        stage: StageRef,
+        packages: &[String],
+    ) -> PendingJob {
+        let build_mode = stage.build_mode;
+        let packages = packages.to_vec();
+
+        let job = match &self.action {
+            StageAction::BuildAllTests => {
+                BuildAllTestsJob::for_packages(destination, build_mode, packages)
+            }
+            StageAction::ListAllTests => {
+                ListAllTestsJob::for_packages(destination, build_mode, packages)
+            }
+            StageAction::RunTests => {
+                let selection = TestSelection::packages(packages);
+                RunTestsJob::with_selection(destination, build_mode, selection)
+            }
+            StageAction::Cargo(args) => {
+                CargoCommandJob::new(destination, build_mode, self.name.clone(), args.clone())
+            }
+        };
+
+        job.with_stage(stage)
+    }
+}
+
+impl FromStr for PipelineStage {
+    type Err = String;
+
+    /// Parses a cargo command stage of the form `name:trigger:args`, for example
+    /// `clippy:build-tests:clippy --all-targets`. The trigger is either the name of
+    /// an earlier stage or "source-changed".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.splitn(3, ':').map(str::trim).collect::<Vec<_>>();
+        if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(format!(
+                "Stage '{}' is not of the form name:trigger:args",
+                s
+            ));
+        }
+
+        let trigger = match parts[1] {
+            SOURCE_CHANGED => StageTrigger::SourceChanged,
+            stage_name => StageTrigger::After(stage_name.into()),
+        };
+        let args = parts[2].split_whitespace().map(String::from).collect();
+
+        Ok(Self::new(parts[0], StageAction::Cargo(args), trigger))
+    }
+}
+
+/// Identifies a stage of the pipeline running in a particular `BuildMode`.
+/// Jobs created by the pipeline carry one of these so that the engine can
+/// tell which stage has completed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StageRef {
+    pub build_mode: BuildMode,
+    /// The index of the stage in `Pipeline::stages`.
+    pub index: usize,
+}
+
+/// An ordered list of stages. A stage can only be triggered by an earlier
+/// stage, so the order of the list is also the order in which the stages run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    stages: Vec<PipelineStage>,
+}
+
+impl Default for Pipeline {
+    /// The standard build tests -> list tests -> run tests pipeline.
+    fn default() -> Self {
+        Self {
+            stages: vec![
+                PipelineStage::new(
+                    "build-tests",
+                    StageAction::BuildAllTests,
+                    StageTrigger::SourceChanged,
+                ),
+                PipelineStage::new(
+                    "list-tests",
+                    StageAction::ListAllTests,
+                    StageTrigger::After("build-tests".into()),
+                ),
+                PipelineStage::new(
+                    "run-tests",
+                    StageAction::RunTests,
+                    StageTrigger::After("list-tests".into()),
+                ),
+            ],
+        }
+    }
+}
+
+impl Pipeline {
+    /// Creates a pipeline from a list of stages. Fails if the stage names
+    /// are not unique or a stage is triggered by a stage that is not before it.
+    pub fn new(stages: Vec<PipelineStage>) -> Result<Self, String> {
+        let mut pipeline = Self { stages: Vec::new() };
+        for stage in stages {
+            pipeline.add_stage(stage)?;
+        }
+
+        Ok(pipeline)
+    }
+
+    /// Adds a stage to the end of the pipeline.
+    pub fn add_stage(&mut self, stage: PipelineStage) -> Result<(), String> {
+        if self.index_of(&stage.name).is_some() {
+            return Err(format!("There is already a stage called '{}'", stage.name));
+        }
+
+        if let StageTrigger::After(name) = &stage.trigger {
+            if self.index_of(name).is_none() {
+                return Err(format!(
+                    "Stage '{}' is triggered by '{}', which is not an earlier stage",
+                    stage.name, name
+                ));
+            }
+        }
+
+        self.stages.push(stage);
+        Ok(())
+    }
+
+    pub fn stages(&self) -> &[PipelineStage] {
+        &self.stages
+    }
+
+    pub fn stage_mut(&mut self, name: &str) -> Option<&mut PipelineStage> {
+        self.stages.iter_mut().find(|s| s.name == name)
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|s| s.name == name)
+    }
+}
+
+/// Tracks which stages of the pipeline need to run. Each `BuildMode` has its
+/// own set of flags, so that the debug and release pipelines can progress (and
+/// fail) independently of each other.
+#[derive(Debug, Clone)]
+pub(crate) struct PipelineProgress {
+    required: HashMap<BuildMode, Vec<BoolFlag>>,
+}
+
+impl PipelineProgress {
+    pub(crate) fn new(pipeline: &Pipeline) -> Self {
+        let flags = || {
+            pipeline
+                .stages()
+                .iter()
+                .map(|_| BoolFlag::default())
+                .collect()
+        };
+
+        let mut required = HashMap::new();
+        required.insert(BuildMode::Debug, flags());
+        required.insert(BuildMode::Release, flags());
+        Self { required }
+    }
+
+    /// Called when the source files change (or fail to). Every stage that is
+    /// triggered by a source change is set to `required`, and every other stage
+    /// is cleared because its input is now out of date.
+    pub(crate) fn source_changed(
+        &self,
+        pipeline: &Pipeline,
+        build_modes: &[BuildMode],
+        required: bool,
+    ) {
+        for build_mode in build_modes {
+            for (stage, flag) in pipeline.stages().iter().zip(&self.required[build_mode]) {
+                flag.set(required && stage.trigger == StageTrigger::SourceChanged);
+            }
+        }
+    }
+
+    /// Returns the next stage to run. When there is more than one build mode the
+    /// pipeline for the first one is run to completion before the next is started.
+    pub(crate) fn next_stage(&self, build_modes: &[BuildMode]) -> Option<StageRef> {
+        build_modes.iter().find_map(|&build_mode| {
+            self.required[&build_mode]
+                .iter()
+                .position(|flag| flag.is_true())
+                .map(|index| StageRef { build_mode, index })
+        })
+    }
+
+    /// Called when the job for a stage completes. The stages it triggers become
+    /// required if it succeeded, or if its `FailurePolicy` says to continue.
+    pub(crate) fn stage_completed(&self, pipeline: &Pipeline, stage: StageRef, succeeded: bool) {
+        let flags = &self.required[&stage.build_mode];
+        flags[stage.index].set_false();
+
+        let completed_stage = &pipeline.stages()[stage.index];
+        if !succeeded && completed_stage.on_failure == FailurePolicy::Stop {
+            return;
+        }
+
+        for (later_stage, flag) in pipeline.stages().iter().zip(flags) {
+            if later_stage.trigger == StageTrigger::After(completed_stage.name.clone()) {
+                flag.set_true();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clippy() -> PipelineStage {
+        "clippy:build-tests:clippy --all-targets".parse().unwrap()
+    }
+
+    #[test]
+    fn stages_are_parsed_as_cargo_commands() {
+        let stage = clippy();
+        assert_eq!(stage.name, "clippy");
+        assert_eq!(stage.trigger, StageTrigger::After("build-tests".into()));
+        assert_eq!(
+            stage.action,
+            StageAction::Cargo(vec!["clippy".into(), "--all-targets".into()])
+        );
+        assert_eq!(stage.on_failure, FailurePolicy::Stop);
+
+        // Only the first two colons separate the parts.
+        let stage = " fmt : source-changed : fmt -- --config a:b ".parse::<PipelineStage>();
+        let stage = stage.unwrap();
+        assert_eq!(stage.name, "fmt");
+        assert_eq!(stage.trigger, StageTrigger::SourceChanged);
+        assert_eq!(
+            stage.action,
+            StageAction::Cargo(vec![
+                "fmt".into(),
+                "--".into(),
+                "--config".into(),
+                "a:b".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn stages_without_a_name_trigger_and_args_are_rejected() {
+        for s in &[
+            "clippy",
+            "clippy:build-tests",
+            "clippy::clippy",
+            ":build-tests:clippy",
+        ] {
+            assert!(s.parse::<PipelineStage>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn add_stage_appends_a_stage_triggered_by_an_earlier_one() {
+        let mut pipeline = Pipeline::default();
+        pipeline.add_stage(clippy()).unwrap();
+
+        let names = pipeline
+            .stages()
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["build-tests", "list-tests", "run-tests", "clippy"]
+        );
+    }
+
+    #[test]
+    fn add_stage_rejects_duplicate_names_and_later_triggers() {
+        let mut pipeline = Pipeline::default();
+        pipeline.add_stage(clippy()).unwrap();
+        assert_eq!(
+            pipeline.add_stage(clippy()),
+            Err("There is already a stage called 'clippy'".to_string())
+        );
+
+        let stage = "doc:docs:doc".parse().unwrap();
+        assert_eq!(
+            pipeline.add_stage(stage),
+            Err("Stage 'doc' is triggered by 'docs', which is not an earlier stage".to_string())
+        );
+        assert_eq!(pipeline.stages().len(), 4);
+
+        // A stage can't be triggered by one that comes after it.
+        let stages = vec!["doc:clippy:doc".parse().unwrap(), clippy()];
+        assert!(Pipeline::new(stages).is_err());
+    }
+}
@@ -1,43 +1,107 @@
-use log::info;
+use crate::{bool_flag::BoolFlag, utils::plural_s};
+use log::{info, warn};
 use std::{
     collections::{hash_map::Entry, HashMap},
-    path::{PathBuf, MAIN_SEPARATOR},
+    fs,
+    path::{Path, PathBuf, MAIN_SEPARATOR},
     sync::mpsc::Sender,
-    thread,
+    thread::{self, JoinHandle},
 };
+use tempfile::TempDir;
 use watchexec::cli::ArgsBuilder;
 use watchexec::{pathop::PathOp, Args, Handler};
-use crate::utils::plural_s;
+
+/// The name of the file that `DirectoryWatcher::stop` writes to wake the watch.
+const WAKE_FILE: &str = "stop";
 
 /// Start a 'cargo-watch-like' watch process on `path` (which will be the source directory).
 /// The watch ignores everything that `.gitignore` would ignore, so that only changes relating
 /// to files we need for compilation should be emitted. Events are emitted on the `sender`
 /// channel.
 ///
-/// The watch runs on a separate thread which runs until the end of the program.
-/// This implies there is no way to change the source directory after the program
-/// has started.
-pub fn start_watching<P>(path: P, sender: Sender<FileSyncEvent>)
+/// The watch runs on a separate thread which runs until `stop` is called on the
+/// returned `DirectoryWatcher`.
+pub fn start_watching<P>(path: P, sender: Sender<FileSyncEvent>) -> DirectoryWatcher
 where
     P: Into<PathBuf>,
 {
-    let args = get_args(path);
-    let handler = FileEventHandler::new(args, sender);
+    // `watchexec` only gives control back to us when a file system event occurs,
+    // and has no other way of being stopped. So it also watches a directory of
+    // our own, which `stop` writes to, leaving the source directory alone.
+    let wake_directory = tempfile::Builder::new()
+        .prefix("rtest-watcher")
+        .tempdir()
+        .expect("Cannot create the DirectoryWatcher's wake directory");
+
+    // `watchexec` reports the paths of the events under the canonical path.
+    let canonical_wake_directory = fs::canonicalize(wake_directory.path())
+        .unwrap_or_else(|_| wake_directory.path().to_path_buf());
+
+    let stop_requested = BoolFlag::default();
+    let args = get_args(path.into(), wake_directory.path());
+    let handler = FileEventHandler::new(
+        args,
+        sender,
+        stop_requested.clone(),
+        canonical_wake_directory,
+    );
 
     let thread_builder = thread::Builder::new().name("DirectoryWatcher".into());
-    thread_builder
+    let thread = thread_builder
         .spawn(move || {
-            watchexec::run::watch(&handler).unwrap();
+            if let Err(err) = watchexec::run::watch(&handler) {
+                warn!("DirectoryWatcher stopped with an error, err = {}", err);
+            }
+            info!("DirectoryWatcher background thread stopped");
         })
         .expect("Cannot create background thread to run the directory watcher");
     info!("Successfully spawned DirectoryWatcher background thread");
+
+    DirectoryWatcher {
+        wake_directory,
+        stop_requested,
+        thread,
+    }
+}
+
+/// A handle to the watch started by `start_watching`.
+#[derive(Debug)]
+pub struct DirectoryWatcher {
+    wake_directory: TempDir,
+    stop_requested: BoolFlag,
+    thread: JoinHandle<()>,
+}
+
+impl DirectoryWatcher {
+    /// Stops the watch and waits for its thread to finish. No more events
+    /// are sent once this has been called.
+    pub fn stop(self) {
+        info!("Stopping DirectoryWatcher");
+        self.stop_requested.set_true();
+
+        // If the thread has already stopped there is nothing to wake.
+        if !self.thread.is_finished() {
+            let wake_file = self.wake_directory.path().join(WAKE_FILE);
+            if let Err(err) = fs::write(&wake_file, "") {
+                warn!(
+                    "Could not create {}, the DirectoryWatcher will not stop, err = {}",
+                    wake_file.display(),
+                    err
+                );
+                return;
+            }
+        }
+
+        if self.thread.join().is_err() {
+            warn!("The DirectoryWatcher background thread panicked");
+        }
+
+        info!("DirectoryWatcher stopped");
+    }
 }
 
 /// Constructs the arguments to be passed to the `watchexec` crate.
-fn get_args<P>(path: P) -> Args
-where
-    P: Into<PathBuf>,
-{
+fn get_args(path: PathBuf, wake_directory: &Path) -> Args {
     // Note that this list of ignores is a glob list, not a regex-list.
     // Taken from cargo-watch/lib.rs and edited a bit.
     let list = vec![
@@ -69,7 +133,7 @@ where
 
     ArgsBuilder::default()
         .cmd(vec!["".into()]) // Execute nothing, just raise events.
-        .paths(vec![path.into()])
+        .paths(vec![path, wake_directory.to_path_buf()])
         .ignores(list)
         .run_initially(false) // turns off the on_manual event.
         .debounce(500_u64)
@@ -82,11 +146,24 @@ where
 struct FileEventHandler {
     args: Args,
     sender: Sender<FileSyncEvent>,
+    stop_requested: BoolFlag,
+    /// See `start_watching`. Events in here are never sent.
+    wake_directory: PathBuf,
 }
 
 impl FileEventHandler {
-    fn new(args: Args, sender: Sender<FileSyncEvent>) -> Self {
-        Self { args, sender }
+    fn new(
+        args: Args,
+        sender: Sender<FileSyncEvent>,
+        stop_requested: BoolFlag,
+        wake_directory: PathBuf,
+    ) -> Self {
+        Self {
+            args,
+            sender,
+            stop_requested,
+            wake_directory,
+        }
     }
 }
 
@@ -117,9 +194,21 @@ impl Handler for FileEventHandler {
     ///
     /// Note that we don't care about directory creation events, since copying a file to the destination
     /// will create all needed parent directories.
+    ///
+    /// Once `DirectoryWatcher::stop` has been called no more events are sent, and
+    /// we tell `watchexec` to stop watching.
     fn on_update(&self, ops: &[watchexec::pathop::PathOp]) -> watchexec::error::Result<bool> {
+        // Returning false ends the watch.
+        if self.stop_requested.is_true() {
+            return Ok(false);
+        }
+
         // Utility function to actually send the appropriate event.
         fn send_event(me: &FileEventHandler, op: &watchexec::pathop::PathOp) {
+            if op.path.starts_with(&me.wake_directory) {
+                return;
+            }
+
             let op_type = op.op.unwrap();
 
             if PathOp::is_remove(op_type) {
@@ -0,0 +1,69 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Returns the git commit that is checked out in `directory`, or in the
+/// repository that contains it. Returns `None` if it isn't in a git
+/// repository or the commit can't be worked out. The files are read
+/// directly, rather than by running git, because this is called each time
+/// the tests are run. Changes that haven't been committed are not reflected.
+pub fn source_revision(directory: &Path) -> Option<String> {
+    let git_dir = directory.ancestors().find_map(git_dir)?;
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+
+    match head.strip_prefix("ref: ") {
+        Some(reference) => resolve_reference(&git_dir, reference.trim()),
+        // A detached HEAD is the commit itself.
+        None if is_commit(head) => Some(head.to_string()),
+        None => None,
+    }
+}
+
+/// Returns the git directory of the repository whose working tree is
+/// `directory`. In a worktree or a submodule `.git` is a file that
+/// points to the git directory.
+fn git_dir(directory: &Path) -> Option<PathBuf> {
+    let dot_git = directory.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+
+    let contents = fs::read_to_string(&dot_git).ok()?;
+    let path = contents.trim().strip_prefix("gitdir: ")?;
+    Some(directory.join(path))
+}
+
+/// Looks up a reference such as "refs/heads/master", which is either in
+/// a file of its own or in the `packed-refs` file. A worktree shares the
+/// references of the main repository, apart from its own HEAD.
+fn resolve_reference(git_dir: &Path, reference: &str) -> Option<String> {
+    let common_dir = fs::read_to_string(git_dir.join("commondir"))
+        .map(|common_dir| git_dir.join(common_dir.trim()))
+        .unwrap_or_else(|_| git_dir.to_path_buf());
+
+    for dir in &[git_dir, &common_dir] {
+        if let Ok(commit) = fs::read_to_string(dir.join(reference)) {
+            let commit = commit.trim();
+            if is_commit(commit) {
+                return Some(commit.to_string());
+            }
+        }
+    }
+
+    let packed_refs = fs::read_to_string(common_dir.join("packed-refs")).ok()?;
+    packed_refs.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(commit), Some(name)) if name == reference && is_commit(commit) => {
+                Some(commit.to_string())
+            }
+            _ => None,
+        }
+    })
+}
+
+fn is_commit(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -1,30 +1,66 @@
-use cargo_test_parser::Tests;
-use log::info;
+use cargo_test_parser::{TestOutcome, TestResult, TestRunResults, Tests};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use std::{
+    cmp::Reverse,
     collections::HashMap,
+    fs,
     hash::Hash,
+    io,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
-use crate::configuration::Configuration;
+use crate::{
+    configuration::{BuildMode, Configuration},
+    duration_stats::{DurationHistory, DurationRegression, SlowBinary, SlowTest},
+    jobs::{IsolatedTestRun, ProcessExit, TestProgress},
+    test_history::{OutcomeHistory, OutcomeRecord, TestRunInfo},
+    test_selection::IgnoredTests,
+};
+
+/// The version of the file that the `State` is saved to. Files with a
+/// different version are ignored rather than misread, so this must be
+/// increased whenever a change to the saved types breaks older files.
+const STATE_FILE_VERSION: u32 = 1;
 
 /// Represents the program state (excluding the engine).
 /// Basically this is the list of known tests and their state.
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct State {
     inner: Arc<Mutex<InnerState>>,
 }
 
+#[derive(Debug)]
 pub struct InnerState {
     configuration: Configuration,
-    tests: Vec<CrateTests>,
+    /// The tests are kept separately for each build mode, because a test
+    /// can behave differently in release mode (no overflow checks, no
+    /// `debug_assert!`) and the test binaries themselves are different.
+    tests: HashMap<BuildMode, Vec<CrateTests>>,
+}
+
+/// The on-disk form of the `State`, see `State::save`. The `tests`
+/// are ignored while the version is checked.
+#[derive(Serialize, Deserialize)]
+struct StateFile<T> {
+    version: u32,
+    saved_date: DateTime<Utc>,
+    tests: T,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CrateTests {
     crate_name: CrateName,
     unit_tests: HashMap<String, UnitTest>,
+    /// The doc tests, if this is the crate's library, keyed by `DocTest::full_name`.
+    doc_tests: HashMap<String, DocTest>,
+    /// How long the binary takes to run all of its tests.
+    durations: DurationHistory,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CrateName {
     /// The name with the UUID removed, for example
     /// "/home/phil/repos/rtest/target/debug/deps/example_lib_tests".
@@ -43,14 +79,78 @@ pub struct CrateName {
     pub basename: String,
 }
 
-#[derive(Debug, Clone)]
+/// A test binary and the tests in it, see `State::test_binaries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedBinary {
+    /// See `CrateName::basename`.
+    pub basename: String,
+    /// The path of the binary, which is the `CrateName::full_name`.
+    pub path: PathBuf,
+    pub tests: Vec<String>,
+}
+
+/// Applies the progress of one run of the tests to the `State`,
+/// see `State::test_progress`.
+#[derive(Debug)]
+struct StateProgress {
+    state: State,
+    run: TestRunInfo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnitTest {
     name: String,
     state: TestState,
     num_times_executed: usize,
+    /// The output of the test, if it was last run in a process of its own.
+    /// Otherwise the output of the tests in a binary is interleaved.
+    output: Option<String>,
+    durations: DurationHistory,
+    history: OutcomeHistory,
+    /// True if the test's state was loaded from the file saved by a previous
+    /// session, and it hasn't been run since, see `State::load`.
+    #[serde(skip)]
+    from_previous_session: bool,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// A doc test. libtest identifies it by where it is, so the `UnitTest`
+/// is named after that (see `DocTest::full_name`), and moving the doc
+/// test makes it a different test.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocTest {
+    test: UnitTest,
+    /// The item that the doc test is attached to.
+    item: String,
+    file_name: String,
+    line_number: usize,
+}
+
+/// Where a doc test is, see `TestSummary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocTestLocation {
+    /// The item that the doc test is attached to, for example "passing_doctest".
+    pub item: String,
+    /// The file, relative to the package directory, for example "src/lib.rs".
+    pub file_name: String,
+    pub line_number: usize,
+}
+
+/// A test and its state, see `State::tests`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSummary {
+    /// See `CrateName::basename`.
+    pub crate_basename: String,
+    /// The name that libtest knows the test by.
+    pub name: String,
+    /// Where the test is, if it is a doc test.
+    pub doc_test: Option<DocTestLocation>,
+    pub state: TestState,
+    pub num_times_executed: usize,
+    /// True if the `state` is from a previous session, see `State::load`.
+    pub from_previous_session: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum TestState {
     NotRun,
     CompilationFailing,
@@ -58,41 +158,543 @@ pub enum TestState {
     Passed,
     Failed,
     Ignored,
+    /// The test's process died before the test completed, with this exit
+    /// (if known). Only detected when each test is run in its own process.
+    Crashed(Option<ProcessExit>),
+    /// The test failed, but when it was rerun it passed some of the time.
+    /// The counts include the original failure.
+    Flaky { passed: usize, failed: usize },
+    /// The test was `TestState::Running` but no outcome was reported for it,
+    /// so whatever was known about it before is out of date. This happens when
+    /// its binary crashes, times out or is cancelled, or when the test list is
+    /// out of date.
+    Stale,
+}
+
+impl TestState {
+    /// For a `TestState::Flaky` test, returns the fraction of
+    /// its runs that passed, between 0 and 1.
+    pub fn pass_ratio(&self) -> Option<f64> {
+        match *self {
+            TestState::Flaky { passed, failed } => Some(passed as f64 / (passed + failed) as f64),
+            _ => None,
+        }
+    }
 }
 
 impl InnerState {
     fn new(configuration: Configuration) -> Self {
-        Self { configuration, tests: Vec::new() }
+        Self { configuration, tests: HashMap::new() }
     }
 
-    pub fn update_test_list(&mut self, test_list: &[Tests]) {
+    pub fn update_test_list(&mut self, build_mode: BuildMode, test_list: &[Tests]) {
         info!(
-            "Updating test list in State, passed {} crates",
+            "Updating {:?} test list in State, passed {} crates",
+            build_mode,
             test_list.len()
         );
 
+        let crate_tests = self.tests.entry(build_mode).or_default();
+
+        // A test binary that has been rebuilt with a different hash replaces the
+        // old one, which is left on disk by cargo but no longer up to date.
+        crate_tests.retain(|ct| {
+            let full_name = &ct.crate_name.full_name;
+            test_list
+                .iter()
+                .filter(|t| t.crate_name.name == ct.crate_name.name)
+                .all(|t| t.crate_name.full_name == *full_name)
+                || test_list.iter().any(|t| t.crate_name.full_name == *full_name)
+        });
+
         for t in test_list.iter() {
-            self.update_test_list_for_crate(t);
+            Self::update_test_list_for_crate(crate_tests, t);
+        }
+    }
+
+    /// Applies the outcomes of a test run. Only the tests that were run are
+    /// updated, the rest keep whatever state they had before. When the outcomes
+    /// were applied as the tests ran (see `State::test_progress`) use
+    /// `update_test_durations` instead.
+    pub fn update_test_results(
+        &mut self,
+        build_mode: BuildMode,
+        run: &TestRunInfo,
+        results: &[TestRunResults],
+    ) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+
+        for result in results {
+            for outcome in &result.outcomes {
+                let unit_test = Self::find_test(
+                    crate_tests,
+                    &result.crate_name,
+                    result.is_doc_tests,
+                    outcome.name,
+                );
+                if let Some(unit_test) = unit_test {
+                    unit_test.record_result(outcome.result);
+                    unit_test.record_history(build_mode, run, outcome.duration);
+                }
+            }
+
+            info!(
+                "Updated {} test results for crate '{}'",
+                result.outcomes.len(),
+                result.crate_name.basename
+            );
         }
+
+        self.update_test_durations(build_mode, results);
+    }
+
+    /// Records how long the tests and the test binaries took to run.
+    pub fn update_test_durations(&mut self, build_mode: BuildMode, results: &[TestRunResults]) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+
+        // A binary can be run more than once, for example once per test when
+        // the tests are isolated, so its durations and outcomes are totalled.
+        let mut binary_runs = HashMap::<usize, (Duration, usize)>::new();
+
+        for result in results.iter().filter(|r| !r.is_doc_tests) {
+            let idx = match crate_tests
+                .iter()
+                .position(|t| t.crate_name.matches(&result.crate_name))
+            {
+                Some(idx) => idx,
+                None => {
+                    info!(
+                        "Ignoring results for unknown crate '{}'",
+                        result.crate_name.full_name
+                    );
+                    continue;
+                }
+            };
+            let crt = &mut crate_tests[idx];
+
+            if let Some(duration) = result.duration {
+                let binary_run = binary_runs.entry(idx).or_default();
+                binary_run.0 += duration;
+                binary_run.1 += result.outcomes.len();
+            }
+
+            // Without `--report-time` there are no durations for the tests, but
+            // a binary that ran just one test took about as long as the test.
+            let single_test_duration = match result.outcomes.as_slice() {
+                [_] => result.duration,
+                _ => None,
+            };
+
+            for outcome in &result.outcomes {
+                if let Some(unit_test) = crt.unit_tests.get_mut(outcome.name) {
+                    let duration = outcome.duration.or(single_test_duration);
+                    match duration {
+                        Some(duration) if outcome.result != TestResult::Ignored => {
+                            unit_test.durations.record(duration);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // The duration of a run of some of the tests can't
+        // be compared with that of a run of all of them.
+        for (idx, (duration, outcomes)) in binary_runs {
+            let crt = &mut crate_tests[idx];
+            if outcomes >= crt.unit_tests.len() {
+                crt.durations.record(duration);
+            }
+        }
+    }
+
+    /// Marks the tests that are about to run as `TestState::Running`,
+    /// see `TestProgress::tests_started`. When only the ignored tests are
+    /// run, libtest doesn't mention the others, so only the tests that are
+    /// known to be `#[ignore]` are marked.
+    pub fn tests_started(
+        &mut self,
+        build_mode: BuildMode,
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+        selected: &[String],
+        skipped: &[String],
+        ignored: IgnoredTests,
+    ) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+        for unit_test in Self::section_tests(crate_tests, crate_name, is_doc_tests) {
+            if (selected.is_empty() || selected.contains(&unit_test.name))
+                && !skipped.contains(&unit_test.name)
+                && (ignored != IgnoredTests::Only || unit_test.is_ignored())
+            {
+                unit_test.mark_running();
+            }
+        }
+    }
+
+    /// Applies the outcome of a test as soon as it completes.
+    pub fn test_completed(
+        &mut self,
+        build_mode: BuildMode,
+        run: &TestRunInfo,
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+        outcome: &TestOutcome<'_>,
+    ) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+        if let Some(unit_test) =
+            Self::find_test(crate_tests, crate_name, is_doc_tests, outcome.name)
+        {
+            unit_test.record_result(outcome.result);
+            unit_test.record_history(build_mode, run, outcome.duration);
+        }
+    }
+
+    /// Marks the tests that were still running when their binary stopped
+    /// as `TestState::Stale`, see `TestProgress::tests_finished`.
+    pub fn tests_finished(
+        &mut self,
+        build_mode: BuildMode,
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+    ) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+        for unit_test in Self::section_tests(crate_tests, crate_name, is_doc_tests) {
+            if unit_test.state == TestState::Running {
+                info!(
+                    "Test {} did not complete, marking it as stale",
+                    unit_test.name
+                );
+                unit_test.state = TestState::Stale;
+            }
+        }
+    }
+
+    /// Applies the results of tests that were run in processes of their own.
+    /// The outcomes that libtest reported have already been applied by
+    /// `update_test_results`, so only crashes are recorded here, along with
+    /// each test's output. The binaries are identified by `CrateName::full_name`.
+    pub fn update_isolated_results(
+        &mut self,
+        build_mode: BuildMode,
+        test_run: &TestRunInfo,
+        runs: &[IsolatedTestRun],
+    ) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+
+        for run in runs {
+            let unit_test = crate_tests
+                .iter_mut()
+                .filter(|t| t.crate_name.full_name == run.binary)
+                .find_map(|t| t.unit_tests.get_mut(&run.test));
+
+            if let Some(unit_test) = unit_test {
+                if run.result.is_none() {
+                    unit_test.record_crash(run.exit);
+                    unit_test.record_history(build_mode, test_run, None);
+                }
+                unit_test.output = Some(run.output.clone());
+            }
+        }
+    }
+
+    /// Applies the outcomes of a job that reran the tests that had just failed,
+    /// see `RunTestsJob::flaky_reruns`. A test that passed on any of the
+    /// reruns is `TestState::Flaky`, one that failed or crashed on all of
+    /// them stays failed. Tests that hadn't failed are left alone, since
+    /// the reruns select tests by name and can include their namesakes.
+    /// The reruns are not added to the tests' histories.
+    pub fn update_flaky_results(
+        &mut self,
+        build_mode: BuildMode,
+        results: &[TestRunResults],
+        isolated_runs: &[IsolatedTestRun],
+    ) {
+        let crate_tests = self.tests.entry(build_mode).or_default();
+
+        // The outcomes are counted for each test, identified by
+        // the index of its crate and its name.
+        let mut counts = HashMap::<(usize, &str), (usize, usize)>::new();
+        for result in results.iter().filter(|r| !r.is_doc_tests) {
+            let idx = match crate_tests
+                .iter()
+                .position(|t| t.crate_name.matches(&result.crate_name))
+            {
+                Some(idx) => idx,
+                None => continue,
+            };
+            for outcome in &result.outcomes {
+                let count = counts.entry((idx, outcome.name)).or_default();
+                match outcome.result {
+                    TestResult::Passed => count.0 += 1,
+                    TestResult::Failed => count.1 += 1,
+                    TestResult::Ignored => {}
+                }
+            }
+        }
+        for run in isolated_runs.iter().filter(|run| run.result.is_none()) {
+            if let Some(idx) = crate_tests
+                .iter()
+                .position(|t| t.crate_name.full_name == run.binary)
+            {
+                counts.entry((idx, run.test.as_str())).or_default().1 += 1;
+            }
+        }
+
+        for ((idx, test), (passed, failed)) in counts {
+            let unit_test = crate_tests[idx]
+                .unit_tests
+                .get_mut(test)
+                .filter(|ut| ut.has_failed());
+
+            if let Some(unit_test) = unit_test {
+                unit_test.record_reruns(passed, failed);
+                info!("Test {} reran as {:?}", test, unit_test.state);
+            }
+        }
+    }
+
+    /// Returns each test binary that has tests that failed the last time they
+    /// were run, with just those tests, sorted by name. Tests with the same name
+    /// in different binaries are different tests, so they are kept apart.
+    pub fn failed_tests(&self, build_mode: BuildMode) -> Vec<ListedBinary> {
+        self.crate_tests(build_mode)
+            .iter()
+            .filter_map(|t| {
+                let mut tests = t
+                    .unit_tests
+                    .values()
+                    .filter(|ut| ut.has_failed())
+                    .map(|ut| ut.name.clone())
+                    .collect::<Vec<_>>();
+                if tests.is_empty() {
+                    return None;
+                }
+
+                tests.sort();
+                Some(ListedBinary {
+                    basename: t.crate_name.basename.clone(),
+                    path: PathBuf::from(&t.crate_name.full_name),
+                    tests,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns each known test binary and its tests, sorted by name. A crate
+    /// that only has doc tests has no binary of its own, so it is left out.
+    pub fn test_binaries(&self, build_mode: BuildMode) -> Vec<ListedBinary> {
+        self.crate_tests(build_mode)
+            .iter()
+            .filter(|t| !t.crate_name.uuid.is_empty())
+            .map(|t| {
+                let mut tests = t.unit_tests.keys().cloned().collect::<Vec<_>>();
+                tests.sort();
+                ListedBinary {
+                    basename: t.crate_name.basename.clone(),
+                    path: PathBuf::from(&t.crate_name.full_name),
+                    tests,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the output of a single test, if it was last run in a process of its own.
+    pub fn test_output(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<String> {
+        self.crate_tests(build_mode)
+            .iter()
+            .filter(|t| t.crate_name.basename == crate_basename)
+            .find_map(|t| t.unit_tests.get(test))
+            .and_then(|ut| ut.output.clone())
+    }
+
+    /// Returns the state of a single test, identified by the basename of its
+    /// crate (see `CrateName::basename`) and its name. For a doc test the name
+    /// is the one libtest uses, see `DocTest::full_name`.
+    pub fn test_state(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<TestState> {
+        self.unit_test(build_mode, crate_basename, test)
+            .map(|ut| ut.state)
+    }
+
+    /// Returns the `count` most recent outcomes of a test, newest first.
+    /// The test is identified as for `test_state`.
+    pub fn last_results(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+        count: usize,
+    ) -> Vec<OutcomeRecord> {
+        self.unit_test(build_mode, crate_basename, test)
+            .map_or_else(Vec::new, |ut| ut.history.last(count))
+    }
+
+    /// Returns when a test started failing, see `OutcomeHistory::first_failure_after_pass`.
+    pub fn first_failure_after_pass(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<OutcomeRecord> {
+        self.unit_test(build_mode, crate_basename, test)
+            .and_then(|ut| ut.history.first_failure_after_pass().cloned())
+    }
+
+    /// Returns the fraction of the recorded runs of a test that passed,
+    /// see `OutcomeHistory::pass_rate`.
+    pub fn pass_rate(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<f64> {
+        self.unit_test(build_mode, crate_basename, test)
+            .and_then(|ut| ut.history.pass_rate())
+    }
+
+    /// Returns every test, unit tests and doc tests alike, sorted by crate
+    /// and then by name.
+    pub fn tests(&self, build_mode: BuildMode) -> Vec<TestSummary> {
+        let mut tests = Vec::new();
+        for t in self.crate_tests(build_mode) {
+            let summary = |ut: &UnitTest, doc_test| TestSummary {
+                crate_basename: t.crate_name.basename.clone(),
+                name: ut.name.clone(),
+                doc_test,
+                state: ut.state,
+                num_times_executed: ut.num_times_executed,
+                from_previous_session: ut.from_previous_session,
+            };
+
+            let start = tests.len();
+            tests.extend(t.unit_tests.values().map(|ut| summary(ut, None)));
+            tests.extend(t.doc_tests.values().map(|dt| {
+                let location = DocTestLocation {
+                    item: dt.item.clone(),
+                    file_name: dt.file_name.clone(),
+                    line_number: dt.line_number,
+                };
+                summary(&dt.test, Some(location))
+            }));
+            tests[start..].sort_by(|a, b| a.name.cmp(&b.name));
+        }
+
+        tests
+    }
+
+    /// Returns the `count` tests that take the longest on average, slowest first.
+    pub fn slowest_tests(&self, build_mode: BuildMode, count: usize) -> Vec<SlowTest> {
+        let mut slowest = self
+            .crate_tests(build_mode)
+            .iter()
+            .flat_map(|t| {
+                t.unit_tests.values().filter_map(move |ut| {
+                    Some(SlowTest {
+                        crate_basename: t.crate_name.basename.clone(),
+                        test: ut.name.clone(),
+                        stats: ut.durations.stats()?,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        slowest.sort_by_key(|t| Reverse(t.stats.mean));
+        slowest.truncate(count);
+        slowest
+    }
+
+    /// Returns the `count` test binaries that take the longest on average
+    /// to run all of their tests, slowest first.
+    pub fn slowest_binaries(&self, build_mode: BuildMode, count: usize) -> Vec<SlowBinary> {
+        let mut slowest = self
+            .crate_tests(build_mode)
+            .iter()
+            .filter_map(|t| {
+                Some(SlowBinary {
+                    crate_basename: t.crate_name.basename.clone(),
+                    stats: t.durations.stats()?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        slowest.sort_by_key(|t| Reverse(t.stats.mean));
+        slowest.truncate(count);
+        slowest
+    }
+
+    /// Returns the tests whose last run was slower than their earlier runs by
+    /// more than `Configuration::duration_regression_threshold`, with the
+    /// biggest slowdown first.
+    pub fn duration_regressions(&self, build_mode: BuildMode) -> Vec<DurationRegression> {
+        let threshold = self.configuration.duration_regression_threshold();
+        let mut regressions = self
+            .crate_tests(build_mode)
+            .iter()
+            .flat_map(|t| {
+                t.unit_tests.values().filter_map(move |ut| {
+                    let previous_mean = ut.durations.regression(threshold)?;
+                    Some(DurationRegression {
+                        crate_basename: t.crate_name.basename.clone(),
+                        test: ut.name.clone(),
+                        last: ut.durations.stats()?.last,
+                        previous_mean,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        regressions.sort_by_key(|r| Reverse(r.last - r.previous_mean));
+        regressions
+    }
+
+    /// Returns the tests for the specified `build_mode`, sorted by crate name.
+    pub fn crate_tests(&self, build_mode: BuildMode) -> &[CrateTests] {
+        self.tests.get(&build_mode).map_or(&[], |tests| tests.as_slice())
     }
 
-    fn update_test_list_for_crate(&mut self, test: &Tests) {
-        let idx = match self
-            .tests
+    /// Finds a unit test or a doc test, see `test_state`.
+    fn unit_test(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<&UnitTest> {
+        self.crate_tests(build_mode)
+            .iter()
+            .filter(|t| t.crate_name.basename == crate_basename)
+            .find_map(|t| {
+                t.unit_tests
+                    .get(test)
+                    .or_else(|| t.doc_tests.get(test).map(|dt| &dt.test))
+            })
+    }
+
+    fn update_test_list_for_crate(crate_tests: &mut Vec<CrateTests>, test: &Tests) {
+        let idx = match crate_tests
             .iter()
             .position(|t| t.crate_name.full_name == test.crate_name.full_name)
         {
             Some(idx) => idx,
             None => {
-                self.tests.push(CrateTests::new(&test.crate_name));
-                self.tests.len() - 1
+                crate_tests.push(CrateTests::new(&test.crate_name));
+                crate_tests.len() - 1
             }
         };
 
         // Rebuild the unit_tests collection based on the new data. Doing it this
         // way (by building a new collection) is easier than trying to adjust it
         // in-place.
-        let mut crt = &mut self.tests[idx];
+        let crt = &mut crate_tests[idx];
         let mut updated_unit_tests = HashMap::new();
         for &unit_test in &test.tests {
             let unit_test = unit_test.to_string();
@@ -109,10 +711,150 @@ impl InnerState {
         crt.unit_tests = updated_unit_tests;
         info!("There are now {} tests for crate '{}'", crt.unit_tests.len(), crt.crate_name.basename);
 
-        // TODO: Repeat for the doc tests.
-        // for &doc_test in &test.tests {}
+        let mut updated_doc_tests = HashMap::new();
+        for doc_test in &test.doc_tests {
+            let full_name = doc_test.full_name();
+            let dt = crt
+                .doc_tests
+                .remove(&full_name)
+                .unwrap_or_else(|| DocTest::new(doc_test));
+            updated_doc_tests.insert(full_name, dt);
+        }
+        crt.doc_tests = updated_doc_tests;
+        if !crt.doc_tests.is_empty() {
+            info!(
+                "There are now {} doc tests for crate '{}'",
+                crt.doc_tests.len(),
+                crt.crate_name.basename
+            );
+        }
+
+        crate_tests.sort();
+    }
+
+    /// Saves the tests to `Configuration::state_file`, so that they can be
+    /// loaded when rtest is next started. Failures are logged but otherwise
+    /// ignored, losing the state is not fatal.
+    pub fn save(&self) {
+        let path = self.configuration.state_file();
+        if let Err(err) = self.try_save(&path) {
+            error!(
+                "Error saving the state to {}, err = {}",
+                path.display(),
+                err
+            );
+        }
+    }
+
+    fn try_save(&self, path: &Path) -> io::Result<()> {
+        let file = StateFile {
+            version: STATE_FILE_VERSION,
+            saved_date: Utc::now(),
+            tests: &self.tests,
+        };
+        let json = serde_json::to_string(&file)?;
+
+        // The file is replaced in one go, so that rtest stopping
+        // part way through can't leave half a file behind.
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, json)?;
+        fs::rename(&temp_path, path)?;
+        info!("Saved the state to {}", path.display());
+        Ok(())
+    }
+
+    /// Replaces the tests with those saved by `save`, if there are any. They
+    /// are marked as being from a previous session until they are run again.
+    fn load(&mut self) {
+        let path = self.configuration.state_file();
+        let mut tests = match Self::try_load(&path) {
+            Ok(Some(tests)) => tests,
+            Ok(None) => return,
+            Err(err) => {
+                warn!(
+                    "Cannot load the state from {}, err = {}",
+                    path.display(),
+                    err
+                );
+                return;
+            }
+        };
+
+        for crate_tests in tests.values_mut() {
+            for t in crate_tests {
+                let doc_tests = t.doc_tests.values_mut().map(|dt| &mut dt.test);
+                for unit_test in t.unit_tests.values_mut().chain(doc_tests) {
+                    unit_test.mark_previous_session();
+                }
+            }
+        }
+
+        info!("Loaded the state from {}", path.display());
+        self.tests = tests;
+    }
+
+    fn try_load(path: &Path) -> io::Result<Option<HashMap<BuildMode, Vec<CrateTests>>>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let json = fs::read_to_string(path)?;
+        let file: StateFile<IgnoredAny> = serde_json::from_str(&json)?;
+        if file.version != STATE_FILE_VERSION {
+            info!(
+                "Ignoring {}, it is version {} rather than version {}",
+                path.display(),
+                file.version,
+                STATE_FILE_VERSION
+            );
+            return Ok(None);
+        }
+
+        let file: StateFile<HashMap<BuildMode, Vec<CrateTests>>> = serde_json::from_str(&json)?;
+        Ok(Some(file.tests))
+    }
 
-        self.tests.sort();
+    /// Returns the tests in the section of a test run's output that has the
+    /// heading `crate_name`. Doc tests are reported under the crate's name,
+    /// without a hash, so they are matched by the basename of the crate.
+    fn section_tests<'a>(
+        crate_tests: &'a mut [CrateTests],
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+    ) -> Vec<&'a mut UnitTest> {
+        let mut tests = Vec::new();
+        for t in crate_tests {
+            if is_doc_tests && t.crate_name.basename == crate_name.basename {
+                tests.extend(t.doc_tests.values_mut().map(|dt| &mut dt.test));
+            } else if !is_doc_tests && t.crate_name.matches(crate_name) {
+                tests.extend(t.unit_tests.values_mut());
+            }
+        }
+        tests
+    }
+
+    /// Finds a test by its name in the section of a test run's output that
+    /// has the heading `crate_name`, see `section_tests`.
+    fn find_test<'a>(
+        crate_tests: &'a mut [CrateTests],
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+        name: &str,
+    ) -> Option<&'a mut UnitTest> {
+        if is_doc_tests {
+            crate_tests
+                .iter_mut()
+                .filter(|t| t.crate_name.basename == crate_name.basename)
+                .find_map(|t| t.doc_tests.get_mut(name).map(|dt| &mut dt.test))
+        } else {
+            crate_tests
+                .iter_mut()
+                .filter(|t| t.crate_name.matches(crate_name))
+                .find_map(|t| t.unit_tests.get_mut(name))
+        }
     }
 }
 
@@ -123,9 +865,193 @@ impl State {
         }
     }
 
-    pub fn update_test_list(&mut self, tests: &[Tests]) {
+    /// Creates the state with the tests that were saved by the previous
+    /// session (see `save`), so their last known results can be shown
+    /// straight away. There are no tests if nothing was saved.
+    pub fn load(configuration: Configuration) -> Self {
+        let mut inner = InnerState::new(configuration);
+        inner.load();
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub fn save(&self) {
+        let guard = self.inner.lock().unwrap();
+        guard.save();
+    }
+
+    pub fn update_test_list(&mut self, build_mode: BuildMode, tests: &[Tests]) {
+        let mut guard = self.inner.lock().unwrap();
+        guard.update_test_list(build_mode, tests);
+    }
+
+    /// Returns the `TestProgress` that applies the outcomes of a run of
+    /// the tests as they complete, and records them in each test's history.
+    pub fn test_progress(&self, run: TestRunInfo) -> Arc<dyn TestProgress> {
+        Arc::new(StateProgress {
+            state: self.clone(),
+            run,
+        })
+    }
+
+    pub fn update_test_results(
+        &mut self,
+        build_mode: BuildMode,
+        run: &TestRunInfo,
+        results: &[TestRunResults],
+    ) {
+        let mut guard = self.inner.lock().unwrap();
+        guard.update_test_results(build_mode, run, results);
+    }
+
+    pub fn update_test_durations(&mut self, build_mode: BuildMode, results: &[TestRunResults]) {
         let mut guard = self.inner.lock().unwrap();
-        guard.update_test_list(tests);
+        guard.update_test_durations(build_mode, results);
+    }
+
+    pub fn update_isolated_results(
+        &mut self,
+        build_mode: BuildMode,
+        test_run: &TestRunInfo,
+        runs: &[IsolatedTestRun],
+    ) {
+        let mut guard = self.inner.lock().unwrap();
+        guard.update_isolated_results(build_mode, test_run, runs);
+    }
+
+    pub fn update_flaky_results(
+        &mut self,
+        build_mode: BuildMode,
+        results: &[TestRunResults],
+        isolated_runs: &[IsolatedTestRun],
+    ) {
+        let mut guard = self.inner.lock().unwrap();
+        guard.update_flaky_results(build_mode, results, isolated_runs);
+    }
+
+    pub fn failed_tests(&self, build_mode: BuildMode) -> Vec<ListedBinary> {
+        let guard = self.inner.lock().unwrap();
+        guard.failed_tests(build_mode)
+    }
+
+    pub fn slowest_tests(&self, build_mode: BuildMode, count: usize) -> Vec<SlowTest> {
+        let guard = self.inner.lock().unwrap();
+        guard.slowest_tests(build_mode, count)
+    }
+
+    pub fn slowest_binaries(&self, build_mode: BuildMode, count: usize) -> Vec<SlowBinary> {
+        let guard = self.inner.lock().unwrap();
+        guard.slowest_binaries(build_mode, count)
+    }
+
+    pub fn duration_regressions(&self, build_mode: BuildMode) -> Vec<DurationRegression> {
+        let guard = self.inner.lock().unwrap();
+        guard.duration_regressions(build_mode)
+    }
+
+    pub fn test_binaries(&self, build_mode: BuildMode) -> Vec<ListedBinary> {
+        let guard = self.inner.lock().unwrap();
+        guard.test_binaries(build_mode)
+    }
+
+    pub fn test_state(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<TestState> {
+        let guard = self.inner.lock().unwrap();
+        guard.test_state(build_mode, crate_basename, test)
+    }
+
+    pub fn tests(&self, build_mode: BuildMode) -> Vec<TestSummary> {
+        let guard = self.inner.lock().unwrap();
+        guard.tests(build_mode)
+    }
+
+    pub fn last_results(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+        count: usize,
+    ) -> Vec<OutcomeRecord> {
+        let guard = self.inner.lock().unwrap();
+        guard.last_results(build_mode, crate_basename, test, count)
+    }
+
+    pub fn first_failure_after_pass(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<OutcomeRecord> {
+        let guard = self.inner.lock().unwrap();
+        guard.first_failure_after_pass(build_mode, crate_basename, test)
+    }
+
+    pub fn pass_rate(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<f64> {
+        let guard = self.inner.lock().unwrap();
+        guard.pass_rate(build_mode, crate_basename, test)
+    }
+
+    pub fn test_output(
+        &self,
+        build_mode: BuildMode,
+        crate_basename: &str,
+        test: &str,
+    ) -> Option<String> {
+        let guard = self.inner.lock().unwrap();
+        guard.test_output(build_mode, crate_basename, test)
+    }
+}
+
+impl TestProgress for StateProgress {
+    fn tests_started(
+        &self,
+        build_mode: BuildMode,
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+        selected: &[String],
+        skipped: &[String],
+        ignored: IgnoredTests,
+    ) {
+        let mut guard = self.state.inner.lock().unwrap();
+        guard.tests_started(
+            build_mode,
+            crate_name,
+            is_doc_tests,
+            selected,
+            skipped,
+            ignored,
+        );
+    }
+
+    fn test_completed(
+        &self,
+        build_mode: BuildMode,
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+        outcome: &TestOutcome<'_>,
+    ) {
+        let mut guard = self.state.inner.lock().unwrap();
+        guard.test_completed(build_mode, &self.run, crate_name, is_doc_tests, outcome);
+    }
+
+    fn tests_finished(
+        &self,
+        build_mode: BuildMode,
+        crate_name: &cargo_test_parser::CrateName<'_>,
+        is_doc_tests: bool,
+    ) {
+        let mut guard = self.state.inner.lock().unwrap();
+        guard.tests_finished(build_mode, crate_name, is_doc_tests);
     }
 }
 
@@ -147,6 +1073,8 @@ impl CrateTests {
         Self {
             crate_name: CrateName::new(name),
             unit_tests: Default::default(),
+            doc_tests: Default::default(),
+            durations: Default::default(),
         }
     }
 }
@@ -161,6 +1089,13 @@ impl CrateName {
             name: name.name.into(),
         }
     }
+
+    /// Returns true if `name` refers to this crate. The test binaries have the
+    /// same UUID in the test list and the test run, but the path to them can be
+    /// printed differently, so only the basename and UUID are compared.
+    fn matches(&self, name: &cargo_test_parser::CrateName<'_>) -> bool {
+        self.basename == name.basename && self.uuid == name.uuid
+    }
 }
 
 impl UnitTest {
@@ -169,6 +1104,89 @@ impl UnitTest {
             name: name.into(),
             num_times_executed: 0,
             state: TestState::NotRun,
+            output: None,
+            durations: Default::default(),
+            history: Default::default(),
+            from_previous_session: false,
+        }
+    }
+
+    fn mark_running(&mut self) {
+        self.from_previous_session = false;
+        self.state = TestState::Running;
+    }
+
+    /// Marks the test as loaded from a previous session. A test that was
+    /// running when the state was saved never completed, so it is stale.
+    fn mark_previous_session(&mut self) {
+        self.from_previous_session = true;
+        self.history.mark_previous_session();
+        if self.state == TestState::Running {
+            self.state = TestState::Stale;
+        }
+    }
+
+    fn record_result(&mut self, result: TestResult) {
+        self.from_previous_session = false;
+        self.num_times_executed += 1;
+        self.output = None;
+        self.state = match result {
+            TestResult::Passed => TestState::Passed,
+            TestResult::Failed => TestState::Failed,
+            TestResult::Ignored => TestState::Ignored,
+        };
+    }
+
+    fn record_crash(&mut self, exit: Option<ProcessExit>) {
+        self.from_previous_session = false;
+        self.num_times_executed += 1;
+        self.state = TestState::Crashed(exit);
+    }
+
+    /// Records the outcomes of rerunning a failed test. Unless it passed at
+    /// least once it is still failed, and keeps its `TestState::Crashed`.
+    fn record_reruns(&mut self, passed: usize, failed: usize) {
+        self.from_previous_session = false;
+        self.num_times_executed += passed + failed;
+        if passed > 0 {
+            self.state = TestState::Flaky {
+                passed,
+                failed: failed + 1,
+            };
+        }
+    }
+
+    /// Adds the current state, which is the outcome of the run
+    /// that just completed, to the history of the test.
+    fn record_history(
+        &mut self,
+        build_mode: BuildMode,
+        run: &TestRunInfo,
+        duration: Option<Duration>,
+    ) {
+        let record = OutcomeRecord::new(self.state, duration, build_mode, run);
+        self.history.record(record);
+    }
+
+    /// Returns true if libtest has reported the test as ignored, which
+    /// means that it is `#[ignore]`, even if it has been run since.
+    fn is_ignored(&self) -> bool {
+        self.state == TestState::Ignored || self.history.has_been_ignored()
+    }
+
+    /// Returns true if the test failed or crashed the last time it was run.
+    fn has_failed(&self) -> bool {
+        matches!(self.state, TestState::Failed | TestState::Crashed(_))
+    }
+}
+
+impl DocTest {
+    fn new(doc_test: &cargo_test_parser::DocTest<'_>) -> Self {
+        Self {
+            test: UnitTest::new(doc_test.full_name()),
+            item: doc_test.name.into(),
+            file_name: doc_test.file_name.into(),
+            line_number: doc_test.line_number,
         }
     }
 }
@@ -0,0 +1,135 @@
+use crate::{configuration::BuildMode, jobs::JobId, state::TestState};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, time::Duration};
+
+/// The number of outcomes kept for each test.
+const MAX_OUTCOMES: usize = 100;
+
+/// Identifies the run of the tests that an outcome came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestRunInfo {
+    /// The job that ran the tests.
+    pub job_id: JobId,
+    /// The commit the tests were run on, see `source_revision`.
+    pub source_revision: Option<String>,
+}
+
+/// One of the outcomes in a test's `OutcomeHistory`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutcomeRecord {
+    /// When the outcome was recorded.
+    pub timestamp: DateTime<Utc>,
+    /// This is `TestState::Passed`, `TestState::Failed`,
+    /// `TestState::Ignored` or `TestState::Crashed`.
+    pub outcome: TestState,
+    /// How long the test took, if libtest reported it (see `TestSettings::report_time`).
+    pub duration: Option<Duration>,
+    pub build_mode: BuildMode,
+    /// The job that ran the test. Job ids start again from 1 each time
+    /// rtest is started, so this is only meaningful within a session.
+    pub job_id: JobId,
+    /// The commit the test was run on, if known.
+    pub source_revision: Option<String>,
+    /// True if the outcome was loaded from the file saved by a
+    /// previous session, see `State::load`.
+    #[serde(skip)]
+    pub previous_session: bool,
+}
+
+/// The most recent outcomes of a test, which show when it started failing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutcomeHistory {
+    /// Oldest first, at most `MAX_OUTCOMES` of them.
+    records: VecDeque<OutcomeRecord>,
+}
+
+impl OutcomeRecord {
+    pub fn new(
+        outcome: TestState,
+        duration: Option<Duration>,
+        build_mode: BuildMode,
+        run: &TestRunInfo,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            outcome,
+            duration,
+            build_mode,
+            job_id: run.job_id.clone(),
+            source_revision: run.source_revision.clone(),
+            previous_session: false,
+        }
+    }
+
+    fn is_failure(&self) -> bool {
+        matches!(self.outcome, TestState::Failed | TestState::Crashed(_))
+    }
+}
+
+impl OutcomeHistory {
+    /// Adds the latest outcome, forgetting the oldest if need be.
+    pub fn record(&mut self, record: OutcomeRecord) {
+        if self.records.len() == MAX_OUTCOMES {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Marks all the outcomes as being from a previous session.
+    pub fn mark_previous_session(&mut self) {
+        for record in &mut self.records {
+            record.previous_session = true;
+        }
+    }
+
+    /// Returns true if any of the outcomes is `TestState::Ignored`.
+    pub fn has_been_ignored(&self) -> bool {
+        self.records.iter().any(|r| r.outcome == TestState::Ignored)
+    }
+
+    /// Returns the `count` most recent outcomes, newest first.
+    pub fn last(&self, count: usize) -> Vec<OutcomeRecord> {
+        self.records.iter().rev().take(count).cloned().collect()
+    }
+
+    /// Returns the most recent failure that came straight after a pass. If
+    /// the test is failing now, this is when it started failing. Ignored
+    /// outcomes are skipped over. Returns `None` if the test has never
+    /// failed after passing, as far as the history goes back.
+    pub fn first_failure_after_pass(&self) -> Option<&OutcomeRecord> {
+        let mut first_failure = None;
+        let mut passed = false;
+        for record in self
+            .records
+            .iter()
+            .filter(|r| r.outcome != TestState::Ignored)
+        {
+            if passed && record.is_failure() {
+                first_failure = Some(record);
+            }
+            passed = record.outcome == TestState::Passed;
+        }
+
+        first_failure
+    }
+
+    /// Returns the fraction of the outcomes that were passes, between 0 and 1,
+    /// not counting ignored ones. Returns `None` if there are no such outcomes.
+    pub fn pass_rate(&self) -> Option<f64> {
+        let (passed, failed) = self
+            .records
+            .iter()
+            .fold((0, 0), |(passed, failed), record| match record.outcome {
+                TestState::Passed => (passed + 1, failed),
+                _ if record.is_failure() => (passed, failed + 1),
+                _ => (passed, failed),
+            });
+
+        if passed + failed == 0 {
+            None
+        } else {
+            Some(passed as f64 / (passed + failed) as f64)
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use crate::{configuration::BuildMode, test_settings::TestSettings};
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf, str::FromStr};
+
+/// Which of a package's test targets to run. These correspond to
+/// cargo's target selection options.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TestTarget {
+    /// Every test target, which is what `cargo test` does by default.
+    All,
+    /// The library's unit tests, `--lib`.
+    Lib,
+    /// The unit tests of the named binary, `--bin <name>`.
+    Bin(String),
+    /// The named integration test, `--test <name>`.
+    Test(String),
+    /// The doc tests, `--doc`.
+    Doc,
+}
+
+/// Whether tests marked with `#[ignore]` are run.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum IgnoredTests {
+    /// Ignored tests are not run, and are reported as ignored.
+    #[default]
+    Normal,
+    /// Only the ignored tests are run, `--ignored`.
+    Only,
+    /// The ignored tests are run along with the rest, `--include-ignored`.
+    Include,
+}
+
+impl FromStr for IgnoredTests {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "normal" => Ok(IgnoredTests::Normal),
+            "only" => Ok(IgnoredTests::Only),
+            "include" => Ok(IgnoredTests::Include),
+            _ => Err("no matching IgnoredTests"),
+        }
+    }
+}
+
+/// Selects some tests to run. The `tests` are exact test names; if there are
+/// none then every test in the target is selected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestFilter {
+    /// The package to test, or `None` for every package in the workspace.
+    pub package: Option<String>,
+    pub target: TestTarget,
+    pub tests: Vec<String>,
+}
+
+impl TestFilter {
+    /// Selects every test in the workspace. Usually narrowed down
+    /// to particular tests using `with_test`.
+    pub fn workspace() -> Self {
+        Self {
+            package: None,
+            target: TestTarget::All,
+            tests: Vec::new(),
+        }
+    }
+
+    /// Selects every test in a package.
+    pub fn package<S: Into<String>>(package: S) -> Self {
+        Self {
+            package: Some(package.into()),
+            target: TestTarget::All,
+            tests: Vec::new(),
+        }
+    }
+
+    /// Narrows the filter to one of the package's test targets.
+    pub fn with_target(mut self, target: TestTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Narrows the filter to a single test, in addition to any already selected.
+    pub fn with_test<S: Into<String>>(mut self, test: S) -> Self {
+        self.tests.push(test.into());
+        self
+    }
+
+    fn is_everything(&self) -> bool {
+        self.package.is_none() && self.target == TestTarget::All && self.tests.is_empty()
+    }
+}
+
+impl Display for TestFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.package.as_deref().unwrap_or("workspace"))?;
+        match &self.target {
+            TestTarget::All => {}
+            TestTarget::Lib => write!(f, " --lib")?,
+            TestTarget::Bin(name) => write!(f, " --bin {}", name)?,
+            TestTarget::Test(name) => write!(f, " --test {}", name)?,
+            TestTarget::Doc => write!(f, " --doc")?,
+        }
+        for test in &self.tests {
+            write!(f, " {}", test)?;
+        }
+        Ok(())
+    }
+}
+
+/// The set of tests that a `RunTestsJob` runs. The default selection is
+/// every test in the workspace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestSelection {
+    filters: Vec<TestFilter>,
+    /// Tests that are not run, even if a filter selects them. Each is skipped
+    /// in one package, or in every package if it has none.
+    skip: Vec<(Option<String>, String)>,
+    ignored: IgnoredTests,
+}
+
+impl TestSelection {
+    /// Selects every test in the workspace.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Selects every test in the `packages`, or in the whole
+    /// workspace if there are none.
+    pub fn packages<I: IntoIterator<Item = String>>(packages: I) -> Self {
+        packages
+            .into_iter()
+            .fold(Self::all(), |selection, package| {
+                selection.with_filter(TestFilter::package(package))
+            })
+    }
+
+    /// Adds the tests selected by `filter` to the selection.
+    pub fn with_filter(mut self, filter: TestFilter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Excludes a test, identified by its exact name, from the selection.
+    pub fn with_skipped<S: Into<String>>(mut self, test: S) -> Self {
+        self.skip.push((None, test.into()));
+        self
+    }
+
+    /// Excludes a test from the selection in just one package, so that
+    /// tests with the same name in other packages are still run.
+    pub fn with_skipped_in<P: Into<String>, S: Into<String>>(
+        mut self,
+        package: P,
+        test: S,
+    ) -> Self {
+        self.skip.push((Some(package.into()), test.into()));
+        self
+    }
+
+    pub fn filters(&self) -> &[TestFilter] {
+        &self.filters
+    }
+
+    /// Determines whether the tests marked with `#[ignore]` are run.
+    pub fn with_ignored(mut self, ignored: IgnoredTests) -> Self {
+        self.ignored = ignored;
+        self
+    }
+
+    /// Returns the names of the tests that are skipped by an invocation for
+    /// the `package`, or for the whole workspace if it is `None`.
+    pub fn skipped(&self, package: Option<&str>) -> Vec<String> {
+        self.skip
+            .iter()
+            .filter(|(p, _)| p.is_none() || p.as_deref() == package)
+            .map(|(_, test)| test.clone())
+            .collect()
+    }
+
+    /// Returns the packages that some tests are skipped in. An invocation
+    /// for the whole workspace has to leave these packages to one of their own.
+    pub fn packages_with_skipped_tests(&self) -> Vec<String> {
+        let mut packages = self
+            .skip
+            .iter()
+            .filter_map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+        packages.sort();
+        packages.dedup();
+        packages
+    }
+
+    pub fn ignored(&self) -> IgnoredTests {
+        self.ignored
+    }
+
+    /// Returns true if every test in the workspace is selected,
+    /// and the ignored tests are treated normally.
+    pub fn is_all(&self) -> bool {
+        self.filters_select_everything()
+            && self.skip.is_empty()
+            && self.ignored == IgnoredTests::Normal
+    }
+
+    fn filters_select_everything(&self) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(TestFilter::is_everything)
+    }
+
+    /// Works out the cargo invocations needed to run the selection. Filters
+    /// for the same package and target are combined into a single invocation,
+    /// and a filter for a whole package absorbs the other filters for that
+    /// package, so no test is run twice.
+    pub fn invocations(&self) -> Vec<TestFilter> {
+        if self.filters_select_everything() {
+            return vec![TestFilter {
+                package: None,
+                target: TestTarget::All,
+                tests: Vec::new(),
+            }];
+        }
+
+        let mut invocations: Vec<TestFilter> = Vec::new();
+        for filter in &self.filters {
+            match invocations
+                .iter_mut()
+                .find(|i| i.package == filter.package && i.target == filter.target)
+            {
+                // An empty list of tests means the whole target, which can't be widened.
+                Some(existing) if existing.tests.is_empty() => {}
+                Some(existing) if filter.tests.is_empty() => existing.tests.clear(),
+                Some(existing) => {
+                    for test in &filter.tests {
+                        if !existing.tests.contains(test) {
+                            existing.tests.push(test.clone());
+                        }
+                    }
+                }
+                None => invocations.push(filter.clone()),
+            }
+        }
+
+        let whole_packages = invocations
+            .iter()
+            .filter(|i| i.target == TestTarget::All && i.tests.is_empty())
+            .map(|i| i.package.clone())
+            .collect::<Vec<_>>();
+
+        invocations.retain(|i| {
+            (i.target == TestTarget::All && i.tests.is_empty())
+                || !whole_packages.contains(&i.package)
+        });
+
+        invocations
+    }
+}
+
+/// A test binary that was built by cargo, which can be run directly
+/// rather than through `cargo test`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestBinary {
+    /// The path to the binary, relative to the workspace directory.
+    pub path: PathBuf,
+    pub package: String,
+
+    /// The directory of the package, relative to the workspace directory.
+    /// `cargo test` runs each binary in its package's directory, and
+    /// tests often rely on that to find their data files.
+    pub package_directory: PathBuf,
+
+    /// The targets the binary could have been built from. There is usually
+    /// just one, but if a package has a library and a binary with the
+    /// same name then their test binaries can't be told apart.
+    pub targets: Vec<TestTarget>,
+
+    /// The names of the tests in the binary.
+    pub tests: Vec<String>,
+}
+
+/// The test binaries in a workspace, for running the tests directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestBinaries {
+    pub binaries: Vec<TestBinary>,
+    /// The packages that have doc tests. Doc tests are compiled when they
+    /// are run, so they have no binaries and have to be run by cargo.
+    pub doc_test_packages: Vec<String>,
+}
+
+/// A run of a test binary, see `TestSelection::binary_runs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryRun<'a> {
+    pub binary: &'a TestBinary,
+    /// The exact names of the tests to run, or empty to run all of them.
+    pub tests: Vec<String>,
+}
+
+impl TestSelection {
+    /// Works out which of the `binaries` to run, and which of their tests,
+    /// to run the selection without going through cargo. The doc tests are
+    /// returned separately as `cargo test` invocations, see `invocations`.
+    pub fn binary_runs<'a>(
+        &self,
+        binaries: &'a TestBinaries,
+    ) -> (Vec<BinaryRun<'a>>, Vec<TestFilter>) {
+        // `None` means every test in the binary.
+        let mut runs: BTreeMap<usize, Option<Vec<String>>> = BTreeMap::new();
+        let mut doc_filters = Vec::new();
+
+        for filter in self.invocations() {
+            let in_package = |package: &str| filter.package.as_deref().is_none_or(|p| p == package);
+
+            for (idx, binary) in binaries.binaries.iter().enumerate() {
+                let selected = in_package(&binary.package)
+                    && match &filter.target {
+                        TestTarget::All => true,
+                        TestTarget::Doc => false,
+                        target => binary.targets.contains(target),
+                    };
+                if !selected {
+                    continue;
+                }
+
+                let tests = runs.entry(idx).or_insert_with(|| Some(Vec::new()));
+                match tests {
+                    Some(_) if filter.tests.is_empty() => *tests = None,
+                    Some(tests) => {
+                        for test in &filter.tests {
+                            if !tests.contains(test) {
+                                tests.push(test.clone());
+                            }
+                        }
+                    }
+                    None => {}
+                }
+            }
+
+            let has_doc_tests = binaries.doc_test_packages.iter().any(|p| in_package(p));
+            if has_doc_tests
+                && (filter.target == TestTarget::All || filter.target == TestTarget::Doc)
+            {
+                doc_filters.push(filter.with_target(TestTarget::Doc));
+            }
+        }
+
+        let runs = runs
+            .into_iter()
+            .map(|(idx, tests)| BinaryRun {
+                binary: &binaries.binaries[idx],
+                tests: tests.unwrap_or_default(),
+            })
+            .collect();
+
+        // Combine the doc test filters in the same way as any other filters.
+        let doc_invocations = if doc_filters.is_empty() {
+            Vec::new()
+        } else {
+            TestSelection {
+                filters: doc_filters,
+                ..Default::default()
+            }
+            .invocations()
+            .into_iter()
+            .map(|filter| filter.with_target(TestTarget::Doc))
+            .collect()
+        };
+
+        (runs, doc_invocations)
+    }
+}
+
+impl Display for TestSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.filters_select_everything() {
+            write!(f, "all tests")?;
+        } else {
+            let filters = self
+                .filters
+                .iter()
+                .map(|filter| filter.to_string())
+                .collect::<Vec<_>>();
+            write!(f, "{}", filters.join(", "))?;
+        }
+
+        if !self.skip.is_empty() {
+            let skip = self
+                .skip
+                .iter()
+                .map(|(package, test)| match package {
+                    Some(package) => format!("{} in {}", test, package),
+                    None => test.clone(),
+                })
+                .collect::<Vec<_>>();
+            write!(f, " except {}", skip.join(", "))?;
+        }
+
+        match self.ignored {
+            IgnoredTests::Normal => {}
+            IgnoredTests::Only => write!(f, ", ignored only")?,
+            IgnoredTests::Include => write!(f, ", including ignored")?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the arguments for `cargo test` that run the tests selected by
+/// `filter` (as returned by `TestSelection::invocations`), except for those in
+/// `skip`. If the filter is for the whole workspace, the packages in `exclude`
+/// are left out.
+pub(crate) fn cargo_test_args(
+    filter: &TestFilter,
+    exclude: &[String],
+    skip: &[String],
+    ignored: IgnoredTests,
+    build_mode: BuildMode,
+    settings: &TestSettings,
+) -> Vec<String> {
+    let mut args = vec!["test".to_string(), "--no-fail-fast".to_string()];
+
+    if let Some(package) = &filter.package {
+        args.push("--package".into());
+        args.push(package.clone());
+    } else if !exclude.is_empty() {
+        args.push("--workspace".into());
+        for package in exclude {
+            args.push("--exclude".into());
+            args.push(package.clone());
+        }
+    }
+
+    match &filter.target {
+        TestTarget::All => {}
+        TestTarget::Lib => args.push("--lib".into()),
+        TestTarget::Bin(name) => {
+            args.push("--bin".into());
+            args.push(name.clone());
+        }
+        TestTarget::Test(name) => {
+            args.push("--test".into());
+            args.push(name.clone());
+        }
+        TestTarget::Doc => args.push("--doc".into()),
+    }
+
+    if build_mode == BuildMode::Release {
+        args.push("--release".into());
+    }
+
+    args.push("--".into());
+    args.extend(libtest_args(settings, &filter.tests, skip, ignored));
+    args
+}
+
+/// Returns the arguments for a test binary that run the `tests` (or every
+/// test if there are none) except for those in `skip`. These are also the
+/// arguments that `cargo test` passes on to the binaries.
+pub(crate) fn libtest_args(
+    settings: &TestSettings,
+    tests: &[String],
+    skip: &[String],
+    ignored: IgnoredTests,
+) -> Vec<String> {
+    let mut args = settings.libtest_args();
+
+    match ignored {
+        IgnoredTests::Normal => {}
+        IgnoredTests::Only => args.push("--ignored".into()),
+        IgnoredTests::Include => args.push("--include-ignored".into()),
+    }
+
+    // `--exact` applies to the skip filters too, so a skipped
+    // test doesn't take every test that contains its name with it.
+    if !tests.is_empty() || !skip.is_empty() {
+        args.push("--exact".into());
+        args.extend(tests.iter().cloned());
+    }
+
+    for test in skip {
+        args.push("--skip".into());
+        args.push(test.clone());
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn binary(package: &str, target: TestTarget, tests: &[&str]) -> TestBinary {
+        TestBinary {
+            path: PathBuf::from(format!("target/debug/deps/{}-9bdf7ee7378a8684", package)),
+            package: package.to_string(),
+            package_directory: PathBuf::from(package),
+            targets: vec![target],
+            tests: strings(tests),
+        }
+    }
+
+    #[test]
+    fn everything_is_run_in_a_single_invocation() {
+        let selection = TestSelection::packages(strings(&["a"]))
+            .with_filter(TestFilter::workspace())
+            .with_filter(TestFilter::package("b").with_test("tests::x"));
+        assert_eq!(selection.invocations(), vec![TestFilter::workspace()]);
+        assert_eq!(
+            TestSelection::all().invocations(),
+            vec![TestFilter::workspace()]
+        );
+    }
+
+    #[test]
+    fn filters_for_the_same_package_and_target_are_combined() {
+        let selection = TestSelection::all()
+            .with_filter(TestFilter::package("a").with_test("tests::x"))
+            .with_filter(TestFilter::package("b").with_test("tests::x"))
+            .with_filter(
+                TestFilter::package("a")
+                    .with_test("tests::y")
+                    .with_test("tests::x"),
+            )
+            .with_filter(TestFilter::package("a").with_target(TestTarget::Lib));
+
+        assert_eq!(
+            selection.invocations(),
+            vec![
+                TestFilter::package("a")
+                    .with_test("tests::x")
+                    .with_test("tests::y"),
+                TestFilter::package("b").with_test("tests::x"),
+                TestFilter::package("a").with_target(TestTarget::Lib),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_whole_package_absorbs_the_other_filters_for_it() {
+        let selection = TestSelection::all()
+            .with_filter(TestFilter::package("a").with_test("tests::x"))
+            .with_filter(TestFilter::package("a").with_target(TestTarget::Lib))
+            .with_filter(TestFilter::package("b").with_target(TestTarget::Doc))
+            .with_filter(TestFilter::package("a"));
+
+        assert_eq!(
+            selection.invocations(),
+            vec![
+                TestFilter::package("a"),
+                TestFilter::package("b").with_target(TestTarget::Doc),
+            ]
+        );
+    }
+
+    #[test]
+    fn tests_skipped_in_a_package_are_only_skipped_there() {
+        let selection = TestSelection::all()
+            .with_skipped("tests::x")
+            .with_skipped_in("b", "tests::y")
+            .with_skipped_in("a", "tests::z");
+
+        assert_eq!(selection.skipped(None), strings(&["tests::x"]));
+        assert_eq!(
+            selection.skipped(Some("a")),
+            strings(&["tests::x", "tests::z"])
+        );
+        assert_eq!(
+            selection.packages_with_skipped_tests(),
+            strings(&["a", "b"])
+        );
+        assert_eq!(
+            selection.to_string(),
+            "all tests except tests::x, tests::y in b, tests::z in a"
+        );
+    }
+
+    #[test]
+    fn binary_runs_select_binaries_by_package_and_target() {
+        let binaries = TestBinaries {
+            binaries: vec![
+                binary("a", TestTarget::Lib, &["tests::x"]),
+                binary("a", TestTarget::Test("it".to_string()), &["x", "y"]),
+                binary("b", TestTarget::Lib, &["tests::x"]),
+            ],
+            doc_test_packages: strings(&["a"]),
+        };
+
+        let selection = TestSelection::all()
+            .with_filter(
+                TestFilter::package("a")
+                    .with_target(TestTarget::Test("it".to_string()))
+                    .with_test("y"),
+            )
+            .with_filter(TestFilter::package("b"));
+        let (runs, doc_invocations) = selection.binary_runs(&binaries);
+        let runs = runs
+            .iter()
+            .map(|run| (run.binary, run.tests.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            vec![
+                (&binaries.binaries[1], strings(&["y"])),
+                (&binaries.binaries[2], Vec::new())
+            ]
+        );
+        // b has no doc tests, and the filter for a is for another target.
+        assert!(doc_invocations.is_empty());
+    }
+
+    #[test]
+    fn binary_runs_leave_the_doc_tests_to_cargo() {
+        let binaries = TestBinaries {
+            binaries: vec![binary("a", TestTarget::Lib, &["tests::x"])],
+            doc_test_packages: strings(&["a"]),
+        };
+
+        let (runs, doc_invocations) = TestSelection::all().binary_runs(&binaries);
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].tests.is_empty());
+        assert_eq!(
+            doc_invocations,
+            vec![TestFilter::workspace().with_target(TestTarget::Doc)]
+        );
+
+        let selection = TestSelection::all().with_filter(TestFilter::package("a").with_test("x"));
+        let (_, doc_invocations) = selection.binary_runs(&binaries);
+        assert_eq!(
+            doc_invocations,
+            vec![TestFilter::package("a")
+                .with_target(TestTarget::Doc)
+                .with_test("x")]
+        );
+    }
+
+    #[test]
+    fn cargo_test_args_select_the_package_and_target() {
+        let filter = TestFilter::package("a")
+            .with_target(TestTarget::Test("it".to_string()))
+            .with_test("tests::x");
+        let args = cargo_test_args(
+            &filter,
+            &[],
+            &strings(&["tests::y"]),
+            IgnoredTests::Normal,
+            BuildMode::Release,
+            &TestSettings::default(),
+        );
+        assert_eq!(
+            args.join(" "),
+            "test --no-fail-fast --package a --test it --release -- \
+             --show-output --test-threads=1 --color never --exact tests::x --skip tests::y"
+        );
+    }
+
+    #[test]
+    fn cargo_test_args_exclude_packages_from_the_workspace() {
+        let filter = TestFilter::workspace().with_target(TestTarget::Doc);
+        let args = cargo_test_args(
+            &filter,
+            &strings(&["a", "b"]),
+            &[],
+            IgnoredTests::Normal,
+            BuildMode::Debug,
+            &TestSettings::default(),
+        );
+        assert_eq!(
+            args.join(" "),
+            "test --no-fail-fast --workspace --exclude a --exclude b --doc -- \
+             --show-output --test-threads=1 --color never"
+        );
+
+        // The exclusions don't apply to a filter for a single package.
+        let filter = TestFilter::package("c");
+        let args = cargo_test_args(
+            &filter,
+            &strings(&["a"]),
+            &[],
+            IgnoredTests::Normal,
+            BuildMode::Debug,
+            &TestSettings::default(),
+        );
+        assert_eq!(
+            &args[..4],
+            &strings(&["test", "--no-fail-fast", "--package", "c"])[..]
+        );
+        assert!(!args.contains(&"--exclude".to_string()));
+    }
+
+    #[test]
+    fn libtest_args_are_exact_only_when_tests_are_named() {
+        let settings = TestSettings::default();
+        let defaults = settings.libtest_args();
+        assert_eq!(
+            libtest_args(&settings, &[], &[], IgnoredTests::Normal),
+            defaults
+        );
+
+        let args = libtest_args(&settings, &[], &strings(&["x"]), IgnoredTests::Normal);
+        assert_eq!(
+            &args[defaults.len()..],
+            &strings(&["--exact", "--skip", "x"])[..]
+        );
+
+        let args = libtest_args(&settings, &strings(&["x", "y"]), &[], IgnoredTests::Only);
+        assert_eq!(
+            &args[defaults.len()..],
+            &strings(&["--ignored", "--exact", "x", "y"])[..]
+        );
+
+        let args = libtest_args(&settings, &[], &[], IgnoredTests::Include);
+        assert_eq!(
+            &args[defaults.len()..],
+            &strings(&["--include-ignored"])[..]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use crate::jobs::ProcessCommand;
+use std::{collections::BTreeMap, num::NonZeroUsize, str::FromStr};
+
+/// The value of `RUST_BACKTRACE` for the tests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backtrace {
+    Off,
+    Short,
+    Full,
+}
+
+impl Backtrace {
+    fn env_value(&self) -> &'static str {
+        match self {
+            Backtrace::Off => "0",
+            Backtrace::Short => "1",
+            Backtrace::Full => "full",
+        }
+    }
+}
+
+impl FromStr for Backtrace {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "0" | "off" => Ok(Backtrace::Off),
+            "1" | "short" => Ok(Backtrace::Short),
+            "full" => Ok(Backtrace::Full),
+            _ => Err("no matching Backtrace"),
+        }
+    }
+}
+
+/// How libtest runs the tests in a package: the arguments passed
+/// to the test binaries, and the environment they run in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSettings {
+    /// The number of threads each test binary runs its tests on, `--test-threads`.
+    pub threads: usize,
+
+    /// The value of `RUST_BACKTRACE`, or `None` to inherit it from rtest.
+    pub backtrace: Option<Backtrace>,
+
+    /// The value of `RUST_LOG`, or `None` to inherit it from rtest.
+    pub log_filter: Option<String>,
+
+    /// Other environment variables to set.
+    pub env: Vec<(String, String)>,
+
+    /// Passes `--nocapture` rather than `--show-output`, so that output is
+    /// written as the tests run rather than after each test has completed.
+    pub nocapture: bool,
+
+    /// Has libtest report how long each test took, so that their durations
+    /// can be tracked. `--report-time` is unstable, so this needs a nightly
+    /// toolchain.
+    pub report_time: bool,
+
+    /// More arguments for libtest, for example `--include-ignored`.
+    pub extra_args: Vec<String>,
+}
+
+impl Default for TestSettings {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            backtrace: None,
+            log_filter: None,
+            env: Vec::new(),
+            nocapture: false,
+            report_time: false,
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+impl TestSettings {
+    /// Returns the libtest arguments, apart from those that select the tests.
+    pub fn libtest_args(&self) -> Vec<String> {
+        let output = if self.nocapture {
+            "--nocapture"
+        } else {
+            "--show-output"
+        };
+
+        let mut args = vec![
+            output.to_string(),
+            format!("--test-threads={}", self.threads),
+            "--color".to_string(),
+            "never".to_string(),
+        ];
+        if self.report_time {
+            args.push("-Zunstable-options".to_string());
+            args.push("--report-time".to_string());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Adds the environment variables to `command`. When the tests are run by
+    /// `cargo test` they are inherited by the test binaries from cargo.
+    pub fn apply_env(&self, mut command: ProcessCommand) -> ProcessCommand {
+        if let Some(backtrace) = self.backtrace {
+            command = command.with_env("RUST_BACKTRACE", backtrace.env_value());
+        }
+        if let Some(log_filter) = &self.log_filter {
+            command = command.with_env("RUST_LOG", log_filter.as_str());
+        }
+        for (key, value) in &self.env {
+            command = command.with_env(key.as_str(), value.as_str());
+        }
+        command
+    }
+
+    /// Changes one setting, as specified on the command line by `key=value`,
+    /// for example "threads=4", "backtrace=full" or "env=KEY=VALUE". The
+    /// values of "env" and "arg" are added to those already present.
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (key, value) = match setting.find('=') {
+            Some(idx) => (&setting[..idx], &setting[idx + 1..]),
+            None => (setting, ""),
+        };
+
+        match key {
+            // libtest refuses to run the tests on no threads at all.
+            "threads" => {
+                let threads = value.parse::<NonZeroUsize>();
+                self.threads = threads.map_err(|_| invalid(setting))?.get();
+            }
+            "backtrace" => self.backtrace = Some(value.parse().map_err(|_| invalid(setting))?),
+            "log" => self.log_filter = Some(value.to_string()),
+            "env" => self.env.push(parse_env_var(value)?),
+            "nocapture" if value.is_empty() => self.nocapture = true,
+            "nocapture" => self.nocapture = value.parse().map_err(|_| invalid(setting))?,
+            "report-time" if value.is_empty() => self.report_time = true,
+            "report-time" => self.report_time = value.parse().map_err(|_| invalid(setting))?,
+            "arg" => self.extra_args.push(value.to_string()),
+            _ => return Err(invalid(setting)),
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(setting: &str) -> String {
+    format!("Invalid test setting '{}'", setting)
+}
+
+/// Parses an environment variable specified as `KEY=VALUE`.
+pub fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(idx) if idx > 0 => Ok((s[..idx].to_string(), s[idx + 1..].to_string())),
+        _ => Err(format!("Expected KEY=VALUE, got '{}'", s)),
+    }
+}
+
+/// The `TestSettings` for every package in the workspace. Most packages
+/// use the default settings, but some can have settings of their own.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestExecutionSettings {
+    pub default: TestSettings,
+    pub packages: BTreeMap<String, TestSettings>,
+}
+
+impl TestExecutionSettings {
+    /// Returns the settings for `package`, or the default settings for `None`,
+    /// which means tests from more than one package.
+    pub fn for_package(&self, package: Option<&str>) -> &TestSettings {
+        package
+            .and_then(|p| self.packages.get(p))
+            .unwrap_or(&self.default)
+    }
+
+    /// Changes a setting for one package, as specified on the command line
+    /// by `package:key=value`. The package starts with the default settings.
+    pub fn set_for_package(&mut self, setting: &str) -> Result<(), String> {
+        let idx = setting.find(':').ok_or_else(|| invalid(setting))?;
+        let (package, setting) = (&setting[..idx], &setting[idx + 1..]);
+        let default = &self.default;
+        self.packages
+            .entry(package.to_string())
+            .or_insert_with(|| default.clone())
+            .set(setting)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_changes_one_setting() {
+        let mut settings = TestSettings::default();
+        settings.set("threads=4").unwrap();
+        settings.set("backtrace=full").unwrap();
+        settings.set("log=rtest=debug").unwrap();
+        settings.set("nocapture").unwrap();
+        settings.set("report-time=true").unwrap();
+
+        assert_eq!(settings.threads, 4);
+        assert_eq!(settings.backtrace, Some(Backtrace::Full));
+        assert_eq!(settings.log_filter.as_deref(), Some("rtest=debug"));
+        assert!(settings.nocapture);
+        assert!(settings.report_time);
+
+        settings.set("nocapture=false").unwrap();
+        assert!(!settings.nocapture);
+    }
+
+    #[test]
+    fn set_adds_to_the_env_and_args() {
+        let mut settings = TestSettings::default();
+        settings.set("env=A=1").unwrap();
+        settings.set("env=B=x=y").unwrap();
+        settings.set("arg=--include-ignored").unwrap();
+        settings.set("arg=--exact").unwrap();
+
+        assert_eq!(
+            settings.env,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "x=y".to_string())
+            ]
+        );
+        assert_eq!(settings.extra_args, vec!["--include-ignored", "--exact"]);
+    }
+
+    #[test]
+    fn set_rejects_invalid_settings() {
+        let mut settings = TestSettings::default();
+        for setting in &[
+            "threads=0",
+            "threads=many",
+            "backtrace=long",
+            "env=A",
+            "env==1",
+            "nocapture=maybe",
+            "colour=never",
+        ] {
+            assert!(settings.set(setting).is_err(), "{}", setting);
+        }
+        assert_eq!(settings, TestSettings::default());
+    }
+
+    #[test]
+    fn set_for_package_starts_with_the_default_settings() {
+        let mut settings = TestExecutionSettings::default();
+        settings.default.set("threads=2").unwrap();
+        settings.set_for_package("a:backtrace=1").unwrap();
+
+        let a = settings.for_package(Some("a"));
+        assert_eq!((a.threads, a.backtrace), (2, Some(Backtrace::Short)));
+        assert_eq!(settings.for_package(Some("b")), &settings.default);
+        assert_eq!(settings.for_package(None), &settings.default);
+        assert!(settings.set_for_package("threads=2").is_err());
+    }
+}
@@ -0,0 +1,564 @@
+use crate::{
+    configuration::BuildMode,
+    jobs::ProcessCommand,
+    state::ListedBinary,
+    test_selection::{TestBinaries, TestBinary, TestTarget},
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// A package that is a member of the workspace being tested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspacePackage {
+    pub name: String,
+
+    /// The directory containing the package's Cargo.toml, relative
+    /// to the directory that `cargo metadata` was run in.
+    pub directory: PathBuf,
+
+    /// The other workspace members that this package depends on, including
+    /// dev and build dependencies, since they can also affect the tests.
+    pub dependencies: Vec<String>,
+
+    /// The targets that `cargo test` builds a test binary for.
+    pub test_targets: Vec<WorkspaceTarget>,
+
+    /// True if the package's library has doc tests.
+    pub has_doc_tests: bool,
+}
+
+/// A target of a package that has tests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceTarget {
+    pub target: TestTarget,
+    /// The name of the crate the target is compiled as, which is
+    /// the name of its test binary without the hash.
+    pub crate_name: String,
+}
+
+/// The packages in a workspace and the dependencies between them, as
+/// reported by `cargo metadata`. Used to work out which packages can be
+/// affected by a change to a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    packages: Vec<WorkspacePackage>,
+}
+
+/// The parts of the output of `cargo metadata` that we need.
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<MetadataPackage>,
+}
+
+#[derive(Deserialize)]
+struct MetadataPackage {
+    name: String,
+    manifest_path: PathBuf,
+    dependencies: Vec<MetadataDependency>,
+    #[serde(default)]
+    targets: Vec<MetadataTarget>,
+}
+
+#[derive(Deserialize)]
+struct MetadataDependency {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct MetadataTarget {
+    name: String,
+    kind: Vec<String>,
+    #[serde(default = "default_true")]
+    test: bool,
+    #[serde(default = "default_true")]
+    doctest: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl MetadataTarget {
+    fn is_lib(&self) -> bool {
+        self.kind
+            .iter()
+            .any(|kind| kind == "lib" || kind == "rlib" || kind == "dylib" || kind == "proc-macro")
+    }
+
+    /// Returns the target as selected on cargo's command line,
+    /// or `None` if `cargo test` doesn't build a test binary for it.
+    fn test_target(&self) -> Option<TestTarget> {
+        if !self.test {
+            None
+        } else if self.is_lib() {
+            Some(TestTarget::Lib)
+        } else if self.kind.iter().any(|kind| kind == "bin") {
+            Some(TestTarget::Bin(self.name.clone()))
+        } else if self.kind.iter().any(|kind| kind == "test") {
+            Some(TestTarget::Test(self.name.clone()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Workspace {
+    /// Returns the command that gets the metadata for the workspace in `cwd`.
+    /// Only the workspace members are needed, so dependencies are not resolved
+    /// and the network is never used.
+    pub fn metadata_command(cwd: &Path) -> ProcessCommand {
+        let args = vec![
+            "metadata",
+            "--format-version",
+            "1",
+            "--no-deps",
+            "--offline",
+            "--color",
+            "never",
+        ];
+        ProcessCommand::cargo("Cargo metadata", args, cwd)
+    }
+
+    /// Parses the output of the `metadata_command` that was run in `cwd`.
+    /// The output can contain warnings from cargo as well as the JSON,
+    /// which is always on a line of its own.
+    pub fn from_metadata(output: &str, cwd: &Path) -> Result<Self, String> {
+        let json = output
+            .lines()
+            .find(|line| line.starts_with('{'))
+            .ok_or_else(|| "No JSON found in the output of cargo metadata".to_string())?;
+        let metadata: Metadata = serde_json::from_str(json).map_err(|err| err.to_string())?;
+
+        let names = metadata
+            .packages
+            .iter()
+            .map(|p| p.name.clone())
+            .collect::<BTreeSet<_>>();
+
+        let packages = metadata
+            .packages
+            .into_iter()
+            .map(|p| {
+                let directory = p.manifest_path.parent().unwrap_or_else(|| Path::new(""));
+                let mut dependencies = p
+                    .dependencies
+                    .into_iter()
+                    .map(|d| d.name)
+                    .filter(|name| names.contains(name))
+                    .collect::<Vec<_>>();
+                dependencies.sort();
+                dependencies.dedup();
+
+                let has_doc_tests = p.targets.iter().any(|t| t.is_lib() && t.doctest);
+                let test_targets = p
+                    .targets
+                    .iter()
+                    .filter_map(|t| {
+                        Some(WorkspaceTarget {
+                            target: t.test_target()?,
+                            crate_name: t.name.replace('-', "_"),
+                        })
+                    })
+                    .collect();
+
+                WorkspacePackage {
+                    name: p.name,
+                    directory: directory
+                        .strip_prefix(cwd)
+                        .unwrap_or(directory)
+                        .to_path_buf(),
+                    dependencies,
+                    test_targets,
+                    has_doc_tests,
+                }
+            })
+            .collect();
+
+        Ok(Self { packages })
+    }
+
+    pub fn packages(&self) -> &[WorkspacePackage] {
+        &self.packages
+    }
+
+    /// Returns the package that contains `path`, which is relative to the
+    /// directory `cargo metadata` was run in. When packages are nested the
+    /// innermost one is returned.
+    pub fn package_for_path(&self, path: &Path) -> Option<&WorkspacePackage> {
+        self.packages
+            .iter()
+            .filter(|p| path.starts_with(&p.directory))
+            .max_by_key(|p| p.directory.components().count())
+    }
+
+    /// Returns the `changed` packages plus every package that depends
+    /// on them, directly or indirectly.
+    pub fn affected_packages(&self, changed: &str) -> BTreeSet<String> {
+        let mut affected = BTreeSet::new();
+        let mut to_visit = vec![changed.to_string()];
+
+        while let Some(name) = to_visit.pop() {
+            if affected.insert(name.clone()) {
+                to_visit.extend(
+                    self.packages
+                        .iter()
+                        .filter(|p| p.dependencies.contains(&name))
+                        .map(|p| p.name.clone()),
+                );
+            }
+        }
+
+        affected
+    }
+
+    /// Works out which package and target each of the test binaries (as
+    /// listed by `cargo test -- --list`) belongs to, from the binary's
+    /// basename. Returns an error if that can't be done for every binary,
+    /// because the binary's tests would then never be run.
+    pub fn test_binaries(&self, binaries: &[ListedBinary]) -> Result<TestBinaries, String> {
+        let mut test_binaries = Vec::new();
+
+        for ListedBinary {
+            basename,
+            path,
+            tests,
+        } in binaries
+        {
+            let mut owners = self.packages.iter().filter_map(|p| {
+                let targets = p
+                    .test_targets
+                    .iter()
+                    .filter(|t| &t.crate_name == basename)
+                    .map(|t| t.target.clone())
+                    .collect::<Vec<_>>();
+                if targets.is_empty() {
+                    None
+                } else {
+                    Some((p, targets))
+                }
+            });
+
+            let (package, targets) = match (owners.next(), owners.next()) {
+                (Some(owner), None) => owner,
+                (None, _) => {
+                    return Err(format!(
+                        "Cannot find the package of test binary {}",
+                        path.display()
+                    ));
+                }
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "Test binary {} is in more than one package",
+                        path.display()
+                    ));
+                }
+            };
+
+            test_binaries.push(TestBinary {
+                path: path.clone(),
+                package: package.name.clone(),
+                package_directory: package.directory.clone(),
+                targets,
+                tests: tests.clone(),
+            });
+        }
+
+        let doc_test_packages = self
+            .packages
+            .iter()
+            .filter(|p| p.has_doc_tests)
+            .map(|p| p.name.clone())
+            .collect();
+
+        Ok(TestBinaries {
+            binaries: test_binaries,
+            doc_test_packages,
+        })
+    }
+}
+
+/// The packages that need to be built and tested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageScope {
+    /// Everything in the workspace.
+    Workspace,
+    /// Only these packages. Empty if nothing has changed.
+    Packages(BTreeSet<String>),
+}
+
+impl PackageScope {
+    fn extend(&mut self, packages: BTreeSet<String>) {
+        if let PackageScope::Packages(existing) = self {
+            existing.extend(packages);
+        }
+    }
+}
+
+/// Keeps track of which packages have changed since their tests were last
+/// run, separately for each build mode. Files that can't be attributed to a
+/// single package, such as the workspace's Cargo.toml, affect everything.
+#[derive(Debug)]
+pub(crate) struct ChangedPackages {
+    workspace: Mutex<Option<Workspace>>,
+    scopes: Mutex<HashMap<BuildMode, PackageScope>>,
+}
+
+impl Default for ChangedPackages {
+    fn default() -> Self {
+        let mut scopes = HashMap::new();
+        scopes.insert(BuildMode::Debug, PackageScope::Workspace);
+        scopes.insert(BuildMode::Release, PackageScope::Workspace);
+
+        Self {
+            workspace: Default::default(),
+            scopes: Mutex::new(scopes),
+        }
+    }
+}
+
+impl ChangedPackages {
+    /// Replaces the workspace, or forgets it if it could not be loaded. Either
+    /// way the packages may have changed, so everything needs to be tested.
+    pub(crate) fn set_workspace(&self, workspace: Option<Workspace>) {
+        *self.workspace.lock().unwrap() = workspace;
+        self.everything_changed();
+    }
+
+    pub(crate) fn workspace(&self) -> Option<Workspace> {
+        self.workspace.lock().unwrap().clone()
+    }
+
+    pub(crate) fn everything_changed(&self) {
+        for scope in self.scopes.lock().unwrap().values_mut() {
+            *scope = PackageScope::Workspace;
+        }
+    }
+
+    /// Records a change to the file or directory at `path`, which is relative
+    /// to the workspace directory. Returns true if the file is a manifest, in
+    /// which case the workspace should be reloaded.
+    pub(crate) fn path_changed(&self, path: &Path) -> bool {
+        let is_manifest = path
+            .file_name()
+            .is_some_and(|name| name == "Cargo.toml" || name == "Cargo.lock");
+
+        let affected = match &*self.workspace.lock().unwrap() {
+            // A package at the root of the workspace contains every path, so
+            // a change that is attributed to it is assumed to affect everything.
+            Some(workspace) if !is_manifest => workspace
+                .package_for_path(path)
+                .filter(|package| !package.directory.as_os_str().is_empty())
+                .map(|package| workspace.affected_packages(&package.name)),
+            _ => None,
+        };
+
+        match affected {
+            Some(affected) => {
+                for scope in self.scopes.lock().unwrap().values_mut() {
+                    scope.extend(affected.clone());
+                }
+            }
+            None => self.everything_changed(),
+        }
+
+        is_manifest
+    }
+
+    /// Returns the packages to build and test in `build_mode`. An empty list
+    /// means the whole workspace, which is also what is tested if nothing
+    /// has changed but the pipeline is run anyway.
+    pub(crate) fn packages(&self, build_mode: BuildMode) -> Vec<String> {
+        match &self.scopes.lock().unwrap()[&build_mode] {
+            PackageScope::Workspace => Vec::new(),
+            PackageScope::Packages(packages) => packages.iter().cloned().collect(),
+        }
+    }
+
+    /// Called when the tests have been run in `build_mode`, so
+    /// that only subsequent changes are tested next time.
+    pub(crate) fn tests_run(&self, build_mode: BuildMode) {
+        let mut scopes = self.scopes.lock().unwrap();
+        scopes.insert(build_mode, PackageScope::Packages(BTreeSet::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The output of `cargo metadata` for a workspace whose root package has
+    /// a binary and an integration test with hyphenated names, and whose
+    /// `demo-core` package has the `demo-macros` package nested inside it.
+    static METADATA: &str = include_str!(r"inputs/cargo_metadata.json");
+
+    fn workspace() -> Workspace {
+        Workspace::from_metadata(METADATA, Path::new("/home/dev/rtest-demo")).unwrap()
+    }
+
+    fn listed_binary(basename: &str) -> ListedBinary {
+        ListedBinary {
+            basename: basename.to_string(),
+            path: PathBuf::from(format!("target/debug/deps/{}-9bdf7ee7378a8684", basename)),
+            tests: vec!["tests::a".to_string()],
+        }
+    }
+
+    #[test]
+    fn from_metadata_finds_the_packages_and_their_test_targets() {
+        let workspace = workspace();
+        let names = workspace
+            .packages()
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["demo-core", "demo-macros", "rtest-demo"]);
+
+        assert_eq!(
+            workspace.packages()[2],
+            WorkspacePackage {
+                name: "rtest-demo".to_string(),
+                directory: PathBuf::new(),
+                dependencies: vec!["demo-core".to_string()],
+                test_targets: vec![
+                    WorkspaceTarget {
+                        target: TestTarget::Lib,
+                        crate_name: "rtest_demo".to_string(),
+                    },
+                    WorkspaceTarget {
+                        target: TestTarget::Bin("demo-cli".to_string()),
+                        crate_name: "demo_cli".to_string(),
+                    },
+                    WorkspaceTarget {
+                        target: TestTarget::Test("end-to-end".to_string()),
+                        crate_name: "end_to_end".to_string(),
+                    },
+                ],
+                has_doc_tests: true,
+            }
+        );
+    }
+
+    #[test]
+    fn from_metadata_makes_nested_package_directories_relative() {
+        let workspace = workspace();
+        let directories = workspace
+            .packages()
+            .iter()
+            .map(|p| p.directory.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            directories,
+            vec![
+                PathBuf::from("crates/demo-core"),
+                PathBuf::from("crates/demo-core/demo-macros"),
+                PathBuf::new(),
+            ]
+        );
+
+        // A proc-macro crate is tested like a library.
+        let macros = &workspace.packages()[1];
+        assert_eq!(macros.test_targets[0].target, TestTarget::Lib);
+        assert_eq!(macros.test_targets[0].crate_name, "demo_macros");
+        assert_eq!(workspace.packages()[0].dependencies, vec!["demo-macros"]);
+    }
+
+    #[test]
+    fn from_metadata_skips_cargo_warnings() {
+        let output = format!("warning: unused manifest key: package.foo\n{}", METADATA);
+        let workspace = Workspace::from_metadata(&output, Path::new("/home/dev/rtest-demo"));
+        assert_eq!(workspace.unwrap(), self::workspace());
+    }
+
+    #[test]
+    fn from_metadata_without_json_is_an_error() {
+        let result = Workspace::from_metadata("error: could not find Cargo.toml\n", Path::new(""));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn package_for_path_returns_the_innermost_package() {
+        let workspace = workspace();
+        let package = |path: &str| {
+            workspace
+                .package_for_path(Path::new(path))
+                .map(|p| p.name.clone())
+        };
+
+        assert_eq!(
+            package("crates/demo-core/demo-macros/src/lib.rs").unwrap(),
+            "demo-macros"
+        );
+        assert_eq!(package("crates/demo-core/src/lib.rs").unwrap(), "demo-core");
+        assert_eq!(package("tests/end-to-end.rs").unwrap(), "rtest-demo");
+        // The root package contains everything that isn't in another package.
+        assert_eq!(package("crates/README.md").unwrap(), "rtest-demo");
+    }
+
+    #[test]
+    fn affected_packages_includes_the_packages_that_depend_on_the_change() {
+        let workspace = workspace();
+        let affected = |changed| {
+            workspace
+                .affected_packages(changed)
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            affected("demo-macros"),
+            vec!["demo-core", "demo-macros", "rtest-demo"]
+        );
+        assert_eq!(affected("demo-core"), vec!["demo-core", "rtest-demo"]);
+        assert_eq!(affected("rtest-demo"), vec!["rtest-demo"]);
+    }
+
+    #[test]
+    fn test_binaries_are_matched_to_their_packages_and_targets() {
+        let binaries = vec![
+            listed_binary("demo_cli"),
+            listed_binary("end_to_end"),
+            listed_binary("demo_macros"),
+        ];
+        let test_binaries = workspace().test_binaries(&binaries).unwrap();
+
+        let owners = test_binaries
+            .binaries
+            .iter()
+            .map(|b| (b.package.as_str(), b.targets.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            owners,
+            vec![
+                ("rtest-demo", vec![TestTarget::Bin("demo-cli".to_string())]),
+                (
+                    "rtest-demo",
+                    vec![TestTarget::Test("end-to-end".to_string())]
+                ),
+                ("demo-macros", vec![TestTarget::Lib]),
+            ]
+        );
+        assert_eq!(
+            test_binaries.binaries[2].package_directory,
+            PathBuf::from("crates/demo-core/demo-macros")
+        );
+        assert_eq!(
+            test_binaries.doc_test_packages,
+            vec!["demo-core", "demo-macros", "rtest-demo"]
+        );
+    }
+
+    #[test]
+    fn test_binary_without_a_package_is_an_error() {
+        let binaries = vec![listed_binary("demo_core"), listed_binary("unknown")];
+        let err = workspace().test_binaries(&binaries).unwrap_err();
+        assert_eq!(
+            err,
+            "Cannot find the package of test binary \
+             target/debug/deps/unknown-9bdf7ee7378a8684"
+        );
+    }
+}